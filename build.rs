@@ -0,0 +1,10 @@
+//! 根据 proto/connor.proto 生成 gRPC 服务端代码，使用内置的 protoc
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    println!("cargo:rerun-if-changed=proto/connor.proto");
+    tonic_build::configure()
+        .build_client(false)
+        .compile(&["proto/connor.proto"], &["proto"])?;
+    Ok(())
+}
@@ -1,83 +1,347 @@
+mod connor_client;
+pub mod load_balancer;
+mod service_cache;
+
+pub use connor_client::{ConnorClient, ServerPush};
+pub use service_cache::{ServiceCache, ServiceChange};
+
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use std::thread::sleep;
-use crate::models::{TcpReader, TcpWriter};
-use anyhow::Result;
+use crate::models::request::{HandshakeRequest, ReplicateOp, ReplicateRequest, SnapshotRequest};
+use crate::models::response::{ErrorResponse, HandshakeResponse, SnapshotResponse};
+use crate::protocol::{Codec, Frame, Protocol, VERSION};
+use crate::models::{RpcCodec, RpcKind, TcpReader, TcpWriter};
+use anyhow::{anyhow, Result};
 use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt, TryStreamExt};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{sleep, timeout, Duration};
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
-type PeerClient = Arc<RwLock<Vec<TcpClient>>>;
+/// 每个集群实例待发送数据的缓冲大小
+const PEER_QUEUE_SIZE: usize = 1024;
+/// 拉取全量数据的超时时间
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(5);
+
+type PeerSenders = Arc<RwLock<HashMap<String, Arc<PeerQueue>>>>;
 /// 集群客户端集合
-#[allow(dead_code)]
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct PeerCluster {
-    pub clients: PeerClient,
+    peers: PeerSenders,
 }
 impl PeerCluster {
-    pub async fn init(&mut self ,cluster_addr: &Vec<String>) {
+    /// 为每一个集群实例启动一个连接任务，断线后会自动重连
+    pub fn init(&self, cluster_addr: &[String]) {
         for addr in cluster_addr {
-            loop {
-                match TcpClient::new(addr).await {
-                    Ok(client) => {
-                        info!("Connect peer [{}] success", addr);
-                        self.clients.write().push(client);
-                        break;
-                    }
-                    Err(e) => {
-                        warn!("Connect peer [{}] failed, err: [{:?}]", addr, e);
-                        // 出错重试
-                        sleep(tokio::time::Duration::from_secs(5));
+            let queue = Arc::new(PeerQueue::default());
+            self.peers.write().insert(addr.clone(), queue.clone());
+            tokio::spawn(peer_task(addr.clone(), queue));
+        }
+    }
+
+    /// 将数据发送给集群中所有的实例
+    pub fn send_all(&self, data: Bytes) {
+        for (addr, queue) in self.peers.read().iter() {
+            if !queue.push(data.clone()) {
+                warn!("Peer [{}] queue is full, drop the oldest data", addr);
+            }
+        }
+    }
+
+    /// 将数据发送给指定的集群实例
+    pub fn send_to(&self, addr: &str, data: Bytes) {
+        match self.peers.read().get(addr) {
+            Some(queue) => {
+                if !queue.push(data) {
+                    warn!("Peer [{}] queue is full, drop the oldest data", addr);
+                }
+            }
+            None => warn!("Unknown peer [{}]", addr),
+        }
+    }
+
+    /// 将本节点的数据变更复制到集群中所有的实例
+    pub fn replicate(&self, op: ReplicateOp) {
+        let replicate_request = ReplicateRequest { op };
+        match replicate_request
+            .to_frame(Codec::Json)
+            .and_then(|frame| frame.encode(Protocol::CURRENT))
+        {
+            Ok(data) => self.send_all(data),
+            Err(err) => error!("Encode replicate request failed, err: [{}]", err),
+        }
+    }
+
+    /// 依次尝试从集群实例拉取全量数据，返回第一个成功响应的实例地址和数据
+    pub async fn pull_snapshot(cluster_addr: &[String]) -> Option<(String, SnapshotResponse)> {
+        for addr in cluster_addr {
+            match timeout(SNAPSHOT_TIMEOUT, Self::pull_snapshot_from(addr)).await {
+                Ok(Ok(snapshot)) => return Some((addr.clone(), snapshot)),
+                Ok(Err(err)) => warn!("Pull snapshot from peer [{}] failed, err: [{:?}]", addr, err),
+                Err(_) => warn!("Pull snapshot from peer [{}] timeout", addr),
+            }
+        }
+        None
+    }
+
+    /// 对端自己还在同步全量数据时立即返回错误，不等待超时
+    async fn pull_snapshot_from(addr: &str) -> Result<SnapshotResponse> {
+        let mut client = TcpClient::new(addr).await?;
+        client
+            .write(SnapshotRequest {}.to_frame(Codec::Json)?.encode(Protocol::CURRENT)?)
+            .await?;
+        // 跳过对端推送的广播消息，直到读取到全量数据响应
+        while let Some(data) = client.read().await {
+            if let Ok((_, frame)) = Frame::decode(data.freeze()) {
+                if frame.rpc_kind == RpcKind::Snapshot {
+                    let snapshot = SnapshotResponse::from_frame(&frame)?;
+                    if !snapshot.ready {
+                        return Err(anyhow!("peer [{}] is syncing from peers", addr));
                     }
+                    return Ok(*snapshot);
                 }
             }
         }
+        Err(anyhow!("peer [{}] closed before snapshot response", addr))
+    }
+}
+
+/// 发往单个集群实例的数据队列
+///
+/// 队列满时丢弃最早的数据：对端长时间不可用时积压的是更早的变更，对端重启时拉取的全量数据已经包含它们；
+/// 对端在导入全量数据之后才处理收到的数据，拉取全量数据之后产生的变更按照顺序送达并在导入之后应用
+#[derive(Default)]
+struct PeerQueue {
+    queue: Mutex<VecDeque<Bytes>>,
+    notify: Notify,
+}
+
+impl PeerQueue {
+    /// 加入队列，队列已满丢弃了最早的数据时返回 false
+    fn push(&self, data: Bytes) -> bool {
+        let full = {
+            let mut queue = self.queue.lock();
+            let full = queue.len() >= PEER_QUEUE_SIZE;
+            if full {
+                queue.pop_front();
+            }
+            queue.push_back(data);
+            full
+        };
+        self.notify.notify_one();
+        !full
+    }
+
+    /// 取出最早的数据，队列为空时等待
+    async fn pop(&self) -> Bytes {
+        loop {
+            if let Some(data) = self.queue.lock().pop_front() {
+                return data;
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// 维护与单个集群实例的连接，并将待复制的数据发送过去
+///
+/// 断线期间的数据保留在队列中，写入失败的数据在重连之后首先重新发送，数据按照产生的顺序到达对端
+async fn peer_task(addr: String, queue: Arc<PeerQueue>) {
+    let mut unsent: Option<Bytes> = None;
+    loop {
+        let mut client = match TcpClient::new(&addr).await {
+            Ok(client) => {
+                info!("Connect peer [{}] success", addr);
+                client
+            }
+            Err(e) => {
+                warn!("Connect peer [{}] failed, err: [{:?}]", addr, e);
+                // 出错重试
+                sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+        loop {
+            let data = match unsent.take() {
+                Some(data) => data,
+                None => tokio::select! {
+                    data = queue.pop() => data,
+                    // 对端会把广播推送也发给本连接，读取后直接丢弃
+                    frame = client.read() => {
+                        if frame.is_none() {
+                            warn!("Peer [{}] closed", addr);
+                            break;
+                        }
+                        continue;
+                    }
+                },
+            };
+            if let Err(err) = client.write(data.clone()).await {
+                warn!("Write to peer [{}] failed, err: [{:?}]", addr, err);
+                unsent = Some(data);
+                break;
+            }
+        }
     }
 }
 
+/// 建立连接并协商协议版本和序列化格式，delta 为是否接收增量推送，返回连接和协商的结果
+pub(crate) async fn handshake(
+    addr: &str,
+    codecs: &[Codec],
+    delta: bool,
+) -> Result<(Framed<TcpStream, LengthDelimitedCodec>, HandshakeResponse)> {
+    let tcp_stream = TcpStream::connect(addr).await?;
+    let mut transport = Framed::new(tcp_stream, LengthDelimitedCodec::new());
+    let handshake_request = HandshakeRequest {
+        version: VERSION,
+        codecs: codecs.to_vec(),
+        delta,
+    };
+    transport
+        .send(handshake_request.to_frame(Codec::Json)?.encode(Protocol::CURRENT)?)
+        .await?;
+    // 握手响应之前可能收到对端的广播推送，直接跳过
+    while let Some(data) = transport.try_next().await? {
+        let frame = match Frame::decode(data.freeze()) {
+            Ok((_, frame)) => frame,
+            Err(_) => continue,
+        };
+        match frame.rpc_kind {
+            RpcKind::Handshake => {
+                let response = HandshakeResponse::from_frame(&frame)?;
+                info!(
+                    "Handshake with [{}], protocol version [{}], codec [{:?}]",
+                    addr, response.version, response.codec
+                );
+                return Ok((transport, *response));
+            }
+            RpcKind::Error => {
+                let response = ErrorResponse::from_frame(&frame)?;
+                return Err(anyhow!("handshake with [{}] failed: {}", addr, response.message));
+            }
+            _ => {}
+        }
+    }
+    Err(anyhow!("[{}] closed before handshake", addr))
+}
+
 /// 连接其它集群实例的客户端
-#[allow(dead_code)]
 pub struct TcpClient {
     reader: TcpReader,
     writer: TcpWriter,
 }
 
 impl TcpClient {
-    #[allow(dead_code)]
-    /// 根据一个地址创建一个可读写的客户端
+    /// 根据一个地址创建一个可读写的客户端，并协商协议版本
     pub async fn new(connect: &str) -> Result<Self> {
         info!("Connect peer [{}] ....", connect);
-        let tcp_stream = TcpStream::connect(connect).await?;
-
-        let transport = Framed::new(tcp_stream, LengthDelimitedCodec::new());
+        // 集群实例间的数据使用 json
+        let (transport, _) = handshake(connect, &[Codec::Json], false).await?;
         let (writer, reader) = transport.split();
         Ok(TcpClient {reader, writer })
     }
 
-    #[allow(dead_code)]
     /// 读取数据
     pub async fn read(&mut self) -> Option<BytesMut> {
         match self.reader.try_next().await {
             Ok(ele) => ele,
             Err(err) => {
-                println!("接收响应失败：{:?}", err);
+                warn!("接收响应失败：{:?}", err);
                 None
             }
         }
     }
 
-    #[allow(dead_code)]
     /// 写入数据
-    pub async fn write(&mut self, data: Bytes) {
-        match self.writer.send(data).await {
-            Ok(_) => {
-                println!("数据发送成功");
-            }
-            Err(err) => {
-                println!("数据发送失败：{:?}", err);
-            }
+    pub async fn write(&mut self, data: Bytes) -> Result<()> {
+        self.writer.send(data).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn peer_queue_drops_oldest() {
+        let queue = PeerQueue::default();
+        for index in 0..PEER_QUEUE_SIZE {
+            assert!(queue.push(Bytes::from(index.to_string())));
         }
+        assert!(!queue.push(Bytes::from("new")));
+        assert_eq!(queue.pop().await, Bytes::from("1"));
+        let last = timeout(Duration::from_secs(1), async {
+            let mut last = Bytes::new();
+            for _ in 1..PEER_QUEUE_SIZE {
+                last = queue.pop().await;
+            }
+            last
+        })
+        .await
+        .unwrap();
+        assert_eq!(last, Bytes::from("new"));
+    }
+
+    /// 启动一个只响应一次握手和全量数据请求的集群实例
+    async fn snapshot_peer(snapshot: SnapshotResponse) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut framed = Framed::new(socket, LengthDelimitedCodec::new());
+            framed.next().await;
+            let handshake = HandshakeResponse {
+                version: VERSION,
+                codec: Codec::Json,
+                delta: false,
+                epoch: 0,
+                revision: 0,
+            };
+            framed
+                .send(handshake.to_frame(Codec::Json).unwrap().encode(Protocol::CURRENT).unwrap())
+                .await
+                .unwrap();
+            framed.next().await;
+            framed
+                .send(snapshot.to_frame(Codec::Json).unwrap().encode(Protocol::CURRENT).unwrap())
+                .await
+                .unwrap();
+            framed.next().await;
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn pull_snapshot_skips_syncing_peer() {
+        let syncing = snapshot_peer(SnapshotResponse {
+            ready: false,
+            services: HashMap::new(),
+            heartbeats: HashMap::new(),
+        })
+        .await;
+        let heartbeats = HashMap::from([("instance".to_string(), std::time::SystemTime::now())]);
+        let ready = snapshot_peer(SnapshotResponse {
+            ready: true,
+            services: HashMap::new(),
+            heartbeats: heartbeats.clone(),
+        })
+        .await;
+
+        // 未就绪的实例立即响应，不会等到超时
+        let (addr, snapshot) = timeout(
+            SNAPSHOT_TIMEOUT,
+            PeerCluster::pull_snapshot(&[syncing, ready.clone()]),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(addr, ready);
+        assert_eq!(snapshot.heartbeats, heartbeats);
     }
 }
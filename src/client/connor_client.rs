@@ -0,0 +1,571 @@
+//! 服务端 SDK：注册服务、自动发送心跳、下线服务
+//!
+//! 可以配置多个 Connor 节点，连接断开后依次重连下一个节点，重连成功后恢复订阅并重新注册所有的服务；
+//! 重连到同一个节点（事件历史相同）时，通过 Watch 补发断开期间错过的增量变更
+
+use crate::client::handshake;
+use crate::custom_error::ConnorError;
+use crate::models::request::{
+    DeregistryRequest, DiscoveryRequest, DiscoveryServiceNamesRequest, HeartbeatRequest,
+    RegistryRequest, ResyncRequest, SubscribeRequest, UnsubscribeRequest, WatchRequest,
+};
+use crate::models::response::{
+    AddServiceResponse, DeregistryResponse, DiscoveryResponse, DiscoveryServiceNamesResponse,
+    ErrorResponse, HeartbeatResponse, HeartbeatTimeoutResponse, InstanceStatusResponse, RegistryResponse,
+    RemoveServiceResponse, ResyncRequiredResponse, ResyncResponse, ServiceDeltaResponse, SubscribeResponse,
+    UnsubscribeResponse, WatchResponse,
+};
+use crate::models::{NewService, RpcCodec, RpcKind, TcpReader};
+use crate::protocol::{Codec, Frame, FrameWriter, Protocol};
+use anyhow::{anyhow, Result};
+use futures::{StreamExt, TryStreamExt};
+use parking_lot::{Mutex, RwLock};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, oneshot, Mutex as AsyncMutex};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, sleep_until, timeout, Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// 等待响应的超时时间
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// 服务端推送消息的缓冲大小
+const PUSH_CHANNEL_SIZE: usize = 1024;
+/// 重连失败后的初始等待时间，每次失败翻倍
+const RECONNECT_BACKOFF_MIN: Duration = Duration::from_millis(500);
+/// 重连失败后的最大等待时间
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(30);
+
+/// 响应的帧，或者服务端返回的错误
+type ResponseResult = Result<Frame, ConnorError>;
+
+/// 服务端主动推送的消息
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerPush {
+    /// 某服务注册了新的实例，携带该服务全部的实例
+    AddService(AddServiceResponse),
+    /// 某服务下线了实例，携带该服务剩余的实例
+    RemoveService(RemoveServiceResponse),
+    /// 心跳超时被剔除的实例
+    HeartbeatTimeout(HeartbeatTimeoutResponse),
+    /// 实例的心跳状态发生了变化
+    InstanceStatus(InstanceStatusResponse),
+    /// 某服务实例的增量变更
+    ServiceDelta(ServiceDeltaResponse),
+    /// 服务端的推送落后，丢失了部分推送，需要重新同步
+    ResyncRequired(ResyncRequiredResponse),
+    /// 连接断开后重新连接到了某个节点，断开期间的推送已经丢失
+    Reconnected(String),
+    /// 连接断开后重新连接到了同一个节点，断开期间错过的增量变更已经补发
+    Resumed(String),
+}
+
+/// 连接 Connor 的客户端
+///
+/// 注册的服务会按照心跳周期自动发送心跳，心跳响应失败（之前心跳超时被剔除）时自动重新注册；
+/// 连接断开后自动重连其它节点并重新注册；调用 shutdown 或者 drop 时下线所有注册的服务
+pub struct ConnorClient {
+    pub(super) inner: Arc<ClientInner>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+pub(super) struct ClientInner {
+    /// Connor 节点地址
+    addrs: Vec<String>,
+    /// 按照优先顺序排列的序列化格式
+    codecs: Vec<Codec>,
+    /// 当前连接，断开重连期间为 None
+    writer: AsyncMutex<Option<FrameWriter>>,
+    /// 等待响应的请求：<request id, 响应>
+    pending: Mutex<HashMap<u64, oneshot::Sender<ResponseResult>>>,
+    /// 下一个请求的 request id，0 保留给服务端推送
+    next_request_id: AtomicU64,
+    /// 已经注册的服务：<实例ID, 服务>
+    services: RwLock<HashMap<String, NewService>>,
+    /// 订阅的服务名称，None 表示没有订阅过，接收所有服务的推送
+    subscriptions: RwLock<Option<Vec<String>>>,
+    /// 转发服务端推送的消息
+    push_sender: broadcast::Sender<ServerPush>,
+    /// 已经收到的变更在服务端事件历史中的位置：(epoch, revision)
+    position: Mutex<(u64, u64)>,
+}
+
+impl ConnorClient {
+    /// 依次尝试连接 Connor 节点，连接成功后开启读取响应和发送心跳的任务
+    pub async fn connect(addrs: &[String], heartbeat_interval: Duration) -> Result<Self> {
+        Self::connect_with_codecs(addrs, heartbeat_interval, &[Codec::Json]).await
+    }
+
+    /// 同 connect，codecs 为按照优先顺序排列的消息体序列化格式，由服务端选择其中一种
+    pub async fn connect_with_codecs(
+        addrs: &[String],
+        heartbeat_interval: Duration,
+        codecs: &[Codec],
+    ) -> Result<Self> {
+        let mut connected = None;
+        for (index, addr) in addrs.iter().enumerate() {
+            match ClientInner::open(addr, codecs).await {
+                Ok(stream) => {
+                    connected = Some((index, stream));
+                    break;
+                }
+                Err(err) => warn!("Connect connor [{}] failed, err: [{:?}]", addr, err),
+            }
+        }
+        let (index, (writer, reader, position)) =
+            connected.ok_or_else(|| anyhow!("no connor available in {:?}", addrs))?;
+        let inner = Arc::new(ClientInner {
+            addrs: addrs.to_vec(),
+            codecs: codecs.to_vec(),
+            writer: AsyncMutex::new(Some(writer)),
+            pending: Mutex::new(HashMap::new()),
+            next_request_id: AtomicU64::new(1),
+            services: RwLock::new(HashMap::new()),
+            subscriptions: RwLock::new(None),
+            push_sender: broadcast::channel(PUSH_CHANNEL_SIZE).0,
+            position: Mutex::new(position),
+        });
+
+        let connection_task = tokio::spawn(inner.clone().connection_loop(index, reader));
+        let heartbeat_task = tokio::spawn(inner.clone().heartbeat_loop(heartbeat_interval));
+        Ok(Self {
+            inner,
+            tasks: vec![connection_task, heartbeat_task],
+        })
+    }
+
+    /// 注册服务，注册成功后按照服务端返回的心跳间隔为其发送心跳
+    pub async fn register(&self, service: NewService) -> Result<bool> {
+        self.inner.register(service).await
+    }
+
+    /// 下线服务，并停止为其发送心跳
+    pub async fn deregister(&self, service_name: &str, service_id: &str) -> Result<bool> {
+        self.inner.services.write().remove(service_id);
+        let deregistry_request = DeregistryRequest {
+            service_name: service_name.to_string(),
+            service_id: service_id.to_string(),
+        };
+        let response: DeregistryResponse = self.inner.request(&deregistry_request).await?;
+        Ok(response.success)
+    }
+
+    /// 根据 service-name 查询所有的实例，服务不存在时返回 None
+    pub async fn discovery(&self, service_name: &str) -> Result<Option<Vec<NewService>>> {
+        self.inner.discovery(service_name).await
+    }
+
+    /// 获取所有的 service-name
+    pub async fn service_names(&self) -> Result<Vec<String>> {
+        self.inner.service_names().await
+    }
+
+    /// 只接收指定服务的推送，名称支持通配符 `*`、`?`，返回当前订阅的所有名称
+    ///
+    /// 没有调用过时接收所有服务的推送；订阅之后 ServiceCache 也只会收到订阅的服务的变更
+    pub async fn subscribe_services(&self, service_names: &[&str]) -> Result<Vec<String>> {
+        let subscribe_request = SubscribeRequest {
+            service_names: service_names.iter().map(|name| name.to_string()).collect(),
+        };
+        let response: SubscribeResponse = self.inner.request(&subscribe_request).await?;
+        *self.inner.subscriptions.write() = Some(response.service_names.clone());
+        Ok(response.service_names)
+    }
+
+    /// 取消订阅，名称需要与订阅时一致，返回当前订阅的所有名称
+    pub async fn unsubscribe_services(&self, service_names: &[&str]) -> Result<Vec<String>> {
+        let unsubscribe_request = UnsubscribeRequest {
+            service_names: service_names.iter().map(|name| name.to_string()).collect(),
+        };
+        let response: UnsubscribeResponse = self.inner.request(&unsubscribe_request).await?;
+        if let Some(subscriptions) = self.inner.subscriptions.write().as_mut() {
+            *subscriptions = response.service_names.clone();
+        }
+        Ok(response.service_names)
+    }
+
+    /// 订阅服务端推送的消息
+    pub fn subscribe(&self) -> broadcast::Receiver<ServerPush> {
+        self.inner.push_sender.subscribe()
+    }
+
+    /// 下线所有注册的服务并关闭客户端
+    pub async fn shutdown(mut self) {
+        self.inner.deregister_all().await;
+        self.abort_tasks();
+    }
+
+    fn abort_tasks(&mut self) {
+        for task in self.tasks.drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl Drop for ConnorClient {
+    fn drop(&mut self) {
+        if self.tasks.is_empty() {
+            return;
+        }
+        let tasks = std::mem::take(&mut self.tasks);
+        // 没有调用 shutdown 时，在后台下线所有注册的服务
+        match tokio::runtime::Handle::try_current() {
+            Ok(handle) => {
+                let inner = self.inner.clone();
+                handle.spawn(async move {
+                    inner.deregister_all().await;
+                    for task in tasks {
+                        task.abort();
+                    }
+                });
+            }
+            Err(_) => {
+                warn!("No tokio runtime, skip deregistry on drop");
+                for task in tasks {
+                    task.abort();
+                }
+            }
+        }
+    }
+}
+
+impl ClientInner {
+    /// 建立连接并协商协议版本和序列化格式，服务变更使用增量推送；同时返回服务端事件历史的当前位置
+    async fn open(addr: &str, codecs: &[Codec]) -> Result<(FrameWriter, TcpReader, (u64, u64))> {
+        info!("Connect connor [{}] ....", addr);
+        let (transport, handshake) = handshake(addr, codecs, true).await?;
+        let (writer, reader) = transport.split();
+        let protocol = Protocol::Binary(handshake.version);
+        let writer = FrameWriter::new(writer, protocol, handshake.codec);
+        Ok((writer, reader, (handshake.epoch, handshake.revision)))
+    }
+
+    /// 维持连接：连接断开后从下一个节点开始依次重连，重连成功后重新注册所有的服务
+    async fn connection_loop(self: Arc<Self>, mut index: usize, mut reader: TcpReader) {
+        loop {
+            self.read_loop(reader).await;
+            {
+                let mut writer = self.writer.lock().await;
+                *writer = None;
+                // 连接断开，等待中的请求全部失败
+                self.pending.lock().clear();
+            }
+
+            // 断开之前收到的位置，新的连接握手之后的变更都会推送
+            let resume = *self.position.lock();
+            let mut backoff = RECONNECT_BACKOFF_MIN;
+            let until = loop {
+                index = (index + 1) % self.addrs.len();
+                match Self::open(&self.addrs[index], &self.codecs).await {
+                    Ok((new_writer, new_reader, position)) => {
+                        *self.writer.lock().await = Some(new_writer);
+                        *self.position.lock() = position;
+                        reader = new_reader;
+                        break position.1;
+                    }
+                    Err(err) => {
+                        warn!(
+                            "Reconnect connor [{}] failed, retry after {:?}, err: [{:?}]",
+                            self.addrs[index], backoff, err
+                        );
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(RECONNECT_BACKOFF_MAX);
+                    }
+                }
+            };
+            info!("Reconnected connor [{}]", self.addrs[index]);
+            // 重新注册需要读取响应，在单独的任务中进行
+            tokio::spawn(self.clone().replay(self.addrs[index].clone(), resume, until));
+        }
+    }
+
+    /// 在新的连接上恢复订阅、补发错过的变更、重新注册所有的服务，无法补发时通知订阅者重新同步
+    async fn replay(self: Arc<Self>, addr: String, resume: (u64, u64), until: u64) {
+        let subscriptions = self.subscriptions.read().clone();
+        if let Some(service_names) = subscriptions {
+            let subscribe_request = SubscribeRequest { service_names };
+            if let Err(err) = self
+                .request::<_, SubscribeResponse>(&subscribe_request)
+                .await
+            {
+                warn!("Resubscribe failed, err: [{:?}]", err);
+            }
+        }
+        let resumed = self.resume(resume, until).await;
+        let services = self
+            .services
+            .read()
+            .values()
+            .cloned()
+            .collect::<Vec<NewService>>();
+        for service in services {
+            let service_id = service.id.clone();
+            match self.register(service).await {
+                Ok(success) => info!("Reregistry [{}] success: {}", service_id, success),
+                Err(err) => warn!("Reregistry [{}] failed, err: [{:?}]", service_id, err),
+            }
+        }
+        let push = if resumed {
+            ServerPush::Resumed(addr)
+        } else {
+            ServerPush::Reconnected(addr)
+        };
+        let _ = self.push_sender.send(push);
+    }
+
+    /// 从服务端事件历史中补发 (epoch, revision) 之后、until 之前错过的增量变更，
+    /// until 之后的变更已经推送到新的连接；历史已经被淘汰或者连接到了其它节点时返回 false
+    async fn resume(&self, (epoch, since_revision): (u64, u64), until: u64) -> bool {
+        let watch_request = WatchRequest {
+            epoch,
+            since_revision,
+        };
+        match self.request::<_, WatchResponse>(&watch_request).await {
+            Ok(response) if !response.compacted => {
+                info!(
+                    "Resume connor events [{}] -> [{}], [{}] deltas",
+                    since_revision,
+                    until,
+                    response.deltas.len()
+                );
+                for delta in response.deltas {
+                    if delta.store_revision <= until {
+                        let _ = self.push_sender.send(ServerPush::ServiceDelta(delta));
+                    }
+                }
+                true
+            }
+            Ok(_) => {
+                info!("Connor events since [{}] are not available, resync", since_revision);
+                false
+            }
+            Err(err) => {
+                warn!("Resume connor events failed, err: [{:?}]", err);
+                false
+            }
+        }
+    }
+
+    /// 发送请求并等待 request id 对应的响应，同一连接上可以同时有多个未完成的请求
+    async fn request<Req, Resp>(&self, request: &Req) -> Result<Resp>
+    where
+        Req: RpcCodec + Serialize,
+        Resp: RpcCodec + DeserializeOwned,
+    {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut writer = self.writer.lock().await;
+            let writer = writer
+                .as_mut()
+                .ok_or_else(|| anyhow!("connor is reconnecting"))?;
+            self.pending.lock().insert(request_id, sender);
+            let frame = request.to_frame(writer.codec())?.with_request_id(request_id);
+            if let Err(err) = writer.send(&frame).await {
+                self.pending.lock().remove(&request_id);
+                return Err(err.into());
+            }
+        }
+        let frame = match timeout(REQUEST_TIMEOUT, receiver).await {
+            Ok(response) => response??,
+            Err(_) => {
+                self.pending.lock().remove(&request_id);
+                return Err(anyhow!(
+                    "wait [{:?}] response [{}] timeout",
+                    Req::rpc_kind(),
+                    request_id
+                ));
+            }
+        };
+        Ok(*Resp::from_frame(&frame)?)
+    }
+
+    pub(super) async fn discovery(&self, service_name: &str) -> Result<Option<Vec<NewService>>> {
+        let discovery_request = DiscoveryRequest {
+            service_name: service_name.to_string(),
+        };
+        let response: DiscoveryResponse = self.request(&discovery_request).await?;
+        Ok(response.services)
+    }
+
+    pub(super) async fn service_names(&self) -> Result<Vec<String>> {
+        let response: DiscoveryServiceNamesResponse =
+            self.request(&DiscoveryServiceNamesRequest {}).await?;
+        Ok(response.service_names)
+    }
+
+    /// 某服务全部的实例及其当前的 revision
+    pub(super) async fn resync(&self, service_name: &str) -> Result<ResyncResponse> {
+        let resync_request = ResyncRequest {
+            service_name: service_name.to_string(),
+        };
+        self.request(&resync_request).await
+    }
+
+    /// 注册成功后记录服务端修正之后的心跳超时时间和心跳间隔，之后按照修正的间隔发送心跳
+    async fn register(&self, mut service: NewService) -> Result<bool> {
+        let registry_request = RegistryRequest {
+            service: service.clone(),
+        };
+        let response: RegistryResponse = self.request(&registry_request).await?;
+        if response.success {
+            service.ttl = response.ttl.or(service.ttl);
+            service.heartbeat_interval = response.heartbeat_interval.or(service.heartbeat_interval);
+            self.services.write().insert(service.id.clone(), service);
+        }
+        Ok(response.success)
+    }
+
+    async fn deregister_all(&self) {
+        let services = self
+            .services
+            .write()
+            .drain()
+            .map(|(_, service)| service)
+            .collect::<Vec<NewService>>();
+        for service in services {
+            let deregistry_request = DeregistryRequest {
+                service_name: service.name.clone(),
+                service_id: service.id.clone(),
+            };
+            match self
+                .request::<_, DeregistryResponse>(&deregistry_request)
+                .await
+            {
+                Ok(response) => info!("Deregistry [{}] success: {}", service.id, response.success),
+                Err(err) => warn!("Deregistry [{}] failed, err: [{:?}]", service.id, err),
+            }
+        }
+    }
+
+    /// 读取服务端发来的数据，将响应交给等待中的请求，连接断开时返回
+    async fn read_loop(&self, mut reader: TcpReader) {
+        loop {
+            let frame = match reader.try_next().await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!("Read connor response failed, err: [{:?}]", err);
+                    break;
+                }
+            };
+            let frame = match Frame::decode(frame.freeze()) {
+                Ok((_, frame)) => frame,
+                Err(err) => {
+                    warn!("Unknown connor response, err: [{}]", err);
+                    continue;
+                }
+            };
+            if frame.is_push() {
+                if let Some(push) = Self::parse_push(&frame) {
+                    if let ServerPush::ServiceDelta(delta) = &push {
+                        let mut position = self.position.lock();
+                        position.1 = position.1.max(delta.store_revision);
+                    }
+                    // 没有订阅者时直接丢弃
+                    let _ = self.push_sender.send(push);
+                }
+                continue;
+            }
+            let sender = self.pending.lock().remove(&frame.request_id);
+            let result = match frame.rpc_kind {
+                RpcKind::Error => Err(Self::parse_error(&frame)),
+                _ => Ok(frame),
+            };
+            match (sender, result) {
+                (Some(sender), result) => {
+                    let _ = sender.send(result);
+                }
+                (None, Err(error)) => warn!("Connor rejected a frame, err: [{}]", error),
+                (None, Ok(frame)) => debug!("Ignore connor message {:?}", frame),
+            }
+        }
+        warn!("Connor connection closed");
+    }
+
+    /// 解析错误响应
+    fn parse_error(frame: &Frame) -> ConnorError {
+        match ErrorResponse::from_frame(frame) {
+            Ok(response) => ConnorError::Remote {
+                code: response.code,
+                message: response.message,
+            },
+            Err(err) => err,
+        }
+    }
+
+    /// 解析服务端主动推送的消息，不是推送类型时返回 None
+    fn parse_push(frame: &Frame) -> Option<ServerPush> {
+        let push = match frame.rpc_kind {
+            RpcKind::AddService => {
+                AddServiceResponse::from_frame(frame).map(|push| ServerPush::AddService(*push))
+            }
+            RpcKind::RemoveService => RemoveServiceResponse::from_frame(frame)
+                .map(|push| ServerPush::RemoveService(*push)),
+            RpcKind::HeartbeatTimeout => HeartbeatTimeoutResponse::from_frame(frame)
+                .map(|push| ServerPush::HeartbeatTimeout(*push)),
+            RpcKind::InstanceStatus => InstanceStatusResponse::from_frame(frame)
+                .map(|push| ServerPush::InstanceStatus(*push)),
+            RpcKind::ServiceDelta => ServiceDeltaResponse::from_frame(frame)
+                .map(|push| ServerPush::ServiceDelta(*push)),
+            RpcKind::ResyncRequired => ResyncRequiredResponse::from_frame(frame)
+                .map(|push| ServerPush::ResyncRequired(*push)),
+            _ => return None,
+        };
+        push.map_err(|err| warn!("Parse connor push {:?} failed, err: [{}]", frame, err))
+            .ok()
+    }
+
+    /// 定时为已经注册的服务发送心跳，心跳失败的服务重新注册
+    ///
+    /// 按照服务端为每个服务返回的心跳间隔发送，服务端没有返回时使用服务注册时声明的间隔，都没有时使用 heartbeat_interval
+    async fn heartbeat_loop(self: Arc<Self>, heartbeat_interval: Duration) {
+        // 每个服务下一次发送心跳的时间
+        let mut next_beats = HashMap::<String, Instant>::new();
+        loop {
+            let services = self
+                .services
+                .read()
+                .values()
+                .cloned()
+                .collect::<Vec<NewService>>();
+            next_beats.retain(|id, _| services.iter().any(|service| service.id.eq(id)));
+            let now = Instant::now();
+            for service in services {
+                if next_beats.get(&service.id).is_some_and(|next| *next > now) {
+                    continue;
+                }
+                let service_interval = service
+                    .heartbeat_interval
+                    .map(Duration::from_secs)
+                    .unwrap_or(heartbeat_interval);
+                next_beats.insert(service.id.clone(), now + service_interval);
+                let heartbeat_request = HeartbeatRequest {
+                    service_id: service.id.clone(),
+                };
+                match self
+                    .request::<_, HeartbeatResponse>(&heartbeat_request)
+                    .await
+                {
+                    Ok(response) if response.success => {}
+                    Ok(_) => {
+                        warn!("Heartbeat [{}] expired, reregistry", service.id);
+                        if let Err(err) = self.register(service).await {
+                            warn!("Reregistry failed, err: [{:?}]", err);
+                        }
+                    }
+                    Err(err) => warn!("Heartbeat [{}] failed, err: [{:?}]", service.id, err),
+                }
+            }
+            let next = next_beats
+                .values()
+                .min()
+                .copied()
+                .unwrap_or_else(|| Instant::now() + heartbeat_interval);
+            sleep_until(next).await;
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! 客户端负载均衡：从服务发现缓存的实例中选择一个实例
+//!
+//! 实例列表来自 ServiceCache，心跳超时（HeartbeatTimeout 推送）的实例已经从缓存中移除，DOWN 状态的实例不会被选中
+
+use crate::models::{InstanceStatus, NewService};
+use parking_lot::Mutex;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// 实例选择策略
+pub trait LoadBalance: Send + Sync {
+    /// 从实例列表中选择一个实例，key 为调用方的路由键（一致性哈希使用）
+    fn select(&self, instances: &[NewService], key: Option<&str>) -> Option<NewService>;
+
+    /// 对选中实例的调用结束
+    fn release(&self, _service: &NewService) {}
+}
+
+/// 选中的实例，drop 时通知策略本次调用结束
+pub struct Picked {
+    service: NewService,
+    balancer: Arc<dyn LoadBalance>,
+}
+impl Deref for Picked {
+    type Target = NewService;
+
+    fn deref(&self) -> &Self::Target {
+        &self.service
+    }
+}
+impl Drop for Picked {
+    fn drop(&mut self) {
+        self.balancer.release(&self.service);
+    }
+}
+
+/// 使用指定的策略选择实例，跳过 DOWN 状态的实例
+pub fn pick(
+    balancer: &Arc<dyn LoadBalance>,
+    instances: &[NewService],
+    key: Option<&str>,
+) -> Option<Picked> {
+    let instances = instances
+        .iter()
+        .filter(|service| service.status != InstanceStatus::Down)
+        .cloned()
+        .collect::<Vec<NewService>>();
+    balancer.select(&instances, key).map(|service| Picked {
+        service,
+        balancer: balancer.clone(),
+    })
+}
+
+/// 轮询
+#[derive(Default)]
+pub struct RoundRobin {
+    counter: AtomicUsize,
+}
+impl LoadBalance for RoundRobin {
+    fn select(&self, instances: &[NewService], _key: Option<&str>) -> Option<NewService> {
+        if instances.is_empty() {
+            return None;
+        }
+        let index = self.counter.fetch_add(1, Ordering::Relaxed) % instances.len();
+        instances.get(index).cloned()
+    }
+}
+
+/// 随机
+#[derive(Default)]
+pub struct Random;
+impl LoadBalance for Random {
+    fn select(&self, instances: &[NewService], _key: Option<&str>) -> Option<NewService> {
+        if instances.is_empty() {
+            return None;
+        }
+        let index = rand::thread_rng().gen_range(0..instances.len());
+        instances.get(index).cloned()
+    }
+}
+
+/// 按照 meta 中的权重随机选择，没有配置或者无法解析权重的实例使用默认权重
+pub struct Weighted {
+    meta_key: String,
+    default_weight: u32,
+}
+impl Weighted {
+    pub fn new(meta_key: &str, default_weight: u32) -> Self {
+        Self {
+            meta_key: meta_key.to_string(),
+            default_weight,
+        }
+    }
+
+    fn weight(&self, service: &NewService) -> u32 {
+        service
+            .meta
+            .as_ref()
+            .and_then(|meta| meta.get(&self.meta_key))
+            .and_then(|weight| weight.parse::<u32>().ok())
+            .unwrap_or(self.default_weight)
+    }
+}
+impl LoadBalance for Weighted {
+    fn select(&self, instances: &[NewService], _key: Option<&str>) -> Option<NewService> {
+        let total = instances
+            .iter()
+            .map(|service| self.weight(service) as u64)
+            .sum::<u64>();
+        if total == 0 {
+            return None;
+        }
+        let mut point = rand::thread_rng().gen_range(0..total);
+        for service in instances {
+            let weight = self.weight(service) as u64;
+            if point < weight {
+                return Some(service.clone());
+            }
+            point -= weight;
+        }
+        None
+    }
+}
+
+/// 选择当前未完成调用最少的实例
+#[derive(Default)]
+pub struct LeastOutstanding {
+    /// <实例ID, 未完成的调用数>
+    outstanding: Mutex<HashMap<String, usize>>,
+}
+impl LoadBalance for LeastOutstanding {
+    fn select(&self, instances: &[NewService], _key: Option<&str>) -> Option<NewService> {
+        let mut outstanding = self.outstanding.lock();
+        let service = instances
+            .iter()
+            .min_by_key(|service| outstanding.get(&service.id).cloned().unwrap_or(0))?;
+        *outstanding.entry(service.id.clone()).or_insert(0) += 1;
+        Some(service.clone())
+    }
+
+    fn release(&self, service: &NewService) {
+        let mut outstanding = self.outstanding.lock();
+        if let Some(count) = outstanding.get_mut(&service.id) {
+            *count -= 1;
+            if *count == 0 {
+                outstanding.remove(&service.id);
+            }
+        }
+    }
+}
+
+/// 根据调用方的 key 做一致性哈希，实例变化时只有少量 key 会迁移
+///
+/// 未提供 key 时退化为随机选择
+pub struct ConsistentHash {
+    /// 每个实例在哈希环上的虚拟节点数
+    virtual_nodes: usize,
+    /// 缓存的哈希环：(构建哈希环的实例ID, <哈希值, 实例ID>)
+    ring: Mutex<(Vec<String>, BTreeMap<u64, String>)>,
+}
+impl ConsistentHash {
+    pub fn new(virtual_nodes: usize) -> Self {
+        Self {
+            virtual_nodes: virtual_nodes.max(1),
+            ring: Mutex::new((vec![], BTreeMap::new())),
+        }
+    }
+}
+impl Default for ConsistentHash {
+    fn default() -> Self {
+        Self::new(160)
+    }
+}
+impl LoadBalance for ConsistentHash {
+    fn select(&self, instances: &[NewService], key: Option<&str>) -> Option<NewService> {
+        let key = match key {
+            Some(key) => key,
+            None => return Random.select(instances, None),
+        };
+        let mut ids = instances
+            .iter()
+            .map(|service| service.id.clone())
+            .collect::<Vec<String>>();
+        ids.sort();
+
+        let mut ring = self.ring.lock();
+        if ring.0 != ids {
+            let nodes = ids
+                .iter()
+                .flat_map(|id| {
+                    (0..self.virtual_nodes)
+                        .map(move |node| (fnv1a(&format!("{}#{}", id, node)), id.clone()))
+                })
+                .collect::<BTreeMap<u64, String>>();
+            *ring = (ids, nodes);
+        }
+        let hash = fnv1a(key);
+        let id = ring
+            .1
+            .range(hash..)
+            .next()
+            .or_else(|| ring.1.iter().next())
+            .map(|(_, id)| id)?;
+        instances.iter().find(|service| &service.id == id).cloned()
+    }
+}
+
+/// FNV-1a 哈希，不同进程和版本之间结果稳定
+fn fnv1a(content: &str) -> u64 {
+    content.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn instances(count: usize) -> Vec<NewService> {
+        (0..count)
+            .map(|index| NewService {
+                id: format!("id-{}", index),
+                name: "service-a".to_string(),
+                port: 8000 + index as u32,
+                host: "127.0.0.1".to_string(),
+                meta: Some(HashMap::from([("weight".to_string(), index.to_string())])),
+                status: InstanceStatus::Up,
+                ttl: None,
+                heartbeat_interval: None,
+                health_check: None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let instances = instances(3);
+        let balancer = RoundRobin::default();
+        let ids = (0..4)
+            .map(|_| balancer.select(&instances, None).unwrap().id)
+            .collect::<Vec<String>>();
+        assert_eq!(ids, vec!["id-0", "id-1", "id-2", "id-0"]);
+        assert!(balancer.select(&[], None).is_none());
+    }
+
+    #[test]
+    fn weighted_skips_zero_weight() {
+        let instances = instances(3);
+        let balancer = Weighted::new("weight", 1);
+        for _ in 0..100 {
+            assert_ne!(balancer.select(&instances, None).unwrap().id, "id-0");
+        }
+    }
+
+    #[test]
+    fn least_outstanding() {
+        let instances = instances(2);
+        let balancer: Arc<dyn LoadBalance> = Arc::new(LeastOutstanding::default());
+        let first = pick(&balancer, &instances, None).unwrap();
+        let second = pick(&balancer, &instances, None).unwrap();
+        assert_ne!(first.id, second.id);
+        let first_id = first.id.clone();
+        drop(first);
+        assert_eq!(pick(&balancer, &instances, None).unwrap().id, first_id);
+    }
+
+    #[test]
+    fn consistent_hash_is_stable() {
+        let balancer = ConsistentHash::default();
+        let all = instances(5);
+        let picked = balancer.select(&all, Some("user-42")).unwrap();
+        assert_eq!(balancer.select(&all, Some("user-42")).unwrap(), picked);
+
+        // 移除其它实例，key 仍然落在原来的实例上
+        let remain = all
+            .iter()
+            .filter(|service| service.id == picked.id || service.id == "id-0")
+            .cloned()
+            .collect::<Vec<NewService>>();
+        assert_eq!(balancer.select(&remain, Some("user-42")).unwrap(), picked);
+    }
+}
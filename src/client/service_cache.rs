@@ -0,0 +1,338 @@
+//! 客户端服务发现缓存
+//!
+//! 启动时拉取所有服务的实例及其 revision，之后根据服务端推送的增量变更（ServiceDelta）保持同步；
+//! 某服务收到的 revision 不连续时重新拉取该服务的实例，
+//! 客户端重连到其它节点或者推送积压丢失（本地或者服务端）时，重新拉取所有服务的实例；
+//! 重连到同一个节点时，断开期间错过的增量变更由客户端补发，不需要重新拉取
+
+use crate::client::connor_client::{ClientInner, ConnorClient, ServerPush};
+use crate::client::load_balancer::{pick, LoadBalance, Picked};
+use crate::models::response::{ResyncResponse, ServiceDeltaResponse};
+use crate::models::NewService;
+use anyhow::Result;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::BroadcastStream;
+use tracing::{info, warn};
+
+/// 变更通知的缓冲大小
+const CHANGE_CHANNEL_SIZE: usize = 1024;
+
+type ServiceInstances = Arc<RwLock<HashMap<String, Vec<NewService>>>>;
+/// 每个服务已经应用的 revision：<service-name, revision>，没有记录的服务为 0
+type Revisions = HashMap<String, u64>;
+
+/// 某个服务的实例发生了变化
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceChange {
+    pub service_name: String,
+    /// 变化之后的全部实例
+    pub services: Vec<NewService>,
+}
+
+/// 服务发现缓存：<service-name, 实例列表>
+pub struct ServiceCache {
+    services: ServiceInstances,
+    change_sender: broadcast::Sender<ServiceChange>,
+    task: JoinHandle<()>,
+}
+
+impl ServiceCache {
+    /// 拉取所有服务的实例作为初始数据，并开始监听服务端的推送
+    pub async fn start(client: &ConnorClient) -> Result<Self> {
+        // 先订阅推送，避免拉取期间的变更丢失
+        let push_receiver = client.subscribe();
+        let mut seed = HashMap::new();
+        let mut revisions = Revisions::new();
+        for fetched in Self::fetch(&client.inner, vec![]).await? {
+            revisions.insert(fetched.service_name.clone(), fetched.revision);
+            if !fetched.services.is_empty() {
+                seed.insert(fetched.service_name, fetched.services);
+            }
+        }
+        info!("Service cache seeded with [{}] services", seed.len());
+
+        let services = Arc::new(RwLock::new(seed));
+        let (change_sender, _) = broadcast::channel(CHANGE_CHANNEL_SIZE);
+        let task = tokio::spawn(Self::listen(
+            client.inner.clone(),
+            push_receiver,
+            services.clone(),
+            revisions,
+            change_sender.clone(),
+        ));
+        Ok(Self {
+            services,
+            change_sender,
+            task,
+        })
+    }
+
+    /// 获取某个服务缓存的实例
+    pub fn instances(&self, service_name: &str) -> Vec<NewService> {
+        self.services
+            .read()
+            .get(service_name)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// 使用指定的负载均衡策略从某个服务缓存的实例中选择一个实例
+    pub fn pick(
+        &self,
+        service_name: &str,
+        balancer: &Arc<dyn LoadBalance>,
+        key: Option<&str>,
+    ) -> Option<Picked> {
+        let services = self.services.read();
+        pick(balancer, services.get(service_name)?, key)
+    }
+
+    /// 缓存中所有的 service-name
+    pub fn service_names(&self) -> Vec<String> {
+        self.services.read().keys().cloned().collect()
+    }
+
+    /// 服务实例的变更通知
+    pub fn changes(&self) -> BroadcastStream<ServiceChange> {
+        BroadcastStream::new(self.change_sender.subscribe())
+    }
+
+    /// 拉取服务端所有的服务以及 extra_names 中服务的实例及其 revision，服务不存在时对应的实例列表为空
+    async fn fetch(client: &ClientInner, extra_names: Vec<String>) -> Result<Vec<ResyncResponse>> {
+        let mut service_names = client.service_names().await?;
+        for service_name in extra_names {
+            if !service_names.contains(&service_name) {
+                service_names.push(service_name);
+            }
+        }
+        let mut fetched = vec![];
+        for service_name in service_names {
+            fetched.push(client.resync(&service_name).await?);
+        }
+        Ok(fetched)
+    }
+
+    /// 重新拉取所有服务的实例替换缓存，返回发生变化的服务
+    async fn resync(
+        client: &ClientInner,
+        services: &ServiceInstances,
+        revisions: &mut Revisions,
+    ) -> Vec<ServiceChange> {
+        let cached_names = services.read().keys().cloned().collect();
+        let fetched = match Self::fetch(client, cached_names).await {
+            Ok(fetched) => fetched,
+            Err(err) => {
+                warn!("Service cache resync failed, err: [{:?}]", err);
+                return vec![];
+            }
+        };
+        info!("Service cache resynced [{}] services", fetched.len());
+        // 重连后 revision 以新节点为准
+        revisions.clear();
+        let mut services = services.write();
+        fetched
+            .into_iter()
+            .filter_map(|fetched| Self::replace(&mut services, revisions, fetched))
+            .collect()
+    }
+
+    /// 重新拉取某个服务的实例替换缓存
+    async fn resync_service(
+        client: &ClientInner,
+        services: &ServiceInstances,
+        revisions: &mut Revisions,
+        service_name: &str,
+    ) -> Vec<ServiceChange> {
+        match client.resync(service_name).await {
+            Ok(fetched) => {
+                info!(
+                    "Service cache resynced [{}] revision [{}]",
+                    service_name, fetched.revision
+                );
+                Self::replace(&mut services.write(), revisions, fetched)
+                    .into_iter()
+                    .collect()
+            }
+            Err(err) => {
+                warn!(
+                    "Service cache resync [{}] failed, err: [{:?}]",
+                    service_name, err
+                );
+                vec![]
+            }
+        }
+    }
+
+    /// 使用拉取的实例替换缓存，实例发生变化时返回变更
+    fn replace(
+        services: &mut HashMap<String, Vec<NewService>>,
+        revisions: &mut Revisions,
+        fetched: ResyncResponse,
+    ) -> Option<ServiceChange> {
+        let ResyncResponse {
+            service_name,
+            revision,
+            services: list,
+        } = fetched;
+        revisions.insert(service_name.clone(), revision);
+        let cached = services.get(&service_name).map(Vec::as_slice);
+        if cached.unwrap_or_default() == list.as_slice() {
+            return None;
+        }
+        if list.is_empty() {
+            services.remove(&service_name);
+        } else {
+            services.insert(service_name.clone(), list.clone());
+        }
+        Some(ServiceChange {
+            service_name,
+            services: list,
+        })
+    }
+
+    async fn listen(
+        client: Arc<ClientInner>,
+        mut push_receiver: broadcast::Receiver<ServerPush>,
+        services: ServiceInstances,
+        mut revisions: Revisions,
+        change_sender: broadcast::Sender<ServiceChange>,
+    ) {
+        loop {
+            let changes = match push_receiver.recv().await {
+                Ok(ServerPush::Reconnected(addr)) => {
+                    info!("Connor reconnected to [{}], resync service cache", addr);
+                    Self::resync(&client, &services, &mut revisions).await
+                }
+                Ok(ServerPush::Resumed(addr)) => {
+                    info!("Connor resumed on [{}]", addr);
+                    vec![]
+                }
+                Ok(ServerPush::ServiceDelta(delta)) => {
+                    let revision = revisions.get(&delta.service_name).cloned().unwrap_or(0);
+                    if delta.revision <= revision {
+                        // 拉取实例之前就已经包含的变更
+                        vec![]
+                    } else if delta.revision == revision + 1 {
+                        revisions.insert(delta.service_name.clone(), delta.revision);
+                        Self::apply_delta(&services, delta)
+                    } else {
+                        warn!(
+                            "Service [{}] revision gap [{}] -> [{}], resync",
+                            delta.service_name, revision, delta.revision
+                        );
+                        Self::resync_service(
+                            &client,
+                            &services,
+                            &mut revisions,
+                            &delta.service_name,
+                        )
+                        .await
+                    }
+                }
+                Ok(ServerPush::ResyncRequired(response)) => {
+                    warn!("Connor lagged [{}] pushes, resync", response.lagged);
+                    Self::resync(&client, &services, &mut revisions).await
+                }
+                Ok(push) => Self::apply(&services, push),
+                Err(RecvError::Lagged(count)) => {
+                    warn!("Service cache lagged [{}] pushes, resync", count);
+                    Self::resync(&client, &services, &mut revisions).await
+                }
+                Err(RecvError::Closed) => return,
+            };
+            for change in changes {
+                // 没有订阅者时直接丢弃
+                let _ = change_sender.send(change);
+            }
+        }
+    }
+
+    /// 将增量变更应用到缓存
+    fn apply_delta(services: &ServiceInstances, delta: ServiceDeltaResponse) -> Vec<ServiceChange> {
+        let mut services = services.write();
+        let list = services.entry(delta.service_name.clone()).or_default();
+        delta.apply(list);
+        let change = ServiceChange {
+            service_name: delta.service_name,
+            services: list.clone(),
+        };
+        if change.services.is_empty() {
+            services.remove(&change.service_name);
+        }
+        vec![change]
+    }
+
+    /// 将全量推送应用到缓存，返回发生变化的服务
+    fn apply(services: &ServiceInstances, push: ServerPush) -> Vec<ServiceChange> {
+        let mut services = services.write();
+        match push {
+            ServerPush::AddService(response) => {
+                services.insert(response.service_name.clone(), response.service_list.clone());
+                vec![ServiceChange {
+                    service_name: response.service_name,
+                    services: response.service_list,
+                }]
+            }
+            ServerPush::RemoveService(response) => {
+                if response.service_list.is_empty() {
+                    services.remove(&response.service_name);
+                } else {
+                    services.insert(response.service_name.clone(), response.service_list.clone());
+                }
+                vec![ServiceChange {
+                    service_name: response.service_name,
+                    services: response.service_list,
+                }]
+            }
+            ServerPush::Reconnected(_)
+            | ServerPush::Resumed(_)
+            | ServerPush::ServiceDelta(_)
+            | ServerPush::ResyncRequired(_) => vec![],
+            ServerPush::HeartbeatTimeout(response) => {
+                let timeout_ids = &response.timeout_service_ids;
+                services
+                    .iter_mut()
+                    .filter(|(_, list)| {
+                        list.iter().any(|service| timeout_ids.contains(&service.id))
+                    })
+                    .map(|(service_name, list)| {
+                        list.retain(|service| !timeout_ids.contains(&service.id));
+                        ServiceChange {
+                            service_name: service_name.clone(),
+                            services: list.clone(),
+                        }
+                    })
+                    .collect()
+            }
+            ServerPush::InstanceStatus(response) => {
+                let service_ids = &response.service_ids;
+                services
+                    .iter_mut()
+                    .filter(|(_, list)| {
+                        list.iter().any(|service| service_ids.contains(&service.id))
+                    })
+                    .map(|(service_name, list)| {
+                        list.iter_mut()
+                            .filter(|service| service_ids.contains(&service.id))
+                            .for_each(|service| service.status = response.status);
+                        ServiceChange {
+                            service_name: service_name.clone(),
+                            services: list.clone(),
+                        }
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Drop for ServiceCache {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
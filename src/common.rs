@@ -1,3 +1,4 @@
 pub mod custom_error;
 pub mod models;
 pub mod config;
+pub mod protocol;
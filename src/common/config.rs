@@ -18,6 +18,145 @@ pub struct ServerConfig {
     /// 当前服务器的名称标识
     pub server_address: String,
     pub cluster_address: Vec<String>,
+    #[serde(default)]
+    pub cluster_mode: ClusterMode,
+    /// gossip 模式下与其它实例交换摘要的周期（秒）
+    #[serde(default = "default_gossip_interval")]
+    pub gossip_interval: u64,
+    /// raft 模式下持久化任期、投票、日志和快照的目录
+    #[serde(default = "default_raft_data_dir")]
+    pub raft_data_dir: String,
+    /// raft 模式下已应用的日志超过该条数时压缩为快照
+    #[serde(default = "default_raft_snapshot_threshold")]
+    pub raft_snapshot_threshold: u64,
+    /// HTTP REST 接口的监听地址，不配置时不开启
+    #[serde(default)]
+    pub http_address: Option<String>,
+    /// gRPC 接口的监听地址，不配置时不开启
+    #[serde(default)]
+    pub grpc_address: Option<String>,
+    /// DNS 接口（UDP）的监听地址，不配置时不开启
+    #[serde(default)]
+    pub dns_address: Option<String>,
+    /// DNS 响应记录的 TTL（秒）
+    #[serde(default = "default_dns_ttl")]
+    pub dns_ttl: u32,
+    /// 事件历史最多保存的增量变更个数，断线重连的客户端从中获取错过的变更
+    #[serde(default = "default_event_history_size")]
+    pub event_history_size: usize,
+    /// 服务变更广播通道的容量，连接的推送落后超过该值时需要重新同步
+    #[serde(default = "default_broadcast_capacity")]
+    pub broadcast_capacity: usize,
+    /// 实例心跳超时时间（秒）的默认值，注册时没有携带 ttl 的实例使用
+    #[serde(default = "default_heartbeat_ttl")]
+    pub heartbeat_ttl: u64,
+    /// 实例可以声明的心跳超时时间（秒）的下限
+    #[serde(default = "default_min_heartbeat_ttl")]
+    pub min_heartbeat_ttl: u64,
+    /// 实例可以声明的心跳超时时间（秒）的上限
+    #[serde(default = "default_max_heartbeat_ttl")]
+    pub max_heartbeat_ttl: u64,
+    /// 实例可以声明的心跳间隔（秒）的下限
+    #[serde(default = "default_min_heartbeat_interval")]
+    pub min_heartbeat_interval: u64,
+    /// 实例可以声明的心跳间隔（秒）的上限
+    #[serde(default = "default_max_heartbeat_interval")]
+    pub max_heartbeat_interval: u64,
+    /// 是否开启自我保护：心跳超时的实例占比过高时暂停剔除实例
+    #[serde(default = "default_self_preservation")]
+    pub self_preservation: bool,
+    /// 时间窗口内心跳超时的实例占比超过该值时进入自我保护
+    #[serde(default = "default_self_preservation_threshold")]
+    pub self_preservation_threshold: f64,
+    /// 统计心跳超时实例的时间窗口（秒）
+    #[serde(default = "default_self_preservation_window")]
+    pub self_preservation_window: u64,
+    /// 实例个数少于该值时不进入自我保护
+    #[serde(default = "default_self_preservation_min_instances")]
+    pub self_preservation_min_instances: usize,
+    /// 是否允许实例注册脚本健康检查（在服务端执行命令），默认关闭
+    #[serde(default)]
+    pub script_health_check: bool,
+    /// 主动探测实例的超时时间（秒）
+    #[serde(default = "default_health_check_timeout")]
+    pub health_check_timeout: u64,
+}
+
+fn default_gossip_interval() -> u64 {
+    5
+}
+
+fn default_raft_data_dir() -> String {
+    "raft".to_string()
+}
+
+fn default_raft_snapshot_threshold() -> u64 {
+    1024
+}
+
+fn default_dns_ttl() -> u32 {
+    5
+}
+
+fn default_event_history_size() -> usize {
+    4096
+}
+
+fn default_broadcast_capacity() -> usize {
+    1024
+}
+
+fn default_heartbeat_ttl() -> u64 {
+    90
+}
+
+fn default_min_heartbeat_ttl() -> u64 {
+    4
+}
+
+fn default_max_heartbeat_ttl() -> u64 {
+    600
+}
+
+fn default_min_heartbeat_interval() -> u64 {
+    1
+}
+
+fn default_max_heartbeat_interval() -> u64 {
+    60
+}
+
+fn default_self_preservation() -> bool {
+    true
+}
+
+fn default_self_preservation_threshold() -> f64 {
+    0.5
+}
+
+fn default_self_preservation_window() -> u64 {
+    180
+}
+
+fn default_self_preservation_min_instances() -> usize {
+    3
+}
+
+fn default_health_check_timeout() -> u64 {
+    3
+}
+
+/// 集群间数据变更的提交方式
+#[derive(Debug, serde_derive::Deserialize, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ClusterMode {
+    /// 本地应用后异步复制到其它实例
+    #[default]
+    Replicate,
+    /// CP：通过 Raft 复制日志提交，多数实例确认后才会响应客户端
+    Raft,
+    /// AP：本地写入后通过 gossip 定期交换摘要，最终一致
+    Gossip,
 }
 
 impl ServerConfig {
@@ -30,6 +169,21 @@ impl ServerConfig {
         config.try_deserialize::<ServerConfig>().expect(err_msg)
     }
 
+    /// 按照配置的上下限修正实例声明的心跳超时时间和心跳间隔，返回 (ttl, heartbeat_interval)
+    ///
+    /// 心跳间隔不超过 ttl 的 1/4，与错过心跳 ttl 的 1/3 变为 SUSPECT 之间留有余量，正常发送心跳的实例不会被标记为 SUSPECT
+    pub fn heartbeat_policy(&self, ttl: Option<u64>, heartbeat_interval: Option<u64>) -> (u64, u64) {
+        let ttl = ttl
+            .unwrap_or(self.heartbeat_ttl)
+            .clamp(self.min_heartbeat_ttl, self.max_heartbeat_ttl.max(self.min_heartbeat_ttl));
+        let heartbeat_interval = heartbeat_interval
+            .unwrap_or(ttl / 4)
+            .clamp(self.min_heartbeat_interval, self.max_heartbeat_interval.max(self.min_heartbeat_interval))
+            .min(ttl / 4)
+            .max(1);
+        (ttl, heartbeat_interval)
+    }
+
     /// 获取配置文件path
     fn get_conf_path() -> PathBuf  {
         let work_dir = std::env::current_exe().unwrap();
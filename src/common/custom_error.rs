@@ -2,26 +2,82 @@
 
 use std::fmt::{Display, Formatter};
 
-pub struct Byte2JsonErr;
-
-impl Display for Byte2JsonErr {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Byte To Json Fail ！")
-    }
+/// Connor 的错误类型，服务端处理请求失败时以错误响应的形式返回给客户端
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnorError {
+    /// 收到的数据不是 utf-8 编码
+    Byte2Json,
+    /// 无法识别的 kind 头标识
+    UnknownKind(String),
+    /// json 转换为请求实体失败
+    Json2Struct(String),
+    /// 实体转换为 json 失败
+    Struct2Json(String),
+    /// 服务端不处理此类型的请求
+    Unsupported(String),
+    /// 帧头部不完整，携带实际长度
+    BadHeader(usize),
+    /// 不支持的协议版本
+    UnsupportedVersion(u8),
+    /// 不支持的序列化格式
+    UnsupportedCodec(u8),
+    /// 实例ID已经注册在其它服务下
+    IdConflict {
+        service_id: String,
+        service_name: String,
+    },
+    /// 服务端没有开启脚本健康检查
+    ScriptCheckDisabled,
+    /// 服务端返回的错误
+    Remote { code: u16, message: String },
 }
 
-pub struct Struct2JsonErr;
-
-impl Display for Struct2JsonErr {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Struct To Json Fail ！")
+impl ConnorError {
+    /// 错误码
+    pub fn code(&self) -> u16 {
+        match self {
+            ConnorError::Byte2Json => 1,
+            ConnorError::UnknownKind(_) => 2,
+            ConnorError::Json2Struct(_) => 3,
+            ConnorError::Struct2Json(_) => 4,
+            ConnorError::Unsupported(_) => 5,
+            ConnorError::BadHeader(_) => 6,
+            ConnorError::UnsupportedVersion(_) => 7,
+            ConnorError::UnsupportedCodec(_) => 8,
+            ConnorError::IdConflict { .. } => 9,
+            ConnorError::ScriptCheckDisabled => 10,
+            ConnorError::Remote { code, .. } => *code,
+        }
     }
 }
 
-pub struct Json2StructErr;
-
-impl Display for Json2StructErr {
+impl Display for ConnorError {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Json To Struct Fail ！")
+        match self {
+            ConnorError::Byte2Json => write!(f, "Byte To Json Fail ！"),
+            ConnorError::UnknownKind(kind) => write!(f, "Unknown RpcKind [{}] ！", kind),
+            ConnorError::Json2Struct(err) => write!(f, "Json To Struct Fail ！{}", err),
+            ConnorError::Struct2Json(err) => write!(f, "Struct To Json Fail ！{}", err),
+            ConnorError::Unsupported(kind) => write!(f, "Unsupported RpcKind [{}] ！", kind),
+            ConnorError::BadHeader(len) => write!(f, "Bad Frame Header, length [{}] ！", len),
+            ConnorError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported Protocol Version [{}] ！", version)
+            }
+            ConnorError::UnsupportedCodec(codec) => {
+                write!(f, "Unsupported Codec [{}] ！", codec)
+            }
+            ConnorError::IdConflict {
+                service_id,
+                service_name,
+            } => write!(
+                f,
+                "Service Id [{}] Already Registered By [{}] ！",
+                service_id, service_name
+            ),
+            ConnorError::ScriptCheckDisabled => write!(f, "Script Health Check Is Disabled ！"),
+            ConnorError::Remote { code, message } => write!(f, "[{}] {}", code, message),
+        }
     }
 }
+
+impl std::error::Error for ConnorError {}
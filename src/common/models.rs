@@ -3,13 +3,18 @@
 pub mod request;
 pub mod response;
 
-use crate::custom_error::{Json2StructErr, Struct2JsonErr};
+use crate::custom_error::ConnorError;
+use crate::models::response::ServiceDeltaResponse;
+use crate::protocol::{Codec, Frame};
 use bytes::Bytes;
 use futures::stream::{SplitSink, SplitStream};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::{Debug, Display, Formatter};
 use std::str::FromStr;
+use std::time::SystemTime;
 use tokio::net::TcpStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
@@ -17,7 +22,7 @@ pub type TcpReader = SplitStream<Framed<TcpStream, LengthDelimitedCodec>>;
 pub type TcpWriter = SplitSink<Framed<TcpStream, LengthDelimitedCodec>, Bytes>;
 
 /// 通信类型枚举
-#[derive(PartialEq, Debug, Clone)]
+#[derive(PartialEq, Eq, Hash, Debug, Clone)]
 pub enum RpcKind {
     /// 服务注册
     Registry,
@@ -37,38 +42,127 @@ pub enum RpcKind {
     Heartbeat,
     /// 心跳超时检测响应
     HeartbeatTimeout,
+    /// 集群节点间的数据复制
+    Replicate,
+    /// 集群节点间的全量数据同步
+    Snapshot,
+    /// Raft 节点间的消息
+    Raft,
+    /// gossip 节点间的消息
+    Gossip,
+    /// 请求处理失败的响应
+    Error,
+    /// 协商协议版本
+    Handshake,
+    /// 订阅服务变更的推送
+    Subscribe,
+    /// 取消订阅服务变更的推送
+    Unsubscribe,
+    /// 通知客户端某服务实例的增量变更
+    ServiceDelta,
+    /// 客户端发现增量推送的 revision 不连续时，重新获取某服务的全部实例
+    Resync,
+    /// 断线重连后获取断开期间错过的增量变更
+    Watch,
+    /// 通知客户端推送落后于服务端，丢失了部分推送，需要重新同步
+    ResyncRequired,
+    /// 通知客户端实例的心跳状态发生了变化
+    InstanceStatus,
 }
-/// 序列化时用到
+/// 旧协议序列化时用到
 impl Display for RpcKind {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.clone() as u8)
+        write!(f, "{}", self.code())
     }
 }
-/// 反序列化用到
+/// 旧协议反序列化用到
 impl FromStr for RpcKind {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "0" => Ok(RpcKind::Registry),
-            "1" => Ok(RpcKind::Discovery),
-            "2" => Ok(RpcKind::DiscoveryNames),
-            "3" => Ok(RpcKind::Deregistry),
-            "4" => Ok(RpcKind::ServiceCheck),
-            "5" => Ok(RpcKind::AddService),
-            "6" => Ok(RpcKind::RemoveService),
-            "7" => Ok(RpcKind::Heartbeat),
-            "8" => Ok(RpcKind::HeartbeatTimeout),
-            &_ => Err("RpcKind Parser Fail"),
-        }
+        s.parse::<u16>()
+            .ok()
+            .and_then(RpcKind::from_code)
+            .ok_or("RpcKind Parser Fail")
+    }
+}
+
+impl RpcKind {
+    /// 帧头部中的 kind 编码
+    pub fn code(&self) -> u16 {
+        self.clone() as u16
+    }
+
+    /// 根据帧头部中的 kind 编码获取类型
+    pub fn from_code(code: u16) -> Option<RpcKind> {
+        let rpc_kind = match code {
+            0 => RpcKind::Registry,
+            1 => RpcKind::Discovery,
+            2 => RpcKind::DiscoveryNames,
+            3 => RpcKind::Deregistry,
+            4 => RpcKind::ServiceCheck,
+            5 => RpcKind::AddService,
+            6 => RpcKind::RemoveService,
+            7 => RpcKind::Heartbeat,
+            8 => RpcKind::HeartbeatTimeout,
+            9 => RpcKind::Replicate,
+            10 => RpcKind::Snapshot,
+            11 => RpcKind::Raft,
+            12 => RpcKind::Gossip,
+            13 => RpcKind::Error,
+            14 => RpcKind::Handshake,
+            15 => RpcKind::Subscribe,
+            16 => RpcKind::Unsubscribe,
+            17 => RpcKind::ServiceDelta,
+            18 => RpcKind::Resync,
+            19 => RpcKind::Watch,
+            20 => RpcKind::ResyncRequired,
+            21 => RpcKind::InstanceStatus,
+            _ => return None,
+        };
+        Some(rpc_kind)
+    }
+
+    /// 是否可以与同一连接上的其它请求并发处理
+    ///
+    /// 只有客户端的读写请求可以并发，它们的响应带有 request id；集群节点间的消息（复制、Raft、gossip）
+    /// 以及改变连接状态的请求（握手、订阅、Watch）需要按照到达的顺序处理，否则例如先注册后下线的复制数据乱序后会留下已经下线的实例
+    pub fn concurrent(&self) -> bool {
+        matches!(
+            self,
+            RpcKind::Registry
+                | RpcKind::Discovery
+                | RpcKind::DiscoveryNames
+                | RpcKind::Deregistry
+                | RpcKind::ServiceCheck
+                | RpcKind::Heartbeat
+                | RpcKind::Resync
+        )
+    }
+
+    /// 将传输的内容拆分为 kind 头标识和 json 体
+    ///
+    /// kind 头标识为 json 体之前的所有数字
+    pub fn split_frame(content: &str) -> Result<(RpcKind, &str), ConnorError> {
+        let index = content
+            .find('{')
+            .ok_or_else(|| ConnorError::UnknownKind(content.chars().take(16).collect()))?;
+        let kind = &content[..index];
+        let rpc_kind =
+            RpcKind::from_str(kind).map_err(|_| ConnorError::UnknownKind(kind.to_string()))?;
+        Ok((rpc_kind, &content[index..]))
     }
 }
 
 /// 入站处理器处理之后发送的响应客户端的事件
 #[derive(PartialEq, Debug, Clone)]
 pub enum InboundHandleSingleEvent {
-    /// 服务注册的响应
-    ServiceRegistryResp { success: bool },
+    /// 服务注册的响应，携带服务端修正之后实例实际使用的心跳超时时间和心跳间隔
+    ServiceRegistryResp {
+        success: bool,
+        ttl: u64,
+        heartbeat_interval: u64,
+    },
     /// 服务下线的响应
     ServiceDeregistryResp { success: bool },
     /// 服务发现响应
@@ -79,24 +173,95 @@ pub enum InboundHandleSingleEvent {
     /// 获取所有的 service name list 响应
     ServiceNamesResp { service_names: Vec<String> },
     /// service 状态检测
-    ServiceCheckResp { service_id: String },
+    ServiceCheckResp {
+        service_id: String,
+        status: Option<InstanceStatus>,
+    },
     /// 心跳检测(true: 心跳正常，false: 之前存在心跳超时，需要重新注册到服务端)
     HeartbeatResp { success: bool },
+    /// 全量数据同步响应(ready 为 false 时本节点还在同步全量数据)
+    SnapshotResp {
+        ready: bool,
+        services: HashMap<String, Vec<NewService>>,
+        heartbeats: HashMap<String, SystemTime>,
+    },
+    /// 协商协议版本的响应，delta 为连接是否接收增量推送，(epoch, revision) 为事件历史的当前位置
+    HandshakeResp {
+        version: u8,
+        codec: Codec,
+        delta: bool,
+        epoch: u64,
+        revision: u64,
+    },
+    /// 订阅的响应，携带连接当前订阅的所有服务
+    SubscribeResp { service_names: Vec<String> },
+    /// 取消订阅的响应，携带连接当前订阅的所有服务
+    UnsubscribeResp { service_names: Vec<String> },
+    /// 某服务全部的实例及其当前的 revision
+    ResyncResp {
+        service_name: String,
+        revision: u64,
+        services: Vec<NewService>,
+    },
+    /// 事件历史的当前位置，以及请求的 revision 之后的增量变更；None 表示历史已经被淘汰
+    WatchResp {
+        epoch: u64,
+        revision: u64,
+        deltas: Option<Vec<ServiceDeltaResponse>>,
+    },
+    /// 请求处理失败的响应
+    ErrorResp {
+        error: ConnorError,
+        rpc_kind: Option<RpcKind>,
+    },
 }
+/// 入站处理器处理之后广播给所有连接的事件，deltas 为此次变更涉及的服务的增量变更，接收增量推送的连接只推送 deltas
 #[derive(PartialEq, Debug, Clone)]
 pub enum InboundHandleBroadcastEvent {
     /// 通知客户端缓存添加某服务
     AddServiceResp {
         service_name: String,
         service_list: Vec<NewService>,
+        deltas: Vec<ServiceDeltaResponse>,
     },
     /// 通知客户端缓存删除某服务
     RemoveServiceResp {
         service_name: String,
         service_list: Vec<NewService>,
+        deltas: Vec<ServiceDeltaResponse>,
     },
     /// 心跳检测响应事件
-    HeartbeatTimeoutResp { service_ids: Vec<String> },
+    HeartbeatTimeoutResp {
+        service_ids: Vec<String>,
+        deltas: Vec<ServiceDeltaResponse>,
+    },
+    /// 实例的心跳状态发生了变化
+    InstanceStatusResp {
+        service_ids: Vec<String>,
+        status: InstanceStatus,
+        deltas: Vec<ServiceDeltaResponse>,
+    },
+}
+impl InboundHandleBroadcastEvent {
+    pub fn deltas(&self) -> &[ServiceDeltaResponse] {
+        match self {
+            InboundHandleBroadcastEvent::AddServiceResp { deltas, .. }
+            | InboundHandleBroadcastEvent::RemoveServiceResp { deltas, .. }
+            | InboundHandleBroadcastEvent::HeartbeatTimeoutResp { deltas, .. }
+            | InboundHandleBroadcastEvent::InstanceStatusResp { deltas, .. } => deltas,
+        }
+    }
+
+    /// 替换事件的增量变更
+    pub fn with_deltas(mut self, changes: Vec<ServiceDeltaResponse>) -> Self {
+        match &mut self {
+            InboundHandleBroadcastEvent::AddServiceResp { deltas, .. }
+            | InboundHandleBroadcastEvent::RemoveServiceResp { deltas, .. }
+            | InboundHandleBroadcastEvent::HeartbeatTimeoutResp { deltas, .. }
+            | InboundHandleBroadcastEvent::InstanceStatusResp { deltas, .. } => *deltas = changes,
+        }
+        self
+    }
 }
 
 /// 请求/响应实体的公共方法
@@ -104,24 +269,21 @@ pub trait RpcCodec: Debug {
     /// 获取类型
     fn rpc_kind() -> RpcKind;
 
-    /// 从json转换为struct
-    fn from_json<'a>(json: &'a str) -> Box<Self>
+    /// 按照帧记录的序列化格式将消息体转换为struct
+    fn from_frame(frame: &Frame) -> Result<Box<Self>, ConnorError>
     where
-        Self: Sized + Deserialize<'a>,
+        Self: Sized + DeserializeOwned,
     {
-        Box::new(
-            serde_json::from_str::<Self>(json).unwrap_or_else(|_| panic!("{}", Json2StructErr)),
-        )
+        frame.codec().decode::<Self>(&frame.body).map(Box::new)
     }
 
-    /// 将自己转换为 传输的json，并在前面添加了 kind 头标识
-    fn to_json(&self) -> String
+    /// 按照指定的序列化格式将自己转换为帧
+    fn to_frame(&self, codec: Codec) -> Result<Frame, ConnorError>
     where
-        Self: Serialize,
+        Self: Sized + Serialize,
     {
-        let json = serde_json::to_string(self).unwrap_or_else(|_| panic!("{}", Struct2JsonErr));
-
-        format!("{}{}", Self::rpc_kind(), json)
+        let body = codec.encode(self)?;
+        Ok(Frame::new(Self::rpc_kind(), body).with_codec(codec))
     }
 }
 
@@ -134,4 +296,152 @@ pub struct NewService {
     pub host: String,
     // 元数据，可选
     pub meta: Option<HashMap<String, String>>,
+    /// 由服务端根据心跳维护的状态，注册时总是 UP
+    #[serde(default)]
+    pub status: InstanceStatus,
+    /// 心跳超时时间（秒），超时的实例被剔除；不携带时使用服务端的默认值，注册时按照服务端的上下限修正
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// 心跳间隔（秒），不携带时为 ttl 的 1/4；注册时按照服务端的上下限修正，且不超过 ttl 的 1/4
+    #[serde(default)]
+    pub heartbeat_interval: Option<u64>,
+    /// 无法发送心跳的实例由服务端按照 heartbeat_interval 主动探测，探测成功视为一次心跳
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+}
+
+/// 服务端主动探测实例健康的方式
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum HealthCheck {
+    /// 能够连接实例的 host:port
+    Tcp,
+    /// GET http://host:port{path} 的响应状态码为 expected_status
+    Http {
+        path: String,
+        #[serde(default = "default_expected_status")]
+        expected_status: u16,
+    },
+    /// 在服务端执行脚本，退出码为 0；需要服务端开启 script_health_check
+    Script {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+fn default_expected_status() -> u16 {
+    200
+}
+
+/// 实例的生命周期：UP → SUSPECT → DOWN 由错过的心跳驱动，收到心跳后回到 UP；
+/// 心跳超时的实例被剔除（EVICTED），从注册数据中移除并广播 HeartbeatTimeout
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum InstanceStatus {
+    /// 心跳正常
+    #[default]
+    Up,
+    /// 错过了心跳，可能已经不可用
+    Suspect,
+    /// 长时间没有心跳，不再提供服务，超时后被剔除
+    Down,
+}
+
+/// 向量时钟：<节点 server_address, 版本号>
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Default)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+    /// 递增指定节点的版本号，新版本号不小于 floor
+    pub fn increment(&mut self, node: &str, floor: u64) {
+        let version = self.0.entry(node.to_string()).or_insert(0);
+        *version = (*version + 1).max(floor);
+    }
+
+    /// 合并另一个时钟，各节点取较大的版本号
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node, version) in &other.0 {
+            let current = self.0.entry(node.clone()).or_insert(0);
+            *current = (*current).max(*version);
+        }
+    }
+
+    /// 所有节点中最大的版本号
+    pub fn max_version(&self) -> u64 {
+        self.0.values().cloned().max().unwrap_or(0)
+    }
+
+    /// 比较两个时钟的先后，并发（互不包含）时返回 None
+    pub fn compare(&self, other: &VectorClock) -> Option<Ordering> {
+        let mut ordering = Ordering::Equal;
+        for node in self.0.keys().chain(other.0.keys()) {
+            let left = self.0.get(node).unwrap_or(&0);
+            let right = other.0.get(node).unwrap_or(&0);
+            match (ordering, left.cmp(right)) {
+                (_, Ordering::Equal) => {}
+                (Ordering::Equal, current) => ordering = current,
+                (previous, current) if previous != current => return None,
+                _ => {}
+            }
+        }
+        Some(ordering)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vector_clock_compare() {
+        let mut a = VectorClock::default();
+        a.increment("a", 0);
+        let mut b = a.clone();
+        b.increment("b", 0);
+        assert_eq!(a.compare(&b), Some(Ordering::Less));
+        assert_eq!(b.compare(&a), Some(Ordering::Greater));
+
+        a.increment("a", 0);
+        assert_eq!(a.compare(&b), None);
+
+        a.merge(&b);
+        assert_eq!(a.compare(&b), Some(Ordering::Greater));
+        assert_eq!(a.compare(&a.clone()), Some(Ordering::Equal));
+    }
+
+    #[test]
+    fn ordered_kinds() {
+        assert!(RpcKind::Registry.concurrent());
+        assert!(RpcKind::Heartbeat.concurrent());
+        for rpc_kind in [
+            RpcKind::Replicate,
+            RpcKind::Raft,
+            RpcKind::Gossip,
+            RpcKind::Handshake,
+            RpcKind::Subscribe,
+            RpcKind::Unsubscribe,
+            RpcKind::Watch,
+        ] {
+            assert!(!rpc_kind.concurrent());
+        }
+    }
+
+    #[test]
+    fn split_bad_frame() {
+        assert_eq!(
+            RpcKind::split_frame("99{}"),
+            Err(ConnorError::UnknownKind("99".to_string()))
+        );
+        assert!(RpcKind::split_frame("no json").is_err());
+        let (rpc_kind, json) = RpcKind::split_frame("13{}").unwrap();
+        assert_eq!((rpc_kind, json), (RpcKind::Error, "{}"));
+        let frame = Frame::new(RpcKind::Heartbeat, "{}");
+        assert_eq!(
+            request::HeartbeatRequest::from_frame(&frame)
+                .unwrap_err()
+                .code(),
+            3
+        );
+    }
 }
@@ -1,7 +1,9 @@
 //! request 模型
 
-use crate::models::{NewService, RpcCodec, RpcKind};
+use crate::models::{InstanceStatus, NewService, RpcCodec, RpcKind, VectorClock};
+use crate::protocol::Codec;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 /// 注册服务请求
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
@@ -68,3 +70,205 @@ impl RpcCodec for HeartbeatRequest {
         RpcKind::Heartbeat
     }
 }
+
+/// 集群节点间复制的数据变更
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum ReplicateOp {
+    /// 服务注册
+    Registry(RegistryRequest),
+    /// 服务下线
+    Deregistry(DeregistryRequest),
+    /// 心跳超时剔除
+    HeartbeatTimeout { service_ids: Vec<String> },
+    /// 实例的心跳状态变化
+    InstanceStatus {
+        service_ids: Vec<String>,
+        status: InstanceStatus,
+    },
+    /// 其它节点收到的心跳，只刷新心跳时间
+    Heartbeat { service_ids: Vec<String> },
+}
+
+/// 集群节点间的数据复制请求
+///
+/// 接收方只在本地应用该变更并通知自己的客户端，不会再次转发给其它节点
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ReplicateRequest {
+    pub op: ReplicateOp,
+}
+impl RpcCodec for ReplicateRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Replicate
+    }
+}
+
+/// 集群节点间的全量数据同步请求，节点启动时向存活的节点拉取全部注册数据
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SnapshotRequest {}
+
+impl RpcCodec for SnapshotRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Snapshot
+    }
+}
+
+/// Raft 复制日志中的一条记录，op 为空表示 leader 上任时追加的空日志
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct RaftEntry {
+    pub term: u64,
+    pub op: Option<ReplicateOp>,
+}
+
+/// Raft 的快照：已经应用的日志压缩成的注册数据，以及快照包含的最后一条日志的 index 和任期
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Default)]
+pub struct RaftSnapshot {
+    pub last_index: u64,
+    pub last_term: u64,
+    /// <service-name, 实例列表>
+    pub services: HashMap<String, Vec<NewService>>,
+}
+
+/// Raft 节点间的消息内容
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum RaftBody {
+    /// 候选人请求投票
+    VoteRequest {
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    /// 投票结果
+    VoteResponse { granted: bool },
+    /// leader 追加日志，entries 为空时即为心跳
+    AppendEntries {
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    },
+    /// 追加日志的结果，match_index 为 follower 已经与 leader 一致的日志位置
+    AppendResponse { success: bool, match_index: u64 },
+    /// follower 需要的日志已经压缩到快照中时，leader 发送快照，follower 以 AppendResponse 回复
+    InstallSnapshot { snapshot: RaftSnapshot },
+    /// follower 将客户端的写操作转发给 leader
+    Propose { proposal_id: u64, op: ReplicateOp },
+    /// leader 提交转发的写操作之后的结果
+    ProposeResponse { proposal_id: u64, success: bool },
+}
+
+/// Raft 节点间的消息
+///
+/// 消息都是单向发送的，响应同样以消息的形式通过对端的连接发回
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct RaftMessage {
+    /// 发送方的 server_address
+    pub from: String,
+    pub term: u64,
+    pub body: RaftBody,
+}
+impl RpcCodec for RaftMessage {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Raft
+    }
+}
+
+/// gossip 模式下带版本的服务实例
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct GossipEntry {
+    pub service_id: String,
+    pub service_name: String,
+    /// 为空表示实例已经下线
+    pub service: Option<NewService>,
+    pub clock: VectorClock,
+    /// 产生这个版本的节点
+    pub origin: String,
+}
+
+/// gossip 节点间的消息内容
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub enum GossipBody {
+    /// 发送方所有实例的版本摘要：<实例ID, 版本>
+    Digest {
+        digest: HashMap<String, VectorClock>,
+    },
+    /// 对摘要的回复：对方缺少或者版本较旧的实例，以及需要对方发回的实例ID
+    Sync {
+        entries: Vec<GossipEntry>,
+        pull: Vec<String>,
+    },
+    /// 实例数据
+    Entries { entries: Vec<GossipEntry> },
+}
+
+/// gossip 节点间的消息
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct GossipMessage {
+    /// 发送方的 server_address
+    pub from: String,
+    pub body: GossipBody,
+}
+impl RpcCodec for GossipMessage {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Gossip
+    }
+}
+
+/// 订阅服务变更的推送，service_names 支持通配符 `*`、`?`
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SubscribeRequest {
+    pub service_names: Vec<String>,
+}
+impl RpcCodec for SubscribeRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Subscribe
+    }
+}
+
+/// 取消订阅，service_names 需要与订阅时的名称（通配符）一致
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct UnsubscribeRequest {
+    pub service_names: Vec<String>,
+}
+impl RpcCodec for UnsubscribeRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Unsubscribe
+    }
+}
+
+/// 获取某服务全部的实例及其当前的 revision
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ResyncRequest {
+    pub service_name: String,
+}
+impl RpcCodec for ResyncRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Resync
+    }
+}
+
+/// 获取事件历史中 since_revision 之后的增量变更，epoch 与 revision 来自之前连接的握手响应和推送
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct WatchRequest {
+    pub epoch: u64,
+    pub since_revision: u64,
+}
+impl RpcCodec for WatchRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Watch
+    }
+}
+
+/// 协商协议版本：客户端支持的最高版本，以及按照优先顺序排列的序列化格式
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct HandshakeRequest {
+    pub version: u8,
+    #[serde(default)]
+    pub codecs: Vec<Codec>,
+    /// 是否接收增量推送（ServiceDelta），否则推送服务全部的实例
+    #[serde(default)]
+    pub delta: bool,
+}
+impl RpcCodec for HandshakeRequest {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Handshake
+    }
+}
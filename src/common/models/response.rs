@@ -1,11 +1,20 @@
 //! response 模型
 
-use crate::models::{NewService, RpcCodec, RpcKind};
+use crate::models::{InstanceStatus, NewService, RpcCodec, RpcKind};
+use crate::protocol::Codec;
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct RegistryResponse {
     pub success: bool,
+    /// 服务端修正之后实例实际使用的心跳超时时间（秒），旧版本的服务端不返回
+    #[serde(default)]
+    pub ttl: Option<u64>,
+    /// 服务端修正之后实例实际使用的心跳间隔（秒），旧版本的服务端不返回
+    #[serde(default)]
+    pub heartbeat_interval: Option<u64>,
 }
 impl RpcCodec for RegistryResponse {
     fn rpc_kind() -> RpcKind {
@@ -14,7 +23,7 @@ impl RpcCodec for RegistryResponse {
 }
 
 /// 服务发现响应：根据service-name 获取所有的service
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct DiscoveryResponse {
     pub service_name: String,
     pub services: Option<Vec<NewService>>,
@@ -34,9 +43,9 @@ impl RpcCodec for DiscoveryResponse {
 }
 
 /// 所有的service name获取响应
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct DiscoveryServiceNamesResponse {
-    service_names: Vec<String>,
+    pub service_names: Vec<String>,
 }
 impl DiscoveryServiceNamesResponse {
     pub fn new(service_names: Vec<String>) -> Self {
@@ -64,11 +73,15 @@ impl RpcCodec for DeregistryResponse {
 #[derive(Serialize, Deserialize, PartialEq, Debug)]
 pub struct ServiceCheckResponse {
     pub service_id: String,
+    /// 实例的状态（心跳或者主动探测的结果），实例不存在时为空
+    #[serde(default)]
+    pub status: Option<InstanceStatus>,
 }
 impl ServiceCheckResponse {
-    pub fn new(service_id: &str) -> Self {
+    pub fn new(service_id: &str, status: Option<InstanceStatus>) -> Self {
         Self {
             service_id: service_id.to_string(),
+            status,
         }
     }
 }
@@ -79,10 +92,10 @@ impl RpcCodec for ServiceCheckResponse {
 }
 
 /// 当前客户端添加服务响应
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct AddServiceResponse {
-    service_name: String,
-    service_list: Vec<NewService>,
+    pub service_name: String,
+    pub service_list: Vec<NewService>,
 }
 impl AddServiceResponse {
     pub fn new(service_name: &str, service_list: Vec<NewService>) -> Self {
@@ -99,10 +112,10 @@ impl RpcCodec for AddServiceResponse {
 }
 
 /// 当前客户端删除服务响应
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct RemoveServiceResponse {
-    service_name: String,
-    service_list: Vec<NewService>,
+    pub service_name: String,
+    pub service_list: Vec<NewService>,
 }
 impl RemoveServiceResponse {
     pub fn new(service_name: &str, service_list: Vec<NewService>) -> Self {
@@ -128,7 +141,7 @@ impl RpcCodec for HeartbeatResponse {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct HeartbeatTimeoutResponse {
     pub timeout_service_ids: Vec<String>,
 }
@@ -144,3 +157,238 @@ impl RpcCodec for HeartbeatTimeoutResponse {
         RpcKind::HeartbeatTimeout
     }
 }
+
+/// 集群节点间的全量数据同步响应
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+pub struct SnapshotResponse {
+    /// 对端还在同步全量数据时为 false，此时不包含数据
+    pub ready: bool,
+    pub services: HashMap<String, Vec<NewService>>,
+    pub heartbeats: HashMap<String, SystemTime>,
+}
+impl RpcCodec for SnapshotResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Snapshot
+    }
+}
+
+/// 协商协议版本的响应：双方都支持的版本，以及之后使用的序列化格式
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct HandshakeResponse {
+    pub version: u8,
+    #[serde(default)]
+    pub codec: Codec,
+    /// 服务端是否会发送增量推送
+    #[serde(default)]
+    pub delta: bool,
+    /// 事件历史的标识，节点重启或者连接到其它节点时不同
+    #[serde(default)]
+    pub epoch: u64,
+    /// 握手时事件历史的 revision，之后的变更都会推送到该连接
+    #[serde(default)]
+    pub revision: u64,
+}
+impl RpcCodec for HandshakeResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Handshake
+    }
+}
+
+/// 订阅的响应：连接当前订阅的所有服务
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct SubscribeResponse {
+    pub service_names: Vec<String>,
+}
+impl RpcCodec for SubscribeResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Subscribe
+    }
+}
+
+/// 取消订阅的响应：连接当前订阅的所有服务
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct UnsubscribeResponse {
+    pub service_names: Vec<String>,
+}
+impl RpcCodec for UnsubscribeResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Unsubscribe
+    }
+}
+
+/// 某服务实例的增量变更推送
+///
+/// revision 在每个服务内单调递增（每次变更加一），客户端发现不连续时通过 Resync 重新获取全部实例；
+/// revision 由各个节点各自维护，重连到其它节点后需要重新获取；
+/// store_revision 为该变更在事件历史中的全局 revision，重连同一节点后通过 Watch 获取之后错过的变更
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ServiceDeltaResponse {
+    pub service_name: String,
+    pub revision: u64,
+    #[serde(default)]
+    pub store_revision: u64,
+    /// 新增的实例
+    pub added: Vec<NewService>,
+    /// 移除的实例ID
+    pub removed: Vec<String>,
+    /// 信息发生变化的实例
+    pub updated: Vec<NewService>,
+}
+impl ServiceDeltaResponse {
+    /// 比较变更前后的实例列表，没有变化时返回 None
+    pub fn diff(service_name: &str, before: &[NewService], after: &[NewService]) -> Option<Self> {
+        let find = |list: &[NewService], id: &str| list.iter().position(|service| service.id == id);
+        let mut added = vec![];
+        let mut updated = vec![];
+        for service in after {
+            match find(before, &service.id) {
+                None => added.push(service.clone()),
+                Some(index) if &before[index] != service => updated.push(service.clone()),
+                Some(_) => {}
+            }
+        }
+        let removed = before
+            .iter()
+            .filter(|service| find(after, &service.id).is_none())
+            .map(|service| service.id.clone())
+            .collect::<Vec<String>>();
+        if added.is_empty() && removed.is_empty() && updated.is_empty() {
+            return None;
+        }
+        Some(Self {
+            service_name: service_name.to_string(),
+            revision: 0,
+            store_revision: 0,
+            added,
+            removed,
+            updated,
+        })
+    }
+
+    /// 将增量变更应用到实例列表
+    pub fn apply(&self, list: &mut Vec<NewService>) {
+        list.retain(|service| !self.removed.contains(&service.id));
+        for service in &self.updated {
+            match list.iter_mut().find(|exist| exist.id == service.id) {
+                Some(exist) => *exist = service.clone(),
+                None => list.push(service.clone()),
+            }
+        }
+        list.extend(self.added.iter().cloned());
+    }
+}
+impl RpcCodec for ServiceDeltaResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::ServiceDelta
+    }
+}
+
+/// 某服务全部的实例及其当前的 revision
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ResyncResponse {
+    pub service_name: String,
+    pub revision: u64,
+    pub services: Vec<NewService>,
+}
+impl RpcCodec for ResyncResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Resync
+    }
+}
+
+/// 事件历史的当前位置以及请求的 revision 之后的增量变更
+///
+/// compacted 为 true 时 epoch 不同或者需要的历史已经被淘汰，客户端需要重新同步全部实例
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct WatchResponse {
+    pub epoch: u64,
+    pub revision: u64,
+    pub compacted: bool,
+    pub deltas: Vec<ServiceDeltaResponse>,
+}
+impl RpcCodec for WatchResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Watch
+    }
+}
+
+/// 连接的推送落后于服务端，丢失了 lagged 个变更事件，客户端需要重新同步全部实例
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ResyncRequiredResponse {
+    pub lagged: u64,
+}
+impl RpcCodec for ResyncRequiredResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::ResyncRequired
+    }
+}
+
+/// 实例的心跳状态发生了变化
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct InstanceStatusResponse {
+    pub service_ids: Vec<String>,
+    pub status: InstanceStatus,
+}
+impl RpcCodec for InstanceStatusResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::InstanceStatus
+    }
+}
+
+/// 请求处理失败的响应
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
+pub struct ErrorResponse {
+    /// 错误码，见 ConnorError::code
+    pub code: u16,
+    pub message: String,
+    /// 处理失败的请求的 kind 头标识，无法识别 kind 时为 None
+    pub rpc_kind: Option<String>,
+}
+impl RpcCodec for ErrorResponse {
+    fn rpc_kind() -> RpcKind {
+        RpcKind::Error
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::protocol::Frame;
+
+    fn service(id: &str, port: u32) -> NewService {
+        NewService {
+            id: id.to_string(),
+            name: "order".to_string(),
+            port,
+            host: "127.0.0.1".to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: None,
+            heartbeat_interval: None,
+            health_check: None,
+        }
+    }
+
+    #[test]
+    fn service_delta() {
+        let before = vec![service("a", 80), service("b", 80), service("c", 80)];
+        let after = vec![service("a", 80), service("b", 81), service("d", 80)];
+        let delta = ServiceDeltaResponse::diff("order", &before, &after).unwrap();
+        assert_eq!(delta.added, vec![service("d", 80)]);
+        assert_eq!(delta.removed, vec!["c".to_string()]);
+        assert_eq!(delta.updated, vec![service("b", 81)]);
+
+        let mut list = before.clone();
+        delta.apply(&mut list);
+        assert_eq!(list, after);
+        assert!(ServiceDeltaResponse::diff("order", &after, &after).is_none());
+    }
+
+    #[test]
+    fn registry_response_compatible() {
+        // 旧版本的服务端不返回修正之后的心跳参数
+        let frame = Frame::new(RpcKind::Registry, r#"{"success":true}"#);
+        let response = RegistryResponse::from_frame(&frame).unwrap();
+        assert_eq!((response.ttl, response.heartbeat_interval), (None, None));
+    }
+}
@@ -0,0 +1,269 @@
+//! 传输协议
+//!
+//! 在 LengthDelimitedCodec 的每一帧之前加上固定长度的头部（多字节字段均为大端序）：
+//!
+//! | magic "CN" 2 字节 | version 1 字节 | flags 1 字节 | kind 2 字节 | request id 8 字节 | body |
+//!
+//! 响应原样带回请求的 request id，同一连接上可以同时有多个未完成的请求；
+//! 服务端主动推送的帧 request id 为 0，并带有 FLAG_PUSH 标识。
+//! flags 中还记录了消息体的序列化格式，格式在握手时协商，默认为 json。
+//!
+//! 新协议的客户端连接后首先发送 Handshake 帧，服务端以双方都支持的版本响应。
+//! 兼容旧协议：旧客户端的帧为 `<kind 数字><json>`，服务端根据连接上的第一帧判断协议，
+//! 旧协议的连接仍然按照旧的格式响应；旧客户端无法解析错误响应，请求处理失败时服务端关闭连接
+
+mod codec;
+
+pub use codec::Codec;
+
+use crate::custom_error::ConnorError;
+use crate::models::{RpcKind, TcpWriter};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::SinkExt;
+
+/// 帧头部的魔数
+pub const MAGIC: [u8; 2] = *b"CN";
+/// 当前支持的协议版本
+pub const VERSION: u8 = 1;
+/// 帧头部的长度
+pub const HEADER_LEN: usize = 14;
+/// 旧协议的客户端只读取一位数字的 kind，只能收到编码不超过该值的帧
+pub const LEGACY_MAX_KIND: u16 = 9;
+/// 服务端主动推送的帧
+pub const FLAG_PUSH: u8 = 0b0000_0001;
+/// flags 中序列化格式编号所在的位
+const CODEC_MASK: u8 = 0b0000_0110;
+const CODEC_SHIFT: u8 = 1;
+
+/// 连接使用的协议
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// 旧协议：`<kind 数字><json>`
+    Legacy,
+    /// 带版本号的二进制头部协议
+    Binary(u8),
+}
+
+impl Protocol {
+    /// 当前版本的二进制头部协议
+    pub const CURRENT: Protocol = Protocol::Binary(VERSION);
+}
+
+/// 一帧数据
+#[derive(Debug, Clone, PartialEq)]
+pub struct Frame {
+    pub rpc_kind: RpcKind,
+    pub request_id: u64,
+    pub flags: u8,
+    pub body: Bytes,
+}
+
+impl Frame {
+    pub fn new(rpc_kind: RpcKind, body: impl Into<Bytes>) -> Self {
+        Self {
+            rpc_kind,
+            request_id: 0,
+            flags: 0,
+            body: body.into(),
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: u64) -> Self {
+        self.request_id = request_id;
+        self
+    }
+
+    pub fn with_flags(mut self, flags: u8) -> Self {
+        self.flags |= flags;
+        self
+    }
+
+    /// 是否是服务端主动推送的帧
+    pub fn is_push(&self) -> bool {
+        self.flags & FLAG_PUSH != 0
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.flags = (self.flags & !CODEC_MASK) | (codec.id() << CODEC_SHIFT);
+        self
+    }
+
+    /// 消息体的序列化格式
+    pub fn codec(&self) -> Codec {
+        Codec::from_id((self.flags & CODEC_MASK) >> CODEC_SHIFT).unwrap_or_default()
+    }
+
+    /// 按照指定的协议编码，旧协议只保留 kind 和消息体，不支持编码超过 LEGACY_MAX_KIND 的帧
+    pub fn encode(&self, protocol: Protocol) -> Result<Bytes, ConnorError> {
+        match protocol {
+            Protocol::Legacy => {
+                if self.rpc_kind.code() > LEGACY_MAX_KIND {
+                    return Err(ConnorError::Unsupported(self.rpc_kind.to_string()));
+                }
+                let kind = self.rpc_kind.to_string();
+                let mut buf = BytesMut::with_capacity(kind.len() + self.body.len());
+                buf.put_slice(kind.as_bytes());
+                buf.put_slice(&self.body);
+                Ok(buf.freeze())
+            }
+            Protocol::Binary(version) => {
+                let mut buf = BytesMut::with_capacity(HEADER_LEN + self.body.len());
+                buf.put_slice(&MAGIC);
+                buf.put_u8(version);
+                buf.put_u8(self.flags);
+                buf.put_u16(self.rpc_kind.code());
+                buf.put_u64(self.request_id);
+                buf.put_slice(&self.body);
+                Ok(buf.freeze())
+            }
+        }
+    }
+
+    /// 解码一帧数据，同时返回这一帧使用的协议
+    pub fn decode(mut data: Bytes) -> Result<(Protocol, Frame), ConnorError> {
+        if !data.starts_with(&MAGIC) {
+            let content = std::str::from_utf8(&data).map_err(|_| ConnorError::Byte2Json)?;
+            let (rpc_kind, json) = RpcKind::split_frame(content)?;
+            let body = data.slice(data.len() - json.len()..);
+            return Ok((Protocol::Legacy, Frame::new(rpc_kind, body)));
+        }
+        if data.len() < HEADER_LEN {
+            return Err(ConnorError::BadHeader(data.len()));
+        }
+        data.advance(MAGIC.len());
+        let version = data.get_u8();
+        if version == 0 || version > VERSION {
+            return Err(ConnorError::UnsupportedVersion(version));
+        }
+        let flags = data.get_u8();
+        let codec_id = (flags & CODEC_MASK) >> CODEC_SHIFT;
+        if Codec::from_id(codec_id).is_none() {
+            return Err(ConnorError::UnsupportedCodec(codec_id));
+        }
+        let code = data.get_u16();
+        let rpc_kind =
+            RpcKind::from_code(code).ok_or_else(|| ConnorError::UnknownKind(code.to_string()))?;
+        let request_id = data.get_u64();
+        let frame = Frame {
+            rpc_kind,
+            request_id,
+            flags,
+            body: data,
+        };
+        Ok((Protocol::Binary(version), frame))
+    }
+}
+
+/// 协商双方都支持的协议版本
+pub fn negotiate(version: u8) -> Result<u8, ConnorError> {
+    match version.min(VERSION) {
+        0 => Err(ConnorError::UnsupportedVersion(version)),
+        version => Ok(version),
+    }
+}
+
+/// 按照连接的协议编码并发送帧
+pub struct FrameWriter {
+    sink: TcpWriter,
+    protocol: Protocol,
+    /// 连接协商的序列化格式
+    codec: Codec,
+}
+
+impl FrameWriter {
+    pub fn new(sink: TcpWriter, protocol: Protocol, codec: Codec) -> Self {
+        Self {
+            sink,
+            protocol,
+            codec,
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    pub fn codec(&self) -> Codec {
+        self.codec
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub async fn send(&mut self, frame: &Frame) -> std::io::Result<()> {
+        let data = frame
+            .encode(self.protocol)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        self.sink.send(data).await
+    }
+
+    /// 关闭连接的写入端，客户端读到连接结束
+    pub async fn close(&mut self) -> std::io::Result<()> {
+        self.sink.close().await
+    }
+
+    /// 连接的协议能否发送该类型的帧
+    pub fn accepts(&self, rpc_kind: &RpcKind) -> bool {
+        self.protocol != Protocol::Legacy || rpc_kind.code() <= LEGACY_MAX_KIND
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn binary_round_trip() {
+        let frame = Frame {
+            rpc_kind: RpcKind::Discovery,
+            request_id: 42,
+            flags: FLAG_PUSH,
+            body: Bytes::from_static(b"{\"service_name\":\"a\"}"),
+        };
+        assert!(frame.is_push());
+        assert!(!Frame::new(RpcKind::Discovery, "{}").is_push());
+        let encoded = frame.encode(Protocol::Binary(VERSION)).unwrap();
+        assert_eq!(&encoded[..2], b"CN");
+        assert_eq!(
+            Frame::decode(encoded).unwrap(),
+            (Protocol::Binary(VERSION), frame)
+        );
+    }
+
+    #[test]
+    fn legacy_frame() {
+        let (protocol, frame) = Frame::decode(Bytes::from_static(b"12{}")).unwrap();
+        assert_eq!(protocol, Protocol::Legacy);
+        assert_eq!(frame.rpc_kind, RpcKind::Gossip);
+        assert_eq!(frame.codec(), Codec::Json);
+        assert_eq!(&frame.body[..], b"{}");
+        assert_eq!(
+            frame.encode(Protocol::Legacy),
+            Err(ConnorError::Unsupported("12".to_string()))
+        );
+        let frame = Frame::new(RpcKind::Heartbeat, "{}");
+        assert_eq!(&frame.encode(Protocol::Legacy).unwrap()[..], b"7{}");
+    }
+
+    #[test]
+    fn bad_header() {
+        assert_eq!(
+            Frame::decode(Bytes::from_static(b"CN\x01")),
+            Err(ConnorError::BadHeader(3))
+        );
+        let mut frame = Frame::new(RpcKind::Registry, "{}")
+            .encode(Protocol::Binary(VERSION))
+            .unwrap()
+            .to_vec();
+        frame[2] = 9;
+        assert_eq!(
+            Frame::decode(Bytes::from(frame)),
+            Err(ConnorError::UnsupportedVersion(9))
+        );
+    }
+}
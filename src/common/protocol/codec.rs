@@ -0,0 +1,106 @@
+//! 消息体的序列化格式
+
+use crate::custom_error::ConnorError;
+use bytes::Bytes;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+/// 消息体的序列化格式，默认为 json
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    Json,
+    #[serde(rename = "msgpack")]
+    MessagePack,
+    Cbor,
+}
+
+impl Codec {
+    /// 服务端支持的所有格式
+    pub const ALL: [Codec; 3] = [Codec::Json, Codec::MessagePack, Codec::Cbor];
+
+    /// 帧头部 flags 中的编号
+    pub fn id(&self) -> u8 {
+        match self {
+            Codec::Json => 0,
+            Codec::MessagePack => 1,
+            Codec::Cbor => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|codec| codec.id() == id)
+    }
+
+    /// 按照对方的优先顺序选择第一个支持的格式，都不支持时使用 json
+    pub fn negotiate(offered: &[Codec]) -> Codec {
+        offered
+            .iter()
+            .find(|codec| Codec::ALL.contains(codec))
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Bytes, ConnorError> {
+        let body = match self {
+            Codec::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            Codec::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Codec::Cbor => {
+                let mut body = vec![];
+                ciborium::ser::into_writer(value, &mut body)
+                    .map(|_| body)
+                    .map_err(|err| err.to_string())
+            }
+        };
+        body.map(Bytes::from).map_err(ConnorError::Struct2Json)
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, ConnorError> {
+        let value = match self {
+            Codec::Json => serde_json::from_slice(body).map_err(|err| err.to_string()),
+            Codec::MessagePack => rmp_serde::from_slice(body).map_err(|err| err.to_string()),
+            Codec::Cbor => ciborium::de::from_reader(body).map_err(|err| err.to_string()),
+        };
+        value.map_err(ConnorError::Json2Struct)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::response::SnapshotResponse;
+    use crate::models::{InstanceStatus, NewService};
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+
+    #[test]
+    fn round_trip() {
+        let service = NewService {
+            id: "id-1".to_string(),
+            name: "service-a".to_string(),
+            port: 8080,
+            host: "127.0.0.1".to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: None,
+            heartbeat_interval: None,
+            health_check: None,
+        };
+        let snapshot = SnapshotResponse {
+            ready: true,
+            services: HashMap::from([("service-a".to_string(), vec![service])]),
+            heartbeats: HashMap::from([("id-1".to_string(), SystemTime::now())]),
+        };
+        for codec in Codec::ALL {
+            let body = codec.encode(&snapshot).unwrap();
+            assert_eq!(codec.decode::<SnapshotResponse>(&body).unwrap(), snapshot);
+        }
+    }
+
+    #[test]
+    fn negotiate() {
+        assert_eq!(Codec::negotiate(&[]), Codec::Json);
+        assert_eq!(Codec::negotiate(&[Codec::Cbor, Codec::Json]), Codec::Cbor);
+    }
+}
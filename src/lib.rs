@@ -2,6 +2,6 @@ mod common;
 mod server;
 mod client;
 
-pub use common::{custom_error,models,config};
+pub use common::{custom_error,models,config,protocol};
 pub use server::server_bootstrap;
-pub use client::{TcpClient,PeerCluster};
+pub use client::{load_balancer,TcpClient,PeerCluster,ConnorClient,ServerPush,ServiceCache,ServiceChange};
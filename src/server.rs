@@ -1,5 +1,17 @@
+mod cluster;
+mod deadline_queue;
+mod dns;
+mod event_history;
+mod gossip;
+mod grpc;
+mod health_check;
+mod http_api;
 mod inbound;
+mod metrics;
 mod outbound;
+mod raft;
+mod self_preservation;
+mod subscription;
 pub mod server_bootstrap;
 
 pub use inbound::inbound_handle;
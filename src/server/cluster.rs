@@ -0,0 +1,499 @@
+//! 集群数据变更的提交与应用
+
+use crate::config::{ClusterMode, ServerConfig};
+use crate::models::request::{GossipMessage, RaftMessage, ReplicateOp};
+use crate::models::response::ServiceDeltaResponse;
+use crate::models::{InboundHandleBroadcastEvent, NewService};
+use crate::server::event_history::EventHistory;
+use crate::server::gossip::GossipHandle;
+use crate::server::inbound::{deregistry, heartbeat, registry};
+use crate::server::raft::RaftHandle;
+use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
+use crate::PeerCluster;
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::broadcast::Sender;
+use tracing::{error, warn};
+
+/// 注册数据的状态机：在本地应用数据变更并通知本节点的客户端
+#[derive(Clone)]
+pub struct ServiceStore {
+    services_map: ServersMap,
+    /// 实例最后一次心跳的时间，应用注册时记录第一次心跳，移除实例时一起清理
+    services_heartbeat_map: ServersHeartbeatMap,
+    publisher: Sender<InboundHandleBroadcastEvent>,
+    revisions: Arc<Mutex<Revisions>>,
+}
+
+/// 变更时一起更新的 revision
+struct Revisions {
+    /// 每个服务的 revision：<service-name, revision>，每次实例变化加一
+    services: HashMap<String, u64>,
+    /// 增量变更的事件历史
+    history: EventHistory,
+}
+
+impl ServiceStore {
+    /// history_size 为事件历史最多保存的增量变更个数
+    pub fn new(
+        services_map: ServersMap,
+        services_heartbeat_map: ServersHeartbeatMap,
+        publisher: Sender<InboundHandleBroadcastEvent>,
+        history_size: usize,
+    ) -> Self {
+        Self {
+            services_map,
+            services_heartbeat_map,
+            publisher,
+            revisions: Arc::new(Mutex::new(Revisions {
+                services: HashMap::new(),
+                history: EventHistory::new(history_size),
+            })),
+        }
+    }
+
+    /// 某实例ID当前所属的服务
+    pub fn service_name_of(&self, service_id: &str) -> Option<String> {
+        self.services_map
+            .read()
+            .iter()
+            .find(|(_, services)| services.iter().any(|service| service.id == service_id))
+            .map(|(service_name, _)| service_name.clone())
+    }
+
+    /// 某服务全部的实例及其当前的 revision
+    pub fn resync(&self, service_name: &str) -> (u64, Vec<NewService>) {
+        let revisions = self.revisions.lock();
+        let services = self
+            .services_map
+            .read()
+            .get(service_name)
+            .cloned()
+            .unwrap_or_default();
+        let revision = revisions.services.get(service_name).cloned().unwrap_or(0);
+        (revision, services)
+    }
+
+    /// 事件历史的当前位置：(epoch, revision)
+    pub fn position(&self) -> (u64, u64) {
+        self.revisions.lock().history.position()
+    }
+
+    /// 事件历史的当前位置，以及 since_revision 之后的增量变更；
+    /// epoch 不同或者需要的历史已经被淘汰时增量变更为 None
+    pub fn since(
+        &self,
+        epoch: u64,
+        since_revision: u64,
+    ) -> ((u64, u64), Option<Vec<ServiceDeltaResponse>>) {
+        let revisions = self.revisions.lock();
+        let deltas = revisions.history.since(epoch, since_revision);
+        (revisions.history.position(), deltas)
+    }
+
+    /// 应用一次数据变更，计算涉及的服务的增量变更并递增 revision，实例发生变化时发布更新客户端缓存的事件
+    ///
+    /// 持有 revisions 锁直到事件发布，保证推送的 revision 按照顺序递增，并与事件历史保持一致
+    pub fn apply(&self, op: ReplicateOp) {
+        let mut revisions = self.revisions.lock();
+        let before = self.affected(&op);
+        let handle_event = match op {
+            ReplicateOp::Registry(registry_request) => {
+                // 节点第一次看到实例（包括从其它节点复制过来的实例）时，以应用的时间作为第一次心跳
+                self.services_heartbeat_map
+                    .write()
+                    .insert(registry_request.service.id.clone(), SystemTime::now());
+                registry::store(&registry_request.service, &self.services_map)
+            }
+            ReplicateOp::Deregistry(deregistry_request) => {
+                let handle_event = deregistry::remove(&deregistry_request, &self.services_map);
+                self.purge_heartbeats(&[deregistry_request.service_id]);
+                handle_event
+            }
+            ReplicateOp::HeartbeatTimeout { service_ids } => {
+                heartbeat::remove_timeout(&service_ids, &self.services_map);
+                self.purge_heartbeats(&service_ids);
+                InboundHandleBroadcastEvent::HeartbeatTimeoutResp {
+                    service_ids,
+                    deltas: vec![],
+                }
+            }
+            ReplicateOp::InstanceStatus {
+                service_ids,
+                status,
+            } => {
+                heartbeat::set_status(&service_ids, status, &self.services_map);
+                InboundHandleBroadcastEvent::InstanceStatusResp {
+                    service_ids,
+                    status,
+                    deltas: vec![],
+                }
+            }
+            // 心跳只刷新心跳时间，不改变注册数据
+            ReplicateOp::Heartbeat { service_ids } => {
+                self.renew(&service_ids);
+                return;
+            }
+        };
+        let mut deltas = {
+            let map = self.services_map.read();
+            before
+                .into_iter()
+                .filter_map(|(service_name, list)| {
+                    let after = map
+                        .get(&service_name)
+                        .map(Vec::as_slice)
+                        .unwrap_or_default();
+                    let mut delta = ServiceDeltaResponse::diff(&service_name, &list, after)?;
+                    let revision = revisions.services.entry(service_name).or_insert(0);
+                    *revision += 1;
+                    delta.revision = *revision;
+                    Some(delta)
+                })
+                .collect::<Vec<ServiceDeltaResponse>>()
+        };
+        // 重复注册等没有改变任何实例的变更不通知客户端
+        if deltas.is_empty() {
+            return;
+        }
+        revisions.history.record(&mut deltas);
+        if let Err(err) = self.publisher.send(handle_event.with_deltas(deltas)) {
+            error!("Publisher Event Error [{:?}]", err);
+        }
+    }
+
+    /// 刷新实例的心跳时间，已经不存在的实例不记录
+    fn renew(&self, service_ids: &[String]) {
+        let map = self.services_map.read();
+        let now = SystemTime::now();
+        let mut heartbeats = self.services_heartbeat_map.write();
+        service_ids
+            .iter()
+            .filter(|service_id| map.values().flatten().any(|service| &service.id == *service_id))
+            .for_each(|service_id| {
+                heartbeats.insert(service_id.clone(), now);
+            });
+    }
+
+    /// 清理已经不存在（下线、被剔除）的实例的心跳数据，避免心跳数据与注册数据不一致
+    fn purge_heartbeats(&self, service_ids: &[String]) {
+        let map = self.services_map.read();
+        let mut heartbeats = self.services_heartbeat_map.write();
+        service_ids
+            .iter()
+            .filter(|service_id| !map.values().flatten().any(|service| &service.id == *service_id))
+            .for_each(|service_id| {
+                heartbeats.remove(service_id);
+            });
+    }
+
+    /// 当前全部的注册数据：<service-name, 实例列表>
+    pub fn snapshot(&self) -> HashMap<String, Vec<NewService>> {
+        self.services_map.read().clone()
+    }
+
+    /// 用快照替换全部的注册数据，实例发生变化的服务递增 revision，并按照新的实例列表通知客户端
+    ///
+    /// 快照中第一次看到的实例以导入的时间作为第一次心跳，已经不存在的实例清理心跳数据
+    pub fn install(&self, services: HashMap<String, Vec<NewService>>) {
+        let mut revisions = self.revisions.lock();
+        let before = std::mem::replace(&mut *self.services_map.write(), services.clone());
+        {
+            let now = SystemTime::now();
+            let service_ids = services
+                .values()
+                .flatten()
+                .map(|service| service.id.clone())
+                .collect::<HashSet<String>>();
+            let mut heartbeats = self.services_heartbeat_map.write();
+            heartbeats.retain(|service_id, _| service_ids.contains(service_id));
+            for service_id in service_ids {
+                heartbeats.entry(service_id).or_insert(now);
+            }
+        }
+        let mut service_names = before.keys().chain(services.keys()).collect::<Vec<_>>();
+        service_names.sort();
+        service_names.dedup();
+        for service_name in service_names {
+            let list = before.get(service_name).map(Vec::as_slice).unwrap_or_default();
+            let after = services.get(service_name).cloned().unwrap_or_default();
+            let Some(mut delta) = ServiceDeltaResponse::diff(service_name, list, &after) else {
+                continue;
+            };
+            let revision = revisions.services.entry(service_name.clone()).or_insert(0);
+            *revision += 1;
+            delta.revision = *revision;
+            let mut deltas = vec![delta];
+            revisions.history.record(&mut deltas);
+            let service_name = service_name.clone();
+            let handle_event = if after.is_empty() {
+                InboundHandleBroadcastEvent::RemoveServiceResp {
+                    service_name,
+                    service_list: after,
+                    deltas,
+                }
+            } else {
+                InboundHandleBroadcastEvent::AddServiceResp {
+                    service_name,
+                    service_list: after,
+                    deltas,
+                }
+            };
+            // 重启时恢复快照还没有任何订阅者，发送失败可以忽略
+            let _ = self.publisher.send(handle_event);
+        }
+    }
+
+    /// 变更之前，此次变更可能涉及的服务的实例
+    fn affected(&self, op: &ReplicateOp) -> Vec<(String, Vec<NewService>)> {
+        let map = self.services_map.read();
+        let contains = |list: &[NewService], ids: &[&String]| {
+            list.iter().any(|service| ids.contains(&&service.id))
+        };
+        let (mut service_names, ids) = match op {
+            ReplicateOp::Registry(registry_request) => (
+                vec![registry_request.service.name.clone()],
+                vec![&registry_request.service.id],
+            ),
+            ReplicateOp::Deregistry(deregistry_request) => {
+                (vec![deregistry_request.service_name.clone()], vec![])
+            }
+            ReplicateOp::HeartbeatTimeout { service_ids }
+            | ReplicateOp::InstanceStatus { service_ids, .. } => {
+                (vec![], service_ids.iter().collect())
+            }
+            ReplicateOp::Heartbeat { .. } => (vec![], vec![]),
+        };
+        for (service_name, list) in map.iter() {
+            if !service_names.contains(service_name) && contains(list, &ids) {
+                service_names.push(service_name.clone());
+            }
+        }
+        service_names
+            .into_iter()
+            .map(|service_name| {
+                let list = map.get(&service_name).cloned().unwrap_or_default();
+                (service_name, list)
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+enum Backend {
+    Replicate,
+    Raft(RaftHandle),
+    Gossip(GossipHandle),
+}
+
+/// 按照配置的集群模式提交数据变更
+#[derive(Clone)]
+pub struct Cluster {
+    store: ServiceStore,
+    peer_cluster: PeerCluster,
+    backend: Backend,
+}
+impl Cluster {
+    /// raft 模式下恢复持久化的数据失败时返回错误
+    pub fn new(
+        config: &ServerConfig,
+        store: ServiceStore,
+        peer_cluster: PeerCluster,
+    ) -> Result<Self> {
+        let server_address = &config.server_address;
+        let cluster_address = &config.cluster_address;
+        let backend = match config.cluster_mode {
+            ClusterMode::Replicate => Backend::Replicate,
+            ClusterMode::Raft => Backend::Raft(RaftHandle::start(
+                server_address.clone(),
+                cluster_address.clone(),
+                peer_cluster.clone(),
+                store.clone(),
+                &config.raft_data_dir,
+                config.raft_snapshot_threshold,
+            )?),
+            ClusterMode::Gossip => Backend::Gossip(GossipHandle::start(
+                server_address.clone(),
+                cluster_address.clone(),
+                peer_cluster.clone(),
+                store.clone(),
+                config.gossip_interval,
+            )),
+        };
+        Ok(Self {
+            store,
+            peer_cluster,
+            backend,
+        })
+    }
+
+    pub fn store(&self) -> &ServiceStore {
+        &self.store
+    }
+
+    /// 提交一次数据变更，返回变更是否成功
+    ///
+    /// replicate 模式下本地应用后异步复制到其它实例，总是成功；
+    /// raft 模式下写入复制日志，多数实例确认提交之后才返回；
+    /// gossip 模式下本地写入新版本后推送给其它实例，总是成功
+    pub async fn submit(&self, op: ReplicateOp) -> bool {
+        match &self.backend {
+            Backend::Replicate => {
+                self.store.apply(op.clone());
+                self.peer_cluster.replicate(op);
+                true
+            }
+            Backend::Raft(raft) => raft.propose(op).await,
+            Backend::Gossip(gossip) => {
+                gossip.submit(op);
+                true
+            }
+        }
+    }
+
+    /// 将本节点收到的心跳通知其它节点，各节点都按照最后一次心跳的时间判定实例的状态
+    ///
+    /// 心跳不改变注册数据，各模式下都直接发送给其它实例，不写入复制日志，也不产生 gossip 版本
+    pub fn heartbeat(&self, service_ids: Vec<String>) {
+        self.peer_cluster
+            .replicate(ReplicateOp::Heartbeat { service_ids });
+    }
+
+    /// 处理其它 Raft 节点发来的消息
+    pub async fn step(&self, message: RaftMessage) {
+        match &self.backend {
+            Backend::Raft(raft) => raft.step(message).await,
+            _ => warn!("Ignore raft message from [{}]", message.from),
+        }
+    }
+
+    /// 处理其它 gossip 节点发来的消息
+    pub fn gossip(&self, message: GossipMessage) {
+        match &self.backend {
+            Backend::Gossip(gossip) => gossip.step(message),
+            _ => warn!("Ignore gossip message from [{}]", message.from),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::request::RegistryRequest;
+    use crate::models::InstanceStatus;
+    use tokio::sync::broadcast;
+
+    fn registry(id: &str, port: u32) -> ReplicateOp {
+        ReplicateOp::Registry(RegistryRequest {
+            service: NewService {
+                id: id.to_string(),
+                name: "order".to_string(),
+                port,
+                host: "127.0.0.1".to_string(),
+                meta: None,
+                status: InstanceStatus::Up,
+                ttl: None,
+                heartbeat_interval: None,
+                health_check: None,
+            },
+        })
+    }
+
+    #[test]
+    fn idempotent_registry() {
+        let services_map = ServersMap::default();
+        let (publisher, mut receiver) = broadcast::channel(16);
+        let store = ServiceStore::new(
+            services_map.clone(),
+            ServersHeartbeatMap::default(),
+            publisher,
+            16,
+        );
+
+        store.apply(registry("order-1", 80));
+        assert_eq!(receiver.try_recv().unwrap().deltas()[0].added.len(), 1);
+        // 重复注册没有变化，不通知客户端
+        store.apply(registry("order-1", 80));
+        assert!(receiver.try_recv().is_err());
+        // 同一个实例ID更新信息
+        store.apply(registry("order-1", 81));
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.deltas()[0].updated[0].port, 81);
+        assert_eq!(event.deltas()[0].revision, 2);
+        assert_eq!(services_map.read()["order"].len(), 1);
+        assert_eq!(store.service_name_of("order-1").as_deref(), Some("order"));
+    }
+
+    #[test]
+    fn instance_status_transition() {
+        let services_map = ServersMap::default();
+        let (publisher, mut receiver) = broadcast::channel(16);
+        let store = ServiceStore::new(
+            services_map.clone(),
+            ServersHeartbeatMap::default(),
+            publisher,
+            16,
+        );
+        store.apply(registry("order-1", 80));
+        receiver.try_recv().unwrap();
+
+        let status = |status| ReplicateOp::InstanceStatus {
+            service_ids: vec!["order-1".to_string()],
+            status,
+        };
+        store.apply(status(InstanceStatus::Suspect));
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.deltas()[0].updated[0].status, InstanceStatus::Suspect);
+        // 状态没有变化，不通知客户端
+        store.apply(status(InstanceStatus::Suspect));
+        assert!(receiver.try_recv().is_err());
+        // 重新注册恢复为 UP
+        store.apply(registry("order-1", 80));
+        let event = receiver.try_recv().unwrap();
+        assert_eq!(event.deltas()[0].updated[0].status, InstanceStatus::Up);
+    }
+
+    #[test]
+    fn heartbeat_follows_registry() {
+        let services_map = ServersMap::default();
+        let heartbeats = ServersHeartbeatMap::default();
+        let (publisher, _receiver) = broadcast::channel(16);
+        let store = ServiceStore::new(services_map.clone(), heartbeats.clone(), publisher, 16);
+
+        // 应用注册时记录第一次心跳
+        store.apply(registry("order-1", 80));
+        let registered = heartbeats.read()["order-1"];
+        // 其它节点转发的心跳刷新心跳时间，不存在的实例不记录
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        store.apply(ReplicateOp::Heartbeat {
+            service_ids: vec!["order-1".to_string(), "order-2".to_string()],
+        });
+        assert!(heartbeats.read()["order-1"] > registered);
+        assert!(!heartbeats.read().contains_key("order-2"));
+        // 被剔除的实例清理心跳数据
+        store.apply(ReplicateOp::HeartbeatTimeout {
+            service_ids: vec!["order-1".to_string()],
+        });
+        assert!(heartbeats.read().is_empty());
+
+        // 导入快照时第一次看到的实例以导入的时间作为第一次心跳，已经不存在的实例清理心跳数据
+        store.apply(registry("order-1", 80));
+        let before = heartbeats.read()["order-1"];
+        let mut snapshot = store.snapshot();
+        snapshot
+            .get_mut("order")
+            .unwrap()
+            .push(match registry("order-2", 81) {
+                ReplicateOp::Registry(registry_request) => registry_request.service,
+                _ => unreachable!(),
+            });
+        heartbeats.write().insert("stale".to_string(), SystemTime::now());
+        store.install(snapshot);
+        assert_eq!(heartbeats.read()["order-1"], before);
+        assert!(heartbeats.read().contains_key("order-2"));
+        assert!(!heartbeats.read().contains_key("stale"));
+    }
+}
@@ -0,0 +1,82 @@
+//! 实例心跳的截止时间队列
+//!
+//! 心跳检测任务只在最早的截止时间到达时醒来，检查到期的实例；
+//! 每个实例只保留最早的截止时间，收到心跳不需要更新队列，到期时根据最后一次心跳重新计算下一个截止时间
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, SystemTime};
+
+#[derive(Default)]
+pub struct DeadlineQueue {
+    heap: BinaryHeap<Reverse<(SystemTime, String)>>,
+    /// 每个实例当前有效的截止时间，heap 中与之不同的是已经失效的条目
+    scheduled: HashMap<String, SystemTime>,
+}
+
+impl DeadlineQueue {
+    /// 安排实例在 deadline 时检查；已经安排了更早的截止时间时忽略
+    pub fn schedule(&mut self, service_id: &str, deadline: SystemTime) {
+        if let Some(scheduled) = self.scheduled.get(service_id) {
+            if *scheduled <= deadline {
+                return;
+            }
+        }
+        self.scheduled.insert(service_id.to_string(), deadline);
+        self.heap.push(Reverse((deadline, service_id.to_string())));
+    }
+
+    /// 距离最早的截止时间还需要等待多久，队列为空时返回 None
+    pub fn next_wait(&mut self) -> Option<Duration> {
+        while let Some(Reverse((deadline, service_id))) = self.heap.peek() {
+            if self.scheduled.get(service_id) == Some(deadline) {
+                let wait = deadline
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                return Some(wait);
+            }
+            self.heap.pop();
+        }
+        None
+    }
+
+    /// 取出截止时间不晚于 now 的实例
+    pub fn expired(&mut self, now: SystemTime) -> Vec<String> {
+        let mut expired = vec![];
+        while let Some(Reverse((deadline, _))) = self.heap.peek() {
+            if *deadline > now {
+                break;
+            }
+            if let Some(Reverse((deadline, service_id))) = self.heap.pop() {
+                if self.scheduled.get(&service_id) == Some(&deadline) {
+                    self.scheduled.remove(&service_id);
+                    expired.push(service_id);
+                }
+            }
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn earliest_deadline_wins() {
+        let now = SystemTime::now();
+        let mut queue = DeadlineQueue::default();
+        assert_eq!(queue.next_wait(), None);
+        queue.schedule("a", now + Duration::from_secs(30));
+        queue.schedule("b", now + Duration::from_secs(10));
+        // 更晚的截止时间被忽略，更早的替换之前的
+        queue.schedule("b", now + Duration::from_secs(20));
+        queue.schedule("a", now + Duration::from_secs(5));
+        assert!(queue.next_wait().unwrap() <= Duration::from_secs(5));
+
+        assert_eq!(queue.expired(now + Duration::from_secs(10)), vec!["a", "b"]);
+        // a 之前 30 秒的条目已经失效
+        assert_eq!(queue.next_wait(), None);
+        assert!(queue.expired(now + Duration::from_secs(60)).is_empty());
+    }
+}
@@ -0,0 +1,448 @@
+//! DNS 接口
+//!
+//! 只支持 DNS 的旧组件通过 UDP 解析 `<service_name>.service.connor`：
+//!
+//! - A：服务所有实例的 host（只返回 host 为 IPv4 地址的实例）
+//! - SRV：服务所有实例的 port，target 为 `<实例ID>.<service_name>.service.connor`，并在附加记录中带上它的 A 记录；
+//!   host 不是 IP 地址时 target 直接使用 host
+//!
+//! `<实例ID>.<service_name>.service.connor` 也可以单独解析 A 记录。
+//! 实例ID或者 host 无法组成合法域名（label 超过 63 字节或者域名超过 253 字节）的实例不返回 SRV 记录；
+//! 数据来自 ServersMap，DOWN 状态的实例，以及心跳已经超时、但还没有被心跳检测任务移除的实例不会返回
+
+use crate::models::{InstanceStatus, NewService};
+use crate::server_bootstrap::{is_heartbeat_timeout, service_ttl, ServersHeartbeatMap, ServersMap};
+use anyhow::Result;
+use bytes::{BufMut, BytesMut};
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::SystemTime;
+use tokio::net::UdpSocket;
+use tracing::{info, warn};
+
+/// 负责解析的域名
+const DOMAIN: &str = "service.connor";
+/// UDP 响应的最大长度，超出时截断并设置 TC 标识
+const MAX_UDP_LEN: usize = 512;
+const HEADER_LEN: usize = 12;
+/// 一条记录至少 16 字节（域名都以 service.connor 结尾），超出的记录不可能放进 UDP 响应
+const MAX_RECORDS: usize = MAX_UDP_LEN / 16;
+/// 域名中每个 label 的最大长度
+const MAX_LABEL_LEN: usize = 63;
+/// 域名（不带末尾的点）的最大长度
+const MAX_NAME_LEN: usize = 253;
+
+const TYPE_A: u16 = 1;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+const RCODE_FORMERR: u16 = 1;
+const RCODE_NXDOMAIN: u16 = 3;
+const RCODE_NOTIMP: u16 = 4;
+const RCODE_REFUSED: u16 = 5;
+
+/// 在指定地址上开启 DNS 接口
+pub async fn serve(
+    addr: &str,
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    ttl: u32,
+) -> Result<()> {
+    let socket = UdpSocket::bind(addr).await?;
+    let mut buf = [0u8; 1500];
+    loop {
+        let (len, peer) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("dns receive failed, err: [{:?}]", err);
+                continue;
+            }
+        };
+        let response = {
+            let services = services_map.read();
+            let heartbeats = services_heartbeat_map.read();
+            answer(&buf[..len], &services, &heartbeats, ttl)
+        };
+        if let Some(response) = response {
+            if let Err(err) = socket.send_to(&response, peer).await {
+                warn!("dns response to [{}] failed, err: [{:?}]", peer, err);
+            }
+        }
+    }
+}
+
+/// 解析出来的查询
+struct Query {
+    id: u16,
+    flags: u16,
+    /// 小写、不带末尾的点
+    name: String,
+    qtype: u16,
+    qclass: u16,
+}
+
+/// 响应中的一条记录
+enum Record {
+    A {
+        name: String,
+        addr: Ipv4Addr,
+    },
+    Srv {
+        name: String,
+        port: u16,
+        target: String,
+    },
+}
+
+/// 处理一个查询包，返回响应包；无法识别的数据不响应
+fn answer(
+    packet: &[u8],
+    services: &HashMap<String, Vec<NewService>>,
+    heartbeats: &HashMap<String, SystemTime>,
+    ttl: u32,
+) -> Option<Vec<u8>> {
+    if packet.len() < HEADER_LEN {
+        return None;
+    }
+    let id = u16::from_be_bytes([packet[0], packet[1]]);
+    let flags = u16::from_be_bytes([packet[2], packet[3]]);
+    // 响应包直接丢弃
+    if flags & 0x8000 != 0 {
+        return None;
+    }
+    let query = match parse_query(id, flags, packet) {
+        Ok(query) => query,
+        Err(rcode) => return Some(encode_error(id, flags, rcode)),
+    };
+    info!("dns query [{}] type [{}]", query.name, query.qtype);
+    if query.qclass != CLASS_IN {
+        return Some(encode(&query, 0, vec![], vec![], ttl));
+    }
+    let alive = |service: &&NewService| {
+        service.status != InstanceStatus::Down
+            && !heartbeats
+            .get(&service.id)
+            .is_some_and(|last_heartbeat| is_heartbeat_timeout(last_heartbeat, service_ttl(service)))
+    };
+    let (rcode, answers, additionals) = match resolve(&query, services, alive) {
+        Ok((answers, additionals)) => (0, answers, additionals),
+        Err(rcode) => (rcode, vec![], vec![]),
+    };
+    Some(encode(&query, rcode, answers, additionals, ttl))
+}
+
+/// 根据查询的域名查找实例，返回应答记录和附加记录
+fn resolve<F>(
+    query: &Query,
+    services: &HashMap<String, Vec<NewService>>,
+    alive: F,
+) -> Result<(Vec<Record>, Vec<Record>), u16>
+where
+    F: Fn(&&NewService) -> bool,
+{
+    let prefix = query
+        .name
+        .strip_suffix(DOMAIN)
+        .and_then(|prefix| prefix.strip_suffix('.'))
+        .ok_or(RCODE_REFUSED)?;
+    let find = |service_name: &str| {
+        services
+            .iter()
+            .find(|(name, list)| name.eq_ignore_ascii_case(service_name) && !list.is_empty())
+    };
+    let want_a = query.qtype == TYPE_A || query.qtype == TYPE_ANY;
+    let want_srv = query.qtype == TYPE_SRV || query.qtype == TYPE_ANY;
+
+    // <service_name>.service.connor
+    if let Some((service_name, list)) = find(prefix) {
+        let mut answers = vec![];
+        let mut additionals = vec![];
+        for service in list.iter().filter(alive) {
+            let addr = service.host.parse::<Ipv4Addr>().ok();
+            if let (true, Some(addr)) = (want_a, addr) {
+                answers.push(Record::A {
+                    name: query.name.clone(),
+                    addr,
+                });
+            }
+            if !want_srv {
+                continue;
+            }
+            let port = match u16::try_from(service.port) {
+                Ok(port) => port,
+                Err(_) => continue,
+            };
+            let target = match addr {
+                Some(_) => instance_name(&service.id, service_name),
+                None => service.host.to_ascii_lowercase(),
+            };
+            // 截断之后会指向其它的域名，跳过该实例
+            if !valid_name(&target) {
+                warn!("dns skip instance [{}], invalid target [{}]", service.id, target);
+                continue;
+            }
+            if let Some(addr) = addr {
+                additionals.push(Record::A {
+                    name: target.clone(),
+                    addr,
+                });
+            }
+            answers.push(Record::Srv {
+                name: query.name.clone(),
+                port,
+                target,
+            });
+        }
+        return Ok((answers, additionals));
+    }
+
+    // <实例ID>.<service_name>.service.connor
+    let (service_id, service_name) = prefix.split_once('.').ok_or(RCODE_NXDOMAIN)?;
+    let (_, list) = find(service_name).ok_or(RCODE_NXDOMAIN)?;
+    let service = list
+        .iter()
+        .filter(alive)
+        .find(|service| service.id.eq_ignore_ascii_case(service_id))
+        .ok_or(RCODE_NXDOMAIN)?;
+    let answers = match (want_a, service.host.parse::<Ipv4Addr>()) {
+        (true, Ok(addr)) => vec![Record::A {
+            name: query.name.clone(),
+            addr,
+        }],
+        _ => vec![],
+    };
+    Ok((answers, vec![]))
+}
+
+fn instance_name(service_id: &str, service_name: &str) -> String {
+    format!("{}.{}.{}", service_id, service_name, DOMAIN).to_ascii_lowercase()
+}
+
+/// 域名能否按照 DNS 的格式编码
+fn valid_name(name: &str) -> bool {
+    name.len() <= MAX_NAME_LEN
+        && name
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= MAX_LABEL_LEN)
+}
+
+fn parse_query(id: u16, flags: u16, packet: &[u8]) -> Result<Query, u16> {
+    // 只支持标准查询
+    if (flags >> 11) & 0xF != 0 {
+        return Err(RCODE_NOTIMP);
+    }
+    let question_count = u16::from_be_bytes([packet[4], packet[5]]);
+    if question_count != 1 {
+        return Err(RCODE_FORMERR);
+    }
+    let mut offset = HEADER_LEN;
+    let mut labels = vec![];
+    loop {
+        let len = *packet.get(offset).ok_or(RCODE_FORMERR)? as usize;
+        offset += 1;
+        if len == 0 {
+            break;
+        }
+        // 查询中的域名不应该使用压缩指针
+        if len & 0xC0 != 0 {
+            return Err(RCODE_FORMERR);
+        }
+        let label = packet.get(offset..offset + len).ok_or(RCODE_FORMERR)?;
+        labels.push(String::from_utf8_lossy(label).to_ascii_lowercase());
+        offset += len;
+    }
+    let name = labels.join(".");
+    if name.len() > MAX_NAME_LEN {
+        return Err(RCODE_FORMERR);
+    }
+    let tail = packet.get(offset..offset + 4).ok_or(RCODE_FORMERR)?;
+    Ok(Query {
+        id,
+        flags,
+        name,
+        qtype: u16::from_be_bytes([tail[0], tail[1]]),
+        qclass: u16::from_be_bytes([tail[2], tail[3]]),
+    })
+}
+
+/// 响应头部的 flags：QR、AA，保留请求的 opcode 和 RD
+fn response_flags(flags: u16, truncated: bool, rcode: u16) -> u16 {
+    let truncated = if truncated { 0x0200 } else { 0 };
+    0x8000 | (flags & 0x7800) | 0x0400 | truncated | (flags & 0x0100) | rcode
+}
+
+fn encode_error(id: u16, flags: u16, rcode: u16) -> Vec<u8> {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN);
+    buf.put_u16(id);
+    buf.put_u16(response_flags(flags, false, rcode));
+    buf.put_slice(&[0; 8]);
+    buf.to_vec()
+}
+
+/// 编码响应，超出 UDP 长度时先丢弃附加记录，再丢弃应答记录并设置 TC 标识
+fn encode(
+    query: &Query,
+    rcode: u16,
+    mut answers: Vec<Record>,
+    mut additionals: Vec<Record>,
+    ttl: u32,
+) -> Vec<u8> {
+    // 记录数不会超出头部的 u16 计数
+    let mut truncated = answers.len() > MAX_RECORDS;
+    answers.truncate(MAX_RECORDS);
+    additionals.truncate(MAX_RECORDS - answers.len());
+    loop {
+        let mut buf = BytesMut::with_capacity(MAX_UDP_LEN);
+        buf.put_u16(query.id);
+        buf.put_u16(response_flags(query.flags, truncated, rcode));
+        buf.put_u16(1);
+        buf.put_u16(answers.len() as u16);
+        buf.put_u16(0);
+        buf.put_u16(additionals.len() as u16);
+        put_name(&mut buf, &query.name);
+        buf.put_u16(query.qtype);
+        buf.put_u16(query.qclass);
+        for record in answers.iter().chain(additionals.iter()) {
+            put_record(&mut buf, record, ttl);
+        }
+        if buf.len() <= MAX_UDP_LEN {
+            return buf.to_vec();
+        }
+        if additionals.pop().is_none() {
+            answers.pop();
+            truncated = true;
+        }
+    }
+}
+
+fn put_record(buf: &mut BytesMut, record: &Record, ttl: u32) {
+    match record {
+        Record::A { name, addr } => {
+            put_name(buf, name);
+            buf.put_u16(TYPE_A);
+            buf.put_u16(CLASS_IN);
+            buf.put_u32(ttl);
+            buf.put_u16(4);
+            buf.put_slice(&addr.octets());
+        }
+        Record::Srv { name, port, target } => {
+            put_name(buf, name);
+            buf.put_u16(TYPE_SRV);
+            buf.put_u16(CLASS_IN);
+            buf.put_u32(ttl);
+            let mut rdata = BytesMut::new();
+            // priority、weight 相同，由调用方自行选择实例
+            rdata.put_u16(1);
+            rdata.put_u16(1);
+            rdata.put_u16(*port);
+            put_name(&mut rdata, target);
+            // target 是合法的域名，rdata 不超过 261 字节
+            buf.put_u16(rdata.len() as u16);
+            buf.put_slice(&rdata);
+        }
+    }
+}
+
+/// 编码域名，调用方保证每个 label 不超过 63 字节
+fn put_name(buf: &mut BytesMut, name: &str) {
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        buf.put_u8(label.len() as u8);
+        buf.put_slice(label.as_bytes());
+    }
+    buf.put_u8(0);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        buf.put_slice(&[0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+        put_name(&mut buf, name);
+        buf.put_u16(qtype);
+        buf.put_u16(CLASS_IN);
+        buf.to_vec()
+    }
+
+    fn services() -> HashMap<String, Vec<NewService>> {
+        let service = |id: &str, host: &str| NewService {
+            id: id.to_string(),
+            name: "web".to_string(),
+            port: 8080,
+            host: host.to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: Some(90),
+            heartbeat_interval: None,
+            health_check: None,
+        };
+        HashMap::from([(
+            "web".to_string(),
+            vec![
+                service("a", "10.0.0.1"),
+                service("b", "10.0.0.2"),
+                service("c", "web-c.local"),
+            ],
+        )])
+    }
+
+    /// (rcode, 应答记录数, 附加记录数)
+    fn counts(response: &[u8]) -> (u16, u16, u16) {
+        let word = |index: usize| u16::from_be_bytes([response[index], response[index + 1]]);
+        (word(2) & 0xF, word(6), word(10))
+    }
+
+    #[test]
+    fn resolve_records() {
+        let services = services();
+        // b 的心跳已经超时
+        let heartbeats = HashMap::from([(
+            "b".to_string(),
+            SystemTime::now() - Duration::from_secs(600),
+        )]);
+        let resolve = |name: &str, qtype: u16| {
+            counts(&answer(&query(name, qtype), &services, &heartbeats, 5).unwrap())
+        };
+        assert_eq!(resolve("web.service.connor", TYPE_A), (0, 1, 0));
+        assert_eq!(resolve("WEB.service.connor", TYPE_SRV), (0, 2, 1));
+        assert_eq!(resolve("a.web.service.connor", TYPE_A), (0, 1, 0));
+        assert_eq!(
+            resolve("b.web.service.connor", TYPE_A),
+            (RCODE_NXDOMAIN, 0, 0)
+        );
+        assert_eq!(resolve("db.service.connor", TYPE_A), (RCODE_NXDOMAIN, 0, 0));
+        assert_eq!(resolve("example.com", TYPE_A), (RCODE_REFUSED, 0, 0));
+    }
+
+    #[test]
+    fn skip_invalid_names_and_truncate() {
+        let mut services = services();
+        // 实例ID超过 63 字节，无法作为 SRV 的 target
+        services.get_mut("web").unwrap()[0].id = "a".repeat(64);
+        let heartbeats = HashMap::new();
+        let response = answer(&query("web.service.connor", TYPE_SRV), &services, &heartbeats, 5);
+        assert_eq!(counts(&response.unwrap()), (0, 2, 1));
+        let name = format!("{}.web.service.connor", "a".repeat(64));
+        assert!(!valid_name(&name));
+
+        // 实例过多时只返回放得下的记录，并设置 TC 标识
+        let template = services["web"][1].clone();
+        let list = (0..70000)
+            .map(|index| NewService {
+                id: index.to_string(),
+                ..template.clone()
+            })
+            .collect();
+        services.insert("web".to_string(), list);
+        let response = answer(&query("web.service.connor", TYPE_A), &services, &heartbeats, 5);
+        let response = response.unwrap();
+        let (rcode, answers, _) = counts(&response);
+        assert_eq!(rcode, 0);
+        assert!(answers > 0 && answers as usize <= MAX_RECORDS);
+        assert_ne!(u16::from_be_bytes([response[2], response[3]]) & 0x0200, 0);
+        assert!(response.len() <= MAX_UDP_LEN);
+    }
+}
@@ -0,0 +1,122 @@
+//! 服务变更的事件历史
+//!
+//! 每次数据变更产生的增量变更按照全局 revision 保存在有限长度的历史中，
+//! 断线重连的客户端携带最后收到的 revision 获取断开期间错过的变更；
+//! 历史已经被淘汰，或者 epoch 不同（节点重启、连接到了其它节点）时，客户端需要重新同步全部实例
+
+use crate::models::response::ServiceDeltaResponse;
+use std::collections::VecDeque;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct EventHistory {
+    /// 历史的标识，启动时生成；revision 只在同一个 epoch 内有意义
+    epoch: u64,
+    /// 最新的全局 revision
+    revision: u64,
+    /// 已经被淘汰（全部或者部分增量）的最大 revision
+    compacted: u64,
+    /// 最多保存的增量变更个数
+    capacity: usize,
+    deltas: VecDeque<ServiceDeltaResponse>,
+}
+
+impl EventHistory {
+    pub fn new(capacity: usize) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or_default();
+        Self {
+            epoch,
+            revision: 0,
+            compacted: 0,
+            capacity,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// 当前位置：(epoch, revision)
+    pub fn position(&self) -> (u64, u64) {
+        (self.epoch, self.revision)
+    }
+
+    /// 记录一次数据变更的增量，分配新的 revision；没有变化时不记录
+    pub fn record(&mut self, deltas: &mut [ServiceDeltaResponse]) {
+        if deltas.is_empty() {
+            return;
+        }
+        self.revision += 1;
+        for delta in deltas.iter_mut() {
+            delta.store_revision = self.revision;
+            self.deltas.push_back(delta.clone());
+        }
+        while self.deltas.len() > self.capacity {
+            if let Some(delta) = self.deltas.pop_front() {
+                self.compacted = delta.store_revision;
+            }
+        }
+    }
+
+    /// since_revision 之后的增量变更；epoch 不同或者需要的历史已经被淘汰时返回 None
+    pub fn since(&self, epoch: u64, since_revision: u64) -> Option<Vec<ServiceDeltaResponse>> {
+        let available = (self.compacted..=self.revision).contains(&since_revision);
+        if epoch != self.epoch || !available {
+            return None;
+        }
+        Some(
+            self.deltas
+                .iter()
+                .filter(|delta| delta.store_revision > since_revision)
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn delta(service_name: &str) -> ServiceDeltaResponse {
+        ServiceDeltaResponse {
+            service_name: service_name.to_string(),
+            revision: 1,
+            store_revision: 0,
+            added: vec![],
+            removed: vec!["id".to_string()],
+            updated: vec![],
+        }
+    }
+
+    #[test]
+    fn since_and_compaction() {
+        let mut history = EventHistory::new(3);
+        let (epoch, _) = history.position();
+        history.record(&mut [delta("a"), delta("b")]);
+        history.record(&mut []);
+        history.record(&mut [delta("c")]);
+        assert_eq!(history.position(), (epoch, 2));
+        let names = |deltas: Vec<ServiceDeltaResponse>| {
+            deltas
+                .into_iter()
+                .map(|delta| (delta.service_name, delta.store_revision))
+                .collect::<Vec<(String, u64)>>()
+        };
+        assert_eq!(
+            names(history.since(epoch, 0).unwrap()),
+            vec![
+                ("a".to_string(), 1),
+                ("b".to_string(), 1),
+                ("c".to_string(), 2)
+            ]
+        );
+        assert_eq!(history.since(epoch, 2), Some(vec![]));
+        assert_eq!(history.since(epoch + 1, 2), None);
+        assert_eq!(history.since(epoch, 3), None);
+
+        // 淘汰了 revision 1 的部分增量
+        history.record(&mut [delta("d")]);
+        assert_eq!(history.since(epoch, 0), None);
+        assert_eq!(names(history.since(epoch, 1).unwrap()).len(), 2);
+    }
+}
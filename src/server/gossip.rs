@@ -0,0 +1,303 @@
+//! gossip 最终一致模式
+//!
+//! 每个服务实例都带有向量时钟版本，本地写入后立即推送给其它节点，
+//! 同时定期随机选择一个节点交换版本摘要（anti-entropy），补齐推送过程中丢失的数据。
+//!
+//! 并发修改同一个实例时，按照 (最大版本号, 产生版本的节点) 较大者获胜，各节点合并的结果一致。
+//! 本节点的版本号不小于当前毫秒时间戳，节点重启之后产生的新版本仍然大于重启前的旧版本。
+//! 下线的实例以墓碑的形式保留，避免被其它节点的旧版本重新覆盖。
+
+use crate::models::request::{
+    DeregistryRequest, GossipBody, GossipEntry, GossipMessage, RegistryRequest, ReplicateOp,
+};
+use crate::models::{NewService, RpcCodec, VectorClock};
+use crate::protocol::{Codec, Protocol};
+use crate::server::cluster::ServiceStore;
+use crate::PeerCluster;
+use parking_lot::Mutex;
+use rand::seq::SliceRandom;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{interval, Duration};
+use tracing::{debug, error, info};
+
+/// gossip 节点
+#[derive(Clone)]
+pub struct GossipHandle {
+    id: String,
+    peers: Vec<String>,
+    peer_cluster: PeerCluster,
+    store: ServiceStore,
+    /// 所有实例的版本数据：<实例ID, 实例>
+    entries: Arc<Mutex<HashMap<String, GossipEntry>>>,
+}
+
+impl GossipHandle {
+    /// 创建节点，并开启定期交换摘要的任务
+    pub fn start(
+        id: String,
+        peers: Vec<String>,
+        peer_cluster: PeerCluster,
+        store: ServiceStore,
+        interval_secs: u64,
+    ) -> Self {
+        let handle = Self {
+            id,
+            peers,
+            peer_cluster,
+            store,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        };
+        let gossip = handle.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(interval_secs.max(1)));
+            loop {
+                ticker.tick().await;
+                gossip.send_digest();
+            }
+        });
+        handle
+    }
+
+    /// 本地写入一次数据变更，并立即推送给其它节点
+    pub fn submit(&self, op: ReplicateOp) {
+        let changed = {
+            let mut entries = self.entries.lock();
+            let changed = match &op {
+                ReplicateOp::Registry(registry_request) => {
+                    let service = &registry_request.service;
+                    vec![self.next_entry(
+                        &entries,
+                        &service.id,
+                        &service.name,
+                        Some(service.clone()),
+                    )]
+                }
+                ReplicateOp::Deregistry(deregistry_request) => vec![self.next_entry(
+                    &entries,
+                    &deregistry_request.service_id,
+                    &deregistry_request.service_name,
+                    None,
+                )],
+                ReplicateOp::HeartbeatTimeout { service_ids } => service_ids
+                    .iter()
+                    .filter_map(|service_id| {
+                        let service_name = entries.get(service_id)?.service_name.clone();
+                        Some(self.next_entry(&entries, service_id, &service_name, None))
+                    })
+                    .collect(),
+                // 状态是实例信息的一部分，作为实例的新版本交换
+                ReplicateOp::InstanceStatus {
+                    service_ids,
+                    status,
+                } => service_ids
+                    .iter()
+                    .filter_map(|service_id| {
+                        let entry = entries.get(service_id)?;
+                        let service = NewService {
+                            status: *status,
+                            ..entry.service.clone()?
+                        };
+                        let service_name = entry.service_name.clone();
+                        Some(self.next_entry(&entries, service_id, &service_name, Some(service)))
+                    })
+                    .collect(),
+                // 心跳不属于实例的版本数据，由 Cluster::heartbeat 直接发送给其它节点
+                ReplicateOp::Heartbeat { .. } => vec![],
+            };
+            for entry in &changed {
+                entries.insert(entry.service_id.clone(), entry.clone());
+            }
+            // 持有锁应用到 ServersMap，保证与版本数据的变更顺序一致
+            self.store.apply(op);
+            changed
+        };
+        if !changed.is_empty() {
+            self.broadcast(GossipBody::Entries { entries: changed });
+        }
+    }
+
+    /// 处理其它节点发来的消息
+    pub fn step(&self, message: GossipMessage) {
+        let GossipMessage { from, body } = message;
+        match body {
+            GossipBody::Digest { digest } => {
+                let (entries, pull) = self.diff(&digest);
+                if !entries.is_empty() || !pull.is_empty() {
+                    self.send(&from, GossipBody::Sync { entries, pull });
+                }
+            }
+            GossipBody::Sync { entries, pull } => {
+                self.merge(entries);
+                let pulled = {
+                    let local = self.entries.lock();
+                    pull.iter()
+                        .filter_map(|service_id| local.get(service_id).cloned())
+                        .collect::<Vec<GossipEntry>>()
+                };
+                if !pulled.is_empty() {
+                    self.send(&from, GossipBody::Entries { entries: pulled });
+                }
+            }
+            GossipBody::Entries { entries } => self.merge(entries),
+        }
+    }
+
+    /// 基于实例当前的版本生成本节点的新版本
+    fn next_entry(
+        &self,
+        entries: &HashMap<String, GossipEntry>,
+        service_id: &str,
+        service_name: &str,
+        service: Option<NewService>,
+    ) -> GossipEntry {
+        let mut clock = entries
+            .get(service_id)
+            .map(|entry| entry.clock.clone())
+            .unwrap_or_default();
+        clock.increment(&self.id, now_millis());
+        GossipEntry {
+            service_id: service_id.to_string(),
+            service_name: service_name.to_string(),
+            service,
+            clock,
+            origin: self.id.clone(),
+        }
+    }
+
+    /// 随机选择一个节点发送本节点的版本摘要
+    fn send_digest(&self) {
+        let peer = match self.peers.choose(&mut rand::thread_rng()) {
+            Some(peer) => peer,
+            None => return,
+        };
+        let digest = self
+            .entries
+            .lock()
+            .iter()
+            .map(|(service_id, entry)| (service_id.clone(), entry.clock.clone()))
+            .collect::<HashMap<String, VectorClock>>();
+        debug!(
+            "gossip digest with [{}] entries to [{}]",
+            digest.len(),
+            peer
+        );
+        self.send(peer, GossipBody::Digest { digest });
+    }
+
+    /// 对比对方的摘要，返回对方缺少或版本较旧的实例，以及本节点需要对方发回的实例ID
+    fn diff(&self, digest: &HashMap<String, VectorClock>) -> (Vec<GossipEntry>, Vec<String>) {
+        let local = self.entries.lock();
+        let entries = local
+            .values()
+            .filter(|entry| match digest.get(&entry.service_id) {
+                None => true,
+                Some(clock) => !matches!(
+                    entry.clock.compare(clock),
+                    Some(Ordering::Less) | Some(Ordering::Equal)
+                ),
+            })
+            .cloned()
+            .collect();
+        let pull = digest
+            .iter()
+            .filter(|(service_id, clock)| match local.get(*service_id) {
+                None => true,
+                Some(entry) => !matches!(
+                    clock.compare(&entry.clock),
+                    Some(Ordering::Less) | Some(Ordering::Equal)
+                ),
+            })
+            .map(|(service_id, _)| service_id.clone())
+            .collect();
+        (entries, pull)
+    }
+
+    /// 合并其它节点的实例数据，较新的版本会应用到 ServersMap
+    fn merge(&self, incoming: Vec<GossipEntry>) {
+        let mut local = self.entries.lock();
+        let mut accepted = 0;
+        for entry in incoming {
+            let merged = match local.get(&entry.service_id) {
+                None => Some(entry),
+                Some(current) => match entry.clock.compare(&current.clock) {
+                    Some(Ordering::Greater) => Some(entry),
+                    Some(Ordering::Less) | Some(Ordering::Equal) => None,
+                    // 并发修改：合并时钟，内容取获胜的一方
+                    None => {
+                        let mut clock = current.clock.clone();
+                        clock.merge(&entry.clock);
+                        let winner = if (entry.clock.max_version(), &entry.origin)
+                            > (current.clock.max_version(), &current.origin)
+                        {
+                            entry
+                        } else {
+                            current.clone()
+                        };
+                        Some(GossipEntry { clock, ..winner })
+                    }
+                },
+            };
+            let merged = match merged {
+                Some(merged) => merged,
+                None => continue,
+            };
+            let changed = match local.get(&merged.service_id) {
+                None => merged.service.is_some(),
+                Some(current) => current.service != merged.service,
+            };
+            if changed {
+                accepted += 1;
+                match &merged.service {
+                    Some(service) => self.store.apply(ReplicateOp::Registry(RegistryRequest {
+                        service: service.clone(),
+                    })),
+                    None => self.store.apply(ReplicateOp::Deregistry(DeregistryRequest {
+                        service_name: merged.service_name.clone(),
+                        service_id: merged.service_id.clone(),
+                    })),
+                }
+            }
+            local.insert(merged.service_id.clone(), merged);
+        }
+        if accepted > 0 {
+            info!("gossip merged [{}] changed instances", accepted);
+        }
+    }
+
+    fn send(&self, peer: &str, body: GossipBody) {
+        let message = GossipMessage {
+            from: self.id.clone(),
+            body,
+        };
+        match message
+            .to_frame(Codec::Json)
+            .and_then(|frame| frame.encode(Protocol::CURRENT))
+        {
+            Ok(data) => self.peer_cluster.send_to(peer, data),
+            Err(err) => error!("Encode gossip message failed, err: [{}]", err),
+        }
+    }
+
+    fn broadcast(&self, body: GossipBody) {
+        let message = GossipMessage {
+            from: self.id.clone(),
+            body,
+        };
+        match message
+            .to_frame(Codec::Json)
+            .and_then(|frame| frame.encode(Protocol::CURRENT))
+        {
+            Ok(data) => self.peer_cluster.send_all(data),
+            Err(err) => error!("Encode gossip message failed, err: [{}]", err),
+        }
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_millis() as u64)
+}
@@ -0,0 +1,490 @@
+//! gRPC 接口
+//!
+//! 接口定义见 proto/connor.proto，Java、Go 等语言可以直接生成强类型的客户端。
+//! 请求转换为帧交给 inbound_handle 处理，与 TCP 连接共享注册数据、心跳数据和集群；
+//! Watch 订阅与 TCP 连接相同的广播，推送服务变更事件，可以选择推送增量变更；
+//! 断线重连时携带 since_revision 从事件历史中补发错过的变更
+
+use crate::custom_error::ConnorError;
+use crate::models::request::{
+    DeregistryRequest, DiscoveryRequest, HeartbeatRequest, RegistryRequest, ResyncRequest,
+};
+use crate::models::response::ServiceDeltaResponse;
+use crate::models::{
+    HealthCheck, InboundHandleBroadcastEvent, InboundHandleSingleEvent, InstanceStatus, NewService,
+    RpcCodec,
+};
+use crate::protocol::Codec;
+use crate::server::cluster::Cluster;
+use crate::server::inbound::inbound_call;
+use crate::server::metrics::{Subscriber, METRICS};
+use crate::server::subscription::Subscriptions;
+use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
+use anyhow::Result;
+use futures::{stream, Stream, StreamExt};
+use serde::Serialize;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::BroadcastStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+use tracing::{info, warn};
+
+mod proto {
+    tonic::include_proto!("connor");
+}
+
+use proto::connor_server::ConnorServer;
+use proto::watch_event::Event;
+
+/// 在指定地址上开启 gRPC 接口
+pub async fn serve(
+    addr: &str,
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
+    broad_tx: broadcast::Sender<InboundHandleBroadcastEvent>,
+) -> Result<()> {
+    let addr = addr.parse::<SocketAddr>()?;
+    let service = GrpcService {
+        services_map,
+        services_heartbeat_map,
+        cluster,
+        broad_tx,
+    };
+    Server::builder()
+        .add_service(ConnorServer::new(service))
+        .serve(addr)
+        .await?;
+    Ok(())
+}
+
+struct GrpcService {
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
+    broad_tx: broadcast::Sender<InboundHandleBroadcastEvent>,
+}
+
+impl GrpcService {
+    /// 交给 inbound_handle 处理，返回单播的响应事件，错误响应转换为 gRPC 状态
+    async fn dispatch<T: RpcCodec + Serialize>(
+        &self,
+        request: T,
+    ) -> Result<InboundHandleSingleEvent, Status> {
+        let frame = request.to_frame(Codec::Json).map_err(error_status)?;
+        let event = inbound_call(
+            frame,
+            self.services_map.clone(),
+            self.services_heartbeat_map.clone(),
+            self.cluster.clone(),
+        )
+        .await;
+        match event {
+            Some(InboundHandleSingleEvent::ErrorResp { error, .. }) => Err(error_status(error)),
+            Some(event) => Ok(event),
+            None => Err(Status::internal("no response")),
+        }
+    }
+}
+
+type WatchStream = Pin<Box<dyn Stream<Item = Result<proto::WatchEvent, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl proto::connor_server::Connor for GrpcService {
+    async fn registry(
+        &self,
+        request: Request<proto::RegistryRequest>,
+    ) -> Result<Response<proto::RegistryResponse>, Status> {
+        let service = request
+            .into_inner()
+            .service
+            .ok_or_else(|| Status::invalid_argument("service is required"))?;
+        let request = RegistryRequest {
+            service: service.into(),
+        };
+        match self.dispatch(request).await? {
+            InboundHandleSingleEvent::ServiceRegistryResp {
+                success,
+                ttl,
+                heartbeat_interval,
+            } => Ok(Response::new(proto::RegistryResponse {
+                success,
+                ttl,
+                heartbeat_interval,
+            })),
+            event => Err(unexpected(event)),
+        }
+    }
+
+    async fn discovery(
+        &self,
+        request: Request<proto::DiscoveryRequest>,
+    ) -> Result<Response<proto::DiscoveryResponse>, Status> {
+        let request = DiscoveryRequest {
+            service_name: request.into_inner().service_name,
+        };
+        match self.dispatch(request).await? {
+            InboundHandleSingleEvent::ServiceDiscoveryResp {
+                service_name,
+                services,
+            } => Ok(Response::new(proto::DiscoveryResponse {
+                service_name,
+                found: services.is_some(),
+                services: services
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(Into::into)
+                    .collect(),
+            })),
+            event => Err(unexpected(event)),
+        }
+    }
+
+    async fn deregistry(
+        &self,
+        request: Request<proto::DeregistryRequest>,
+    ) -> Result<Response<proto::DeregistryResponse>, Status> {
+        let request = request.into_inner();
+        let request = DeregistryRequest {
+            service_name: request.service_name,
+            service_id: request.service_id,
+        };
+        match self.dispatch(request).await? {
+            InboundHandleSingleEvent::ServiceDeregistryResp { success } => {
+                Ok(Response::new(proto::DeregistryResponse { success }))
+            }
+            event => Err(unexpected(event)),
+        }
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<proto::HeartbeatRequest>,
+    ) -> Result<Response<proto::HeartbeatResponse>, Status> {
+        let request = HeartbeatRequest {
+            service_id: request.into_inner().service_id,
+        };
+        match self.dispatch(request).await? {
+            InboundHandleSingleEvent::HeartbeatResp { success } => {
+                Ok(Response::new(proto::HeartbeatResponse { success }))
+            }
+            event => Err(unexpected(event)),
+        }
+    }
+
+    type WatchStream = WatchStream;
+
+    async fn watch(
+        &self,
+        request: Request<proto::WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        info!("grpc watch from [{:?}]", request.remote_addr());
+        let request = request.into_inner();
+        let subscriptions = Subscriptions::default();
+        subscriptions.set_delta(request.delta);
+        if !request.service_names.is_empty() {
+            subscriptions.subscribe(request.service_names);
+        }
+        // 先订阅广播再读取事件历史，之后的变更只推送 revision 大于当前位置的部分，不会遗漏也不会重复
+        let receiver = self.broad_tx.subscribe();
+        let ((epoch, revision), replay) = match request.since_revision {
+            Some(since_revision) => {
+                let (position, deltas) = self.cluster.store().since(request.epoch, since_revision);
+                let deltas = deltas.ok_or_else(|| {
+                    Status::out_of_range(format!(
+                        "revision [{}] of epoch [{}] has been compacted, resync required",
+                        since_revision, request.epoch
+                    ))
+                })?;
+                (position, deltas)
+            }
+            None => (self.cluster.store().position(), vec![]),
+        };
+        let mut events = vec![proto::WatchEvent {
+            event: Some(Event::Started(proto::WatchStarted { epoch, revision })),
+        }];
+        events.extend(
+            replay
+                .into_iter()
+                .filter(|delta| subscriptions.matches(&delta.service_name))
+                .map(|delta| proto::WatchEvent {
+                    event: Some(Event::ServiceDelta(delta.into())),
+                }),
+        );
+        let live = BroadcastStream::new(receiver).flat_map(move |event| {
+            let events = match event {
+                Ok(event) => match unreplayed(event, revision) {
+                    Some(event) => watch_events(event, &subscriptions),
+                    None => vec![],
+                },
+                // 丢失的事件无法补发，通知客户端重新同步后继续推送
+                Err(BroadcastStreamRecvError::Lagged(lagged)) => {
+                    warn!("grpc watch lagged [{}] events, resync required", lagged);
+                    METRICS.broadcast_lagged(Subscriber::Grpc, lagged);
+                    vec![proto::WatchEvent {
+                        event: Some(Event::ResyncRequired(proto::ResyncRequired { lagged })),
+                    }]
+                }
+            };
+            stream::iter(events.into_iter().map(Ok))
+        });
+        let stream = stream::iter(events.into_iter().map(Ok)).chain(live);
+        Ok(Response::new(Box::pin(stream)))
+    }
+
+    async fn resync(
+        &self,
+        request: Request<proto::ResyncRequest>,
+    ) -> Result<Response<proto::ResyncResponse>, Status> {
+        let request = ResyncRequest {
+            service_name: request.into_inner().service_name,
+        };
+        match self.dispatch(request).await? {
+            InboundHandleSingleEvent::ResyncResp {
+                service_name,
+                revision,
+                services,
+            } => Ok(Response::new(proto::ResyncResponse {
+                service_name,
+                revision,
+                services: services.into_iter().map(Into::into).collect(),
+            })),
+            event => Err(unexpected(event)),
+        }
+    }
+}
+
+/// 去掉事件中在读取事件历史之前就已经发生（已经包含在当前位置中）的增量变更，
+/// 全部的增量变更都已经发生时返回 None
+fn unreplayed(
+    event: InboundHandleBroadcastEvent,
+    revision: u64,
+) -> Option<InboundHandleBroadcastEvent> {
+    let deltas = event.deltas();
+    if deltas.iter().all(|delta| delta.store_revision > revision) {
+        return Some(event);
+    }
+    let deltas = deltas
+        .iter()
+        .filter(|delta| delta.store_revision > revision)
+        .cloned()
+        .collect::<Vec<_>>();
+    if deltas.is_empty() {
+        return None;
+    }
+    Some(event.with_deltas(deltas))
+}
+
+/// 一个广播事件转换为推送给 Watch 的事件：增量推送时每个订阅的服务一个事件
+fn watch_events(
+    event: InboundHandleBroadcastEvent,
+    subscriptions: &Subscriptions,
+) -> Vec<proto::WatchEvent> {
+    if subscriptions.delta() {
+        event
+            .deltas()
+            .iter()
+            .filter(|delta| subscriptions.matches(&delta.service_name))
+            .map(|delta| proto::WatchEvent {
+                event: Some(Event::ServiceDelta(delta.clone().into())),
+            })
+            .collect()
+    } else if subscriptions.accepts(&event) {
+        vec![event.into()]
+    } else {
+        vec![]
+    }
+}
+
+/// 处理失败的错误转换为 gRPC 状态
+fn error_status(error: ConnorError) -> Status {
+    match error {
+        ConnorError::Unsupported(_) => Status::unimplemented(error.to_string()),
+        ConnorError::IdConflict { .. } => Status::already_exists(error.to_string()),
+        ConnorError::ScriptCheckDisabled => Status::permission_denied(error.to_string()),
+        error => Status::invalid_argument(error.to_string()),
+    }
+}
+
+fn unexpected(event: InboundHandleSingleEvent) -> Status {
+    Status::internal(format!("unexpected response {:?}", event))
+}
+
+impl From<proto::Service> for NewService {
+    fn from(service: proto::Service) -> Self {
+        let status = service.status().into();
+        let health_check = service
+            .health_check
+            .and_then(|health_check| health_check.check)
+            .map(Into::into);
+        Self {
+            id: service.id,
+            name: service.name,
+            port: service.port,
+            host: service.host,
+            meta: (!service.meta.is_empty()).then_some(service.meta),
+            status,
+            ttl: service.ttl,
+            heartbeat_interval: service.heartbeat_interval,
+            health_check,
+        }
+    }
+}
+
+impl From<NewService> for proto::Service {
+    fn from(service: NewService) -> Self {
+        Self {
+            id: service.id,
+            name: service.name,
+            port: service.port,
+            host: service.host,
+            meta: service.meta.unwrap_or_default(),
+            status: proto::InstanceStatus::from(service.status).into(),
+            ttl: service.ttl,
+            heartbeat_interval: service.heartbeat_interval,
+            health_check: service.health_check.map(|health_check| proto::HealthCheck {
+                check: Some(health_check.into()),
+            }),
+        }
+    }
+}
+
+impl From<proto::health_check::Check> for HealthCheck {
+    fn from(check: proto::health_check::Check) -> Self {
+        match check {
+            proto::health_check::Check::Tcp(_) => HealthCheck::Tcp,
+            proto::health_check::Check::Http(http) => HealthCheck::Http {
+                path: http.path,
+                expected_status: http.expected_status.unwrap_or(200) as u16,
+            },
+            proto::health_check::Check::Script(script) => HealthCheck::Script {
+                command: script.command,
+                args: script.args,
+            },
+        }
+    }
+}
+
+impl From<HealthCheck> for proto::health_check::Check {
+    fn from(health_check: HealthCheck) -> Self {
+        match health_check {
+            HealthCheck::Tcp => proto::health_check::Check::Tcp(proto::TcpCheck {}),
+            HealthCheck::Http {
+                path,
+                expected_status,
+            } => proto::health_check::Check::Http(proto::HttpCheck {
+                path,
+                expected_status: Some(expected_status as u32),
+            }),
+            HealthCheck::Script { command, args } => {
+                proto::health_check::Check::Script(proto::ScriptCheck { command, args })
+            }
+        }
+    }
+}
+
+impl From<proto::InstanceStatus> for InstanceStatus {
+    fn from(status: proto::InstanceStatus) -> Self {
+        match status {
+            proto::InstanceStatus::Up => InstanceStatus::Up,
+            proto::InstanceStatus::Suspect => InstanceStatus::Suspect,
+            proto::InstanceStatus::Down => InstanceStatus::Down,
+        }
+    }
+}
+
+impl From<InstanceStatus> for proto::InstanceStatus {
+    fn from(status: InstanceStatus) -> Self {
+        match status {
+            InstanceStatus::Up => proto::InstanceStatus::Up,
+            InstanceStatus::Suspect => proto::InstanceStatus::Suspect,
+            InstanceStatus::Down => proto::InstanceStatus::Down,
+        }
+    }
+}
+
+impl From<InboundHandleBroadcastEvent> for proto::WatchEvent {
+    fn from(event: InboundHandleBroadcastEvent) -> Self {
+        let services = |list: Vec<NewService>| list.into_iter().map(Into::into).collect();
+        let event = match event {
+            InboundHandleBroadcastEvent::AddServiceResp {
+                service_name,
+                service_list,
+                ..
+            } => Event::AddService(proto::AddService {
+                service_name,
+                service_list: services(service_list),
+            }),
+            InboundHandleBroadcastEvent::RemoveServiceResp {
+                service_name,
+                service_list,
+                ..
+            } => Event::RemoveService(proto::RemoveService {
+                service_name,
+                service_list: services(service_list),
+            }),
+            InboundHandleBroadcastEvent::HeartbeatTimeoutResp {
+                service_ids,
+                deltas,
+            } => Event::HeartbeatTimeout(proto::HeartbeatTimeout {
+                service_ids,
+                service_names: deltas.into_iter().map(|delta| delta.service_name).collect(),
+            }),
+            InboundHandleBroadcastEvent::InstanceStatusResp {
+                service_ids,
+                status,
+                ..
+            } => Event::InstanceStatus(proto::InstanceStatusChanged {
+                service_ids,
+                status: proto::InstanceStatus::from(status).into(),
+            }),
+        };
+        Self { event: Some(event) }
+    }
+}
+
+impl From<ServiceDeltaResponse> for proto::ServiceDelta {
+    fn from(delta: ServiceDeltaResponse) -> Self {
+        let services = |list: Vec<NewService>| list.into_iter().map(Into::into).collect();
+        Self {
+            service_name: delta.service_name,
+            revision: delta.revision,
+            store_revision: delta.store_revision,
+            added: services(delta.added),
+            removed: delta.removed,
+            updated: services(delta.updated),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn delta(service_name: &str, store_revision: u64) -> ServiceDeltaResponse {
+        ServiceDeltaResponse {
+            service_name: service_name.to_string(),
+            revision: store_revision,
+            store_revision,
+            added: vec![],
+            removed: vec!["id".to_string()],
+            updated: vec![],
+        }
+    }
+
+    #[test]
+    fn skip_replayed_deltas() {
+        let event = InboundHandleBroadcastEvent::HeartbeatTimeoutResp {
+            service_ids: vec!["id".to_string()],
+            deltas: vec![delta("a", 3), delta("b", 4)],
+        };
+        // 只去掉已经通过事件历史推送过的增量变更
+        let rest = unreplayed(event.clone(), 3).unwrap();
+        assert_eq!(rest.deltas(), [delta("b", 4)]);
+        assert_eq!(unreplayed(event.clone(), 2).unwrap(), event);
+        assert!(unreplayed(event, 4).is_none());
+    }
+}
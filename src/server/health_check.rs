@@ -0,0 +1,225 @@
+//! 服务端主动健康检查
+//!
+//! 无法发送心跳的实例注册时携带 health_check，服务端按照实例的 heartbeat_interval 主动探测：
+//! 探测成功等同于收到一次心跳（heartbeat::renew），失败时不续约，
+//! 由心跳检测任务按照错过心跳的时间推进实例的状态并剔除，与发送心跳的实例走相同的路径
+
+use crate::config::SERVER_CONFIG;
+use crate::models::{HealthCheck, InboundHandleBroadcastEvent, NewService};
+use crate::server::cluster::Cluster;
+use crate::server::deadline_queue::DeadlineQueue;
+use crate::server::inbound::heartbeat;
+use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
+use anyhow::{anyhow, Result};
+use std::time::{Duration, SystemTime};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{sleep, timeout};
+use tracing::{debug, warn};
+
+/// HTTP 探测读取状态行的最大长度
+const STATUS_LINE_LIMIT: u64 = 1024;
+
+/// 按照每个实例的间隔探测携带 health_check 的实例；新注册的实例从广播的变更中获得
+pub async fn run(
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
+    mut receiver: broadcast::Receiver<InboundHandleBroadcastEvent>,
+) {
+    let mut deadlines = DeadlineQueue::default();
+    schedule_all(&mut deadlines, &services_map);
+    loop {
+        let wait = deadlines.next_wait();
+        tokio::select! {
+            _ = sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                let now = SystemTime::now();
+                for service_id in deadlines.expired(now) {
+                    let service = services_map
+                        .read()
+                        .values()
+                        .flatten()
+                        .find(|service| service.id.eq(&service_id))
+                        .cloned();
+                    // 已经移除或者不再需要探测的实例
+                    let service = service
+                        .and_then(|service| Some((service.health_check.clone()?, service)));
+                    let (health_check, service) = match service {
+                        Some((health_check, service)) if allowed(&health_check) => {
+                            (health_check, service)
+                        }
+                        _ => continue,
+                    };
+                    let (_, interval) =
+                        SERVER_CONFIG.heartbeat_policy(service.ttl, service.heartbeat_interval);
+                    let interval = Duration::from_secs(interval);
+                    deadlines.schedule(&service_id, now + interval);
+
+                    let services_map = services_map.clone();
+                    let services_heartbeat_map = services_heartbeat_map.clone();
+                    let cluster = cluster.clone();
+                    let probe_timeout = interval.min(Duration::from_secs(SERVER_CONFIG.health_check_timeout));
+                    tokio::spawn(async move {
+                        let result = match timeout(probe_timeout, probe(&service, &health_check)).await {
+                            Ok(result) => result,
+                            Err(_) => Err(anyhow!("timeout after {:?}", probe_timeout)),
+                        };
+                        match result {
+                            Ok(()) => {
+                                debug!("health check [{}] passed", service.id);
+                                heartbeat::renew(&service.id, &services_heartbeat_map, &services_map, &cluster);
+                            }
+                            Err(err) => warn!("health check [{}] failed, err: [{}]", service.id, err),
+                        }
+                    });
+                }
+            }
+            event = receiver.recv() => match event {
+                // 新注册或者更新了的实例立即探测
+                Ok(event) => {
+                    let now = SystemTime::now();
+                    event
+                        .deltas()
+                        .iter()
+                        .flat_map(|delta| delta.added.iter().chain(delta.updated.iter()))
+                        .filter(|service| service.health_check.is_some())
+                        .for_each(|service| deadlines.schedule(&service.id, now));
+                }
+                Err(RecvError::Lagged(lagged)) => {
+                    warn!("health check task lagged [{}] events, check all instances", lagged);
+                    schedule_all(&mut deadlines, &services_map);
+                }
+                Err(RecvError::Closed) => break,
+            },
+        }
+    }
+}
+
+/// 立即探测所有携带 health_check 的实例
+fn schedule_all(deadlines: &mut DeadlineQueue, services_map: &ServersMap) {
+    let now = SystemTime::now();
+    services_map
+        .read()
+        .values()
+        .flatten()
+        .filter(|service| service.health_check.is_some())
+        .for_each(|service| deadlines.schedule(&service.id, now));
+}
+
+/// 脚本健康检查需要开启 script_health_check；从其它实例复制过来的注册数据同样受限
+fn allowed(health_check: &HealthCheck) -> bool {
+    !matches!(health_check, HealthCheck::Script { .. }) || SERVER_CONFIG.script_health_check
+}
+
+/// 探测一次实例
+async fn probe(service: &NewService, health_check: &HealthCheck) -> Result<()> {
+    let addr = (service.host.as_str(), u16::try_from(service.port)?);
+    match health_check {
+        HealthCheck::Tcp => {
+            TcpStream::connect(addr).await?;
+        }
+        HealthCheck::Http {
+            path,
+            expected_status,
+        } => {
+            let mut stream = TcpStream::connect(addr).await?;
+            let path = if path.starts_with('/') {
+                path.clone()
+            } else {
+                format!("/{}", path)
+            };
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUser-Agent: connor-health-check\r\nConnection: close\r\n\r\n",
+                path, service.host, service.port
+            );
+            stream.write_all(request.as_bytes()).await?;
+            // 只读取状态行，限制读取的长度，避免实例返回没有换行的超长响应
+            let mut status_line = String::new();
+            BufReader::new(stream.take(STATUS_LINE_LIMIT))
+                .read_line(&mut status_line)
+                .await?;
+            let status = parse_status(&status_line)
+                .ok_or_else(|| anyhow!("bad response [{}]", status_line.trim_end()))?;
+            if status != *expected_status {
+                return Err(anyhow!("unexpected status [{}]", status));
+            }
+        }
+        HealthCheck::Script { command, args } => {
+            let status = Command::new(command)
+                .args(args)
+                .env("CONNOR_SERVICE_ID", &service.id)
+                .env("CONNOR_SERVICE_HOST", &service.host)
+                .env("CONNOR_SERVICE_PORT", service.port.to_string())
+                .kill_on_drop(true)
+                .status()
+                .await?;
+            if !status.success() {
+                return Err(anyhow!("script exited with [{}]", status));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 解析 HTTP 响应的状态行：`HTTP/1.1 200 OK`
+fn parse_status(status_line: &str) -> Option<u16> {
+    let mut parts = status_line.split_whitespace();
+    if !parts.next()?.starts_with("HTTP/") {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::InstanceStatus;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn status_line() {
+        assert_eq!(parse_status("HTTP/1.1 200 OK\r\n"), Some(200));
+        assert_eq!(parse_status("HTTP/1.0 503 Service Unavailable"), Some(503));
+        assert_eq!(parse_status("SSH-2.0-OpenSSH"), None);
+    }
+
+    #[tokio::test]
+    async fn probe_http() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = [0; 1024];
+                let len = stream.read(&mut buf).await.unwrap_or_default();
+                let request = String::from_utf8_lossy(&buf[..len]).to_string();
+                let response = if request.starts_with("GET /health ") {
+                    "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n"
+                } else {
+                    "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+                };
+                let _ = stream.write_all(response.as_bytes()).await;
+            }
+        });
+        let service = NewService {
+            id: "web-1".to_string(),
+            name: "web".to_string(),
+            port: port as u32,
+            host: "127.0.0.1".to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: None,
+            heartbeat_interval: None,
+            health_check: None,
+        };
+        let http = |path: &str| HealthCheck::Http {
+            path: path.to_string(),
+            expected_status: 200,
+        };
+        assert!(probe(&service, &HealthCheck::Tcp).await.is_ok());
+        assert!(probe(&service, &http("health")).await.is_ok());
+        assert!(probe(&service, &http("/missing")).await.is_err());
+    }
+}
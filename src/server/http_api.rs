@@ -0,0 +1,272 @@
+//! HTTP REST 接口
+//!
+//! 为无法使用 TCP 协议的服务提供与 inbound 相同的操作：将 HTTP 请求转换为帧交给 inbound_handle 处理，
+//! 与 TCP 连接共享注册数据、心跳数据和集群，注册/下线同样会推送给 TCP 客户端
+//!
+//! | 方法 | 路径 | 操作 |
+//! | --- | --- | --- |
+//! | POST | /v1/services | 服务注册，请求体为 NewService |
+//! | GET | /v1/services | 获取所有的 service-names |
+//! | GET | /v1/services/:service_name | 服务发现 |
+//! | DELETE | /v1/services/:service_name/:service_id | 服务下线 |
+//! | GET | /v1/instances/:service_id | 服务检测 |
+//! | PUT | /v1/instances/:service_id/heartbeat | 心跳 |
+//! | GET | /metrics | 运行指标（Prometheus 文本格式） |
+//!
+//! 响应体与 TCP 协议的 json 响应一致，处理失败时返回 400 和 ErrorResponse（实例ID已经属于其它服务时为 409）；
+//! 写操作没有提交成功（raft 模式下没有 leader 或者达不到多数派）时返回 503，下线、心跳的实例不存在时返回 404
+
+use crate::custom_error::ConnorError;
+use crate::models::request::{
+    DeregistryRequest, DiscoveryRequest, DiscoveryServiceNamesRequest, HeartbeatRequest,
+    RegistryRequest, ServiceCheckRequest,
+};
+use crate::models::{InboundHandleSingleEvent, NewService, RpcCodec};
+use crate::protocol::Codec;
+use crate::server::cluster::Cluster;
+use crate::server::inbound::inbound_call;
+use crate::server::metrics::METRICS;
+use crate::server::outbound::response_frame;
+use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
+use anyhow::Result;
+use axum::extract::{Extension, Path};
+use axum::http::{header, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, put};
+use axum::{Json, Router};
+use serde::Serialize;
+use std::net::SocketAddr;
+
+/// HTTP 请求处理共享的数据
+#[derive(Clone)]
+struct HttpState {
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
+}
+
+/// 在指定地址上开启 HTTP REST 接口
+pub async fn serve(
+    addr: &str,
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
+) -> Result<()> {
+    let addr = addr.parse::<SocketAddr>()?;
+    let state = HttpState {
+        services_map,
+        services_heartbeat_map,
+        cluster,
+    };
+    let app = Router::new()
+        .route("/v1/services", get(service_names).post(registry))
+        .route("/v1/services/:service_name", get(discovery))
+        .route("/v1/services/:service_name/:service_id", delete(deregistry))
+        .route("/v1/instances/:service_id", get(service_check))
+        .route("/v1/instances/:service_id/heartbeat", put(heartbeat))
+        .route("/metrics", get(metrics))
+        .layer(Extension(state));
+    axum::Server::try_bind(&addr)?
+        .serve(app.into_make_service())
+        .await?;
+    Ok(())
+}
+
+async fn registry(
+    Extension(state): Extension<HttpState>,
+    Json(service): Json<NewService>,
+) -> Response {
+    dispatch(&state, RegistryRequest { service }).await
+}
+
+async fn service_names(Extension(state): Extension<HttpState>) -> Response {
+    dispatch(&state, DiscoveryServiceNamesRequest {}).await
+}
+
+async fn discovery(
+    Extension(state): Extension<HttpState>,
+    Path(service_name): Path<String>,
+) -> Response {
+    dispatch(&state, DiscoveryRequest { service_name }).await
+}
+
+async fn deregistry(
+    Extension(state): Extension<HttpState>,
+    Path((service_name, service_id)): Path<(String, String)>,
+) -> Response {
+    // 实例不存在或者不属于该服务时不提交
+    let registered = state.cluster.store().service_name_of(&service_id);
+    if registered.as_deref() != Some(service_name.as_str()) {
+        let event = InboundHandleSingleEvent::ServiceDeregistryResp { success: false };
+        return status_response(StatusCode::NOT_FOUND, event);
+    }
+    let request = DeregistryRequest {
+        service_name,
+        service_id,
+    };
+    dispatch(&state, request).await
+}
+
+async fn service_check(
+    Extension(state): Extension<HttpState>,
+    Path(service_id): Path<String>,
+) -> Response {
+    dispatch(&state, ServiceCheckRequest { service_id }).await
+}
+
+async fn heartbeat(
+    Extension(state): Extension<HttpState>,
+    Path(service_id): Path<String>,
+) -> Response {
+    dispatch(&state, HeartbeatRequest { service_id }).await
+}
+
+async fn metrics() -> Response {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        METRICS.render(),
+    )
+        .into_response()
+}
+
+/// 交给 inbound_handle 处理，将单播的响应事件转换为 json 响应
+async fn dispatch<T: RpcCodec + Serialize>(state: &HttpState, request: T) -> Response {
+    let frame = match request.to_frame(Codec::Json) {
+        Ok(frame) => frame,
+        Err(error) => {
+            let rpc_kind = Some(T::rpc_kind());
+            return json_response(InboundHandleSingleEvent::ErrorResp { error, rpc_kind });
+        }
+    };
+    let event = inbound_call(
+        frame,
+        state.services_map.clone(),
+        state.services_heartbeat_map.clone(),
+        state.cluster.clone(),
+    )
+    .await;
+    match event {
+        Some(event) => json_response(event),
+        None => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+/// 按照响应事件选择状态码：处理失败返回 400，写操作没有提交成功返回 503，实例不存在返回 404
+fn json_response(event: InboundHandleSingleEvent) -> Response {
+    let status = match event {
+        InboundHandleSingleEvent::ErrorResp {
+            error: ConnorError::IdConflict { .. },
+            ..
+        } => StatusCode::CONFLICT,
+        InboundHandleSingleEvent::ErrorResp {
+            error: ConnorError::ScriptCheckDisabled,
+            ..
+        } => StatusCode::FORBIDDEN,
+        InboundHandleSingleEvent::ErrorResp { .. } => StatusCode::BAD_REQUEST,
+        // 写操作没有提交成功
+        InboundHandleSingleEvent::ServiceRegistryResp { success: false, .. }
+        | InboundHandleSingleEvent::ServiceDeregistryResp { success: false } => {
+            StatusCode::SERVICE_UNAVAILABLE
+        }
+        // 实例不存在，需要重新注册
+        InboundHandleSingleEvent::HeartbeatResp { success: false } => StatusCode::NOT_FOUND,
+        _ => StatusCode::OK,
+    };
+    status_response(status, event)
+}
+
+/// 响应事件转换为指定状态码的 json 响应
+fn status_response(status: StatusCode, event: InboundHandleSingleEvent) -> Response {
+    let body = response_frame(event, Codec::Json).body;
+    (status, [(header::CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::models::request::ReplicateOp;
+    use crate::models::InstanceStatus;
+    use crate::server::cluster::ServiceStore;
+    use crate::PeerCluster;
+    use config::{Config, File, FileFormat};
+    use tokio::sync::broadcast;
+
+    fn state(config: &str) -> HttpState {
+        let config = Config::builder()
+            .add_source(File::from_str(config, FileFormat::Yaml))
+            .build()
+            .and_then(|config| config.try_deserialize::<ServerConfig>())
+            .unwrap();
+        let services_map = ServersMap::default();
+        let services_heartbeat_map = ServersHeartbeatMap::default();
+        let (publisher, _) = broadcast::channel(16);
+        let store = ServiceStore::new(
+            services_map.clone(),
+            services_heartbeat_map.clone(),
+            publisher,
+            16,
+        );
+        HttpState {
+            services_map,
+            services_heartbeat_map,
+            cluster: Cluster::new(&config, store, PeerCluster::default()).unwrap(),
+        }
+    }
+
+    /// 注册处理读取全局配置，测试中直接写入注册数据
+    fn registered(state: &HttpState) {
+        let service = NewService {
+            id: "a-1".to_string(),
+            name: "a".to_string(),
+            port: 80,
+            host: "127.0.0.1".to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: None,
+            heartbeat_interval: None,
+            health_check: None,
+        };
+        state
+            .cluster
+            .store()
+            .apply(ReplicateOp::Registry(RegistryRequest { service }));
+    }
+
+    fn path(service_name: &str) -> Path<(String, String)> {
+        Path((service_name.to_string(), "a-1".to_string()))
+    }
+
+    #[tokio::test]
+    async fn unknown_instance_not_found() {
+        let state = state("server_address: 127.0.0.1:9080\ncluster_address: []");
+        let response = deregistry(Extension(state.clone()), path("a")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = heartbeat(Extension(state.clone()), Path("a-1".to_string())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        registered(&state);
+        let response = heartbeat(Extension(state.clone()), Path("a-1".to_string())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        // 实例不属于该服务
+        let response = deregistry(Extension(state.clone()), path("b")).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let response = deregistry(Extension(state.clone()), path("a")).await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn uncommitted_write_unavailable() {
+        // 另一个 raft 节点不可达，达不到多数派
+        let data_dir = std::env::temp_dir().join(format!("connor-http-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&data_dir);
+        let state = state(&format!(
+            "server_address: 127.0.0.1:9080\ncluster_address: [127.0.0.1:1]\ncluster_mode: raft\nraft_data_dir: {}",
+            data_dir.display()
+        ));
+        registered(&state);
+        let response = deregistry(Extension(state), path("a")).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let _ = std::fs::remove_dir_all(&data_dir);
+    }
+}
@@ -1,107 +1,151 @@
 //! 消息入站处理模块
 
-mod deregistry;
+pub mod deregistry;
 mod discovery;
 mod discovery_names;
-mod heartbeat;
-mod registry;
+mod gossip;
+mod handshake;
+pub mod heartbeat;
+mod raft;
+pub mod registry;
+mod replicate;
+mod resync;
 mod service_check;
+pub mod snapshot;
+mod subscribe;
+mod unsubscribe;
+mod watch;
 
-use crate::models::InboundHandleSingleEvent::ServiceDeregistryResp;
-use crate::models::{InboundHandleBroadcastEvent, InboundHandleSingleEvent, RpcKind};
+use crate::custom_error::ConnorError;
+use crate::models::{InboundHandleSingleEvent, RpcKind};
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
+use crate::server::subscription::Subscriptions;
 use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
-use tokio::sync::broadcast::Sender;
+use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender as SingleSender;
-use tracing::error;
+use tracing::{error, warn};
 
 /// 消息入站处理参数
 pub struct InboundParams {
-    rpc_kind: RpcKind,
-    json: String,
-    broad: Sender<InboundHandleBroadcastEvent>,
-    unicast: SingleSender<InboundHandleSingleEvent>,
+    frame: Frame,
+    unicast: SingleSender<(u64, InboundHandleSingleEvent)>,
+    /// 请求所在连接的订阅
+    subscriptions: Subscriptions,
 }
 impl InboundParams {
     pub fn new(
-        rpc_kind: RpcKind,
-        json: String,
-        broad: Sender<InboundHandleBroadcastEvent>,
-        unicast: SingleSender<InboundHandleSingleEvent>,
+        frame: Frame,
+        unicast: SingleSender<(u64, InboundHandleSingleEvent)>,
+        subscriptions: Subscriptions,
     ) -> Self {
         Self {
-            rpc_kind,
-            json,
-            broad,
+            frame,
             unicast,
+            subscriptions,
         }
     }
-    /// 单播发布事件消息
+    /// 单播发布事件消息，响应带回请求的 request id
     async fn unicast(&self, handle_event: InboundHandleSingleEvent) {
-        if let Err(err) = self.unicast.send(handle_event).await {
+        if let Err(err) = self.unicast.send((self.frame.request_id, handle_event)).await {
             error!("Response Event Error [{:?}]", err);
         }
     }
-    /// 发布事件消息
-    fn publisher(&self, handle_event: InboundHandleBroadcastEvent) {
-        if let Err(err) = self.broad.send(handle_event) {
-            error!("Publisher Event Error [{:?}]", err);
-        }
-    }
 }
 
-/// 根据解析后的请求类型 和 json 体进行后续处理
+/// 根据解析后的请求类型 和 消息体进行后续处理
+///
+/// 写操作通过 cluster 提交，提交后由 cluster 发布更新客户端缓存的事件，
+/// 由Connor 主动向 client 发送服务刷新请求；处理失败时向 client 发送错误响应
 // #[instrument]
 pub async fn inbound_handle(
     params: InboundParams,
     services_map: ServersMap,
     services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
 ) {
-    match params.rpc_kind {
+    let frame = &params.frame;
+    let handle_result = match frame.rpc_kind {
         // 服务注册
-        RpcKind::Registry => {
-            let new_service = registry::handle(&params.json, services_map).await;
-            // 首先发布此次请求的响应事件
-            params
-                .unicast(InboundHandleSingleEvent::ServiceRegistryResp { success: true })
-                .await;
-            // 然后发布更新客户端缓存信息的事件，由Connor 主动向 client 发送服务刷新请求
-            params.publisher(new_service);
-        }
+        RpcKind::Registry => registry::handle(frame, &cluster).await.map(Some),
         // 服务发现：根据service-name 获取所有的service
-        RpcKind::Discovery => {
-            let handle_event = discovery::handle(&params.json, services_map).await;
-            params.unicast(handle_event).await;
-        }
+        RpcKind::Discovery => discovery::handle(frame, services_map).await.map(Some),
         // 获取所有的service-names
-        RpcKind::DiscoveryNames => {
-            let handle_event = discovery_names::handle(&params.json, services_map).await;
-            params.unicast(handle_event).await;
-        }
+        RpcKind::DiscoveryNames => discovery_names::handle(frame, services_map).await.map(Some),
         // 服务下线
-        RpcKind::Deregistry => {
-            let deregistry_request = deregistry::handle(&params.json, services_map).await;
-            // 同样的这里首先也需要发送响应此次客户端的事件
-            params
-                .unicast(ServiceDeregistryResp { success: true })
-                .await;
-            // 然后需要主动通知客户端更新缓存（删除这个服务）
-            params.publisher(deregistry_request);
-        }
+        RpcKind::Deregistry => deregistry::handle(frame, &cluster).await.map(Some),
         // 服务检测
-        RpcKind::ServiceCheck => {
-            let handle_event = service_check::handle(&params.json, services_map).await;
-            params.unicast(handle_event).await;
-        }
+        RpcKind::ServiceCheck => service_check::handle(frame, services_map).await.map(Some),
         // 心跳检测请求
         RpcKind::Heartbeat => {
-            heartbeat::handle(&params.json, services_heartbeat_map, services_map).await;
+            heartbeat::handle(frame, services_heartbeat_map, services_map, &cluster)
+                .await
+                .map(Some)
+        }
+        // 其它节点复制过来的数据变更，只需在本地应用并通知本节点的客户端
+        RpcKind::Replicate => replicate::handle(frame, &cluster).await.map(|_| None),
+        // 其它节点启动时拉取全量数据
+        RpcKind::Snapshot => {
+            snapshot::handle(frame, services_map, services_heartbeat_map)
+                .await
+                .map(Some)
+        }
+        // 协商协议版本
+        RpcKind::Handshake => handshake::handle(frame, &params.subscriptions, &cluster)
+            .await
+            .map(Some),
+        // 增量推送出现缺口时，获取某服务全部的实例及其 revision
+        RpcKind::Resync => resync::handle(frame, &cluster).await.map(Some),
+        // 断线重连后获取断开期间错过的增量变更
+        RpcKind::Watch => watch::handle(frame, &cluster, &params.subscriptions)
+            .await
+            .map(Some),
+        // 订阅 / 取消订阅服务变更的推送
+        RpcKind::Subscribe => subscribe::handle(frame, &params.subscriptions).await.map(Some),
+        RpcKind::Unsubscribe => unsubscribe::handle(frame, &params.subscriptions)
+            .await
+            .map(Some),
+        // Raft 节点间的消息
+        RpcKind::Raft => raft::handle(frame, &cluster).await.map(|_| None),
+        // gossip 节点间的消息
+        RpcKind::Gossip => gossip::handle(frame, &cluster).await.map(|_| None),
+        // 其他情况,都是server端主动推送的请求
+        RpcKind::HeartbeatTimeout
+        | RpcKind::AddService
+        | RpcKind::RemoveService
+        | RpcKind::ServiceDelta
+        | RpcKind::ResyncRequired
+        | RpcKind::InstanceStatus
+        | RpcKind::Error => Err(ConnorError::Unsupported(frame.rpc_kind.to_string())),
+    };
+    match handle_result {
+        Ok(Some(handle_event)) => params.unicast(handle_event).await,
+        Ok(None) => {}
+        Err(error) => {
+            warn!("Handle [{:?}] failed, err: [{}]", frame.rpc_kind, error);
+            let rpc_kind = Some(frame.rpc_kind.clone());
             params
-                .unicast(InboundHandleSingleEvent::HeartbeatResp { success: true })
+                .unicast(InboundHandleSingleEvent::ErrorResp { error, rpc_kind })
                 .await;
         }
-        // 其他情况,都是server端主动推送的请求
-        RpcKind::HeartbeatTimeout => {}
-        RpcKind::AddService => {}
-        RpcKind::RemoveService => {}
     }
 }
+
+/// 不经过 TCP 连接处理一个请求（HTTP / gRPC 接口），返回单播的响应事件；
+/// 这类请求没有推送，订阅不会生效
+pub async fn inbound_call(
+    frame: Frame,
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+    cluster: Cluster,
+) -> Option<InboundHandleSingleEvent> {
+    let (sender, mut receiver) = mpsc::channel::<(u64, InboundHandleSingleEvent)>(1);
+    inbound_handle(
+        InboundParams::new(frame, sender, Subscriptions::default()),
+        services_map,
+        services_heartbeat_map,
+        cluster,
+    )
+    .await;
+    receiver.recv().await.map(|(_, event)| event)
+}
@@ -1,34 +1,39 @@
 //!  服务下线
 
-use crate::models::request::DeregistryRequest;
-use crate::models::{InboundHandleBroadcastEvent, RpcCodec};
+use crate::custom_error::ConnorError;
+use crate::models::request::{DeregistryRequest, ReplicateOp};
+use crate::models::{InboundHandleBroadcastEvent, InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
 use crate::server_bootstrap::ServersMap;
 use tracing::info;
 
-pub async fn handle(json: &str, map: ServersMap) -> InboundHandleBroadcastEvent {
-    let deregistry_request = DeregistryRequest::from_json(json);
-    let service_name = &deregistry_request.service_name;
+pub async fn handle(frame: &Frame, cluster: &Cluster) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let deregistry_request = DeregistryRequest::from_frame(frame)?;
     info!("inbound data [ {:?} ]", &deregistry_request);
-    {
-        let mut map = map.write();
-        if let Some(services) = map.get_mut(service_name) {
-            *services = services
-                .iter()
-                .filter(|&service| service.id.ne(&deregistry_request.service_id))
-                .cloned()
-                .collect();
-        }
+    let success = cluster
+        .submit(ReplicateOp::Deregistry(*deregistry_request))
+        .await;
+    Ok(InboundHandleSingleEvent::ServiceDeregistryResp { success })
+}
+
+/// 删除下线的服务
+///
+/// 返回更新客户端缓存的事件
+pub fn remove(deregistry_request: &DeregistryRequest, map: &ServersMap) -> InboundHandleBroadcastEvent {
+    let service_name = &deregistry_request.service_name;
+    let mut map = map.write();
+    if let Some(services) = map.get_mut(service_name) {
+        services.retain(|service| service.id.ne(&deregistry_request.service_id));
     }
-    {
-        let map = map.read();
-        InboundHandleBroadcastEvent::RemoveServiceResp {
-            service_name: service_name.clone(),
-            service_list: match map.get(service_name) {
-                None => {
-                    vec![]
-                }
-                Some(list) => list.clone(),
-            },
-        }
+    InboundHandleBroadcastEvent::RemoveServiceResp {
+        service_name: service_name.clone(),
+        service_list: match map.get(service_name) {
+            None => {
+                vec![]
+            }
+            Some(list) => list.clone(),
+        },
+        deltas: vec![],
     }
 }
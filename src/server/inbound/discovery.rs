@@ -1,12 +1,14 @@
 //! 服务发现：根据service-name 获取所有的service
 
+use crate::custom_error::ConnorError;
 use crate::models::request::DiscoveryRequest;
 use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
 use crate::server_bootstrap::ServersMap;
 use tracing::info;
 
-pub async fn handle(json: &str, map: ServersMap) -> InboundHandleSingleEvent {
-    let discovery_req = DiscoveryRequest::from_json(json);
+pub async fn handle(frame: &Frame, map: ServersMap) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let discovery_req = DiscoveryRequest::from_frame(frame)?;
     info!("inbound data [ {:?} ]", &discovery_req);
     let mut services = None;
     {
@@ -16,8 +18,8 @@ pub async fn handle(json: &str, map: ServersMap) -> InboundHandleSingleEvent {
         }
     }
     // 返回服务注册的事件
-    InboundHandleSingleEvent::ServiceDiscoveryResp {
+    Ok(InboundHandleSingleEvent::ServiceDiscoveryResp {
         service_name: discovery_req.service_name.clone(),
         services,
-    }
+    })
 }
@@ -1,12 +1,14 @@
 //! 获取所有的service-names
 
+use crate::custom_error::ConnorError;
 use crate::models::request::DiscoveryServiceNamesRequest;
 use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
 use crate::server_bootstrap::ServersMap;
 use tracing::info;
 
-pub async fn handle(json: &str, map: ServersMap) -> InboundHandleSingleEvent {
-    let service_names_request = DiscoveryServiceNamesRequest::from_json(json);
+pub async fn handle(frame: &Frame, map: ServersMap) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let service_names_request = DiscoveryServiceNamesRequest::from_frame(frame)?;
     info!("inbound data [ {:?} ]", &service_names_request);
     let service_names;
     {
@@ -14,5 +16,5 @@ pub async fn handle(json: &str, map: ServersMap) -> InboundHandleSingleEvent {
         service_names = map.keys().cloned().collect();
     }
 
-    InboundHandleSingleEvent::ServiceNamesResp { service_names }
+    Ok(InboundHandleSingleEvent::ServiceNamesResp { service_names })
 }
@@ -0,0 +1,15 @@
+//! gossip 节点间的消息
+
+use crate::custom_error::ConnorError;
+use crate::models::RpcCodec;
+use crate::models::request::GossipMessage;
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
+use tracing::debug;
+
+pub async fn handle(frame: &Frame, cluster: &Cluster) -> Result<(), ConnorError> {
+    let gossip_message = GossipMessage::from_frame(frame)?;
+    debug!("inbound data [ {:?} ]", &gossip_message);
+    cluster.gossip(*gossip_message);
+    Ok(())
+}
@@ -0,0 +1,31 @@
+//! 协商协议版本
+
+use crate::custom_error::ConnorError;
+use crate::models::request::HandshakeRequest;
+use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::{self, Codec, Frame};
+use crate::server::cluster::Cluster;
+use crate::server::subscription::Subscriptions;
+use tracing::info;
+
+/// 返回双方都支持的协议版本和序列化格式，连接之后按照协商的结果响应；
+/// 客户端支持增量推送时，之后的服务变更推送增量；同时返回事件历史的当前位置，用于断线重连后获取错过的变更
+pub async fn handle(
+    frame: &Frame,
+    subscriptions: &Subscriptions,
+    cluster: &Cluster,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let handshake_request = HandshakeRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &handshake_request);
+    let version = protocol::negotiate(handshake_request.version)?;
+    let codec = Codec::negotiate(&handshake_request.codecs);
+    subscriptions.set_delta(handshake_request.delta);
+    let (epoch, revision) = cluster.store().position();
+    Ok(InboundHandleSingleEvent::HandshakeResp {
+        version,
+        codec,
+        delta: handshake_request.delta,
+        epoch,
+        revision,
+    })
+}
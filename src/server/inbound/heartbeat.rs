@@ -1,46 +1,130 @@
 //! 心跳检测
 
-use crate::models::request::HeartbeatRequest;
-use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::custom_error::ConnorError;
+use crate::models::request::{HeartbeatRequest, ReplicateOp};
+use crate::models::{InboundHandleSingleEvent, InstanceStatus, RpcCodec};
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
 use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
-use std::time::SystemTime;
-use tracing::info;
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use std::collections::HashSet;
+use std::time::{Duration, SystemTime};
+use tokio::time::sleep;
+use tracing::{info, warn};
 
-/// 更新 ServersHeartbeatMap 数据
-///
-/// 判断servers_map中是否存在该实例，如果不存在，表明是之前心跳超时被删除的实例，需要通知客户端重新注册实例
+/// 恢复为 UP 提交失败时重试的间隔
+const RECOVER_RETRY: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// 正在恢复为 UP 的实例，同一个实例只保留一个重试任务
+    static ref RECOVERING: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+}
+
+/// 处理客户端的心跳请求
 ///
-/// 无返回值
-pub async fn handle(json: &str, services_heartbeat_map: ServersHeartbeatMap, services_map: ServersMap) -> InboundHandleSingleEvent {
-    let heartbeat_req = HeartbeatRequest::from_json(json);
-    let service_id = &heartbeat_req.service_id;
+/// 实例不存在时，表明是之前心跳超时被删除的实例，响应失败，需要客户端重新注册实例
+pub async fn handle(
+    frame: &Frame,
+    services_heartbeat_map: ServersHeartbeatMap,
+    services_map: ServersMap,
+    cluster: &Cluster,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let heartbeat_req = HeartbeatRequest::from_frame(frame)?;
     info!("inbound data [ {:?} ]", &heartbeat_req);
+    let service_id = &heartbeat_req.service_id;
+    let success = renew(service_id, &services_heartbeat_map, &services_map, cluster);
+    if !success {
+        warn!("heartbeat from unknown instance [{}], need to reregistry", service_id);
+    }
+    Ok(InboundHandleSingleEvent::HeartbeatResp { success })
+}
+
+/// 实例续约：更新 ServersHeartbeatMap 数据并通知其它节点，客户端的心跳和服务端主动探测成功时调用
+///
+/// 实例不存在时返回 false；存在但是因为错过心跳不是 UP 状态时，在后台通过集群提交恢复为 UP，
+/// 不等待提交的结果，raft 模式下没有 leader 时心跳的响应也不会被阻塞
+pub fn renew(
+    service_id: &str,
+    services_heartbeat_map: &ServersHeartbeatMap,
+    services_map: &ServersMap,
+    cluster: &Cluster,
+) -> bool {
+    let status = services_map
+        .read()
+        .values()
+        .flatten()
+        .find(|service| service.id.eq(service_id))
+        .map(|service| service.status);
+    let status = match status {
+        Some(status) => status,
+        None => return false,
+    };
     {
         let mut write_guard = services_heartbeat_map.write();
-        match write_guard.get_mut(service_id) {
-            None => {
-                // 不存在则插入
-                write_guard.insert(service_id.clone(), SystemTime::now());
-            }
-            Some(time) => {
-                // 存在则更新时间
-                *time = SystemTime::now();
-            }
-        }
+        write_guard.insert(service_id.to_string(), SystemTime::now());
         info!(
             "concurrent services_heartbeat_map [ {:?} ]",
             write_guard.keys()
         );
     }
+    cluster.heartbeat(vec![service_id.to_string()]);
 
-    {
-        let read_guard = services_map.read();
-        let flag = read_guard.values()
+    if status != InstanceStatus::Up && RECOVERING.lock().insert(service_id.to_string()) {
+        info!("instance [{}] is {:?}, recover to UP", service_id, status);
+        tokio::spawn(recover(
+            service_id.to_string(),
+            services_map.clone(),
+            cluster.clone(),
+        ));
+    }
+    true
+}
+
+/// 通过集群提交将实例恢复为 UP，提交失败时记录日志并间隔一段时间重试，直到实例恢复为 UP 或者已经被移除
+async fn recover(service_id: String, services_map: ServersMap, cluster: Cluster) {
+    loop {
+        let op = ReplicateOp::InstanceStatus {
+            service_ids: vec![service_id.clone()],
+            status: InstanceStatus::Up,
+        };
+        if cluster.submit(op).await {
+            break;
+        }
+        warn!(
+            "recover instance [{}] to UP failed, retry after {:?}",
+            service_id, RECOVER_RETRY
+        );
+        sleep(RECOVER_RETRY).await;
+        let status = services_map
+            .read()
+            .values()
             .flatten()
-            .find(|service| { service.id.eq(service_id) })
-            .map_or(false, |_| true);
-        InboundHandleSingleEvent::HeartbeatResp {success: flag}
+            .find(|service| service.id.eq(&service_id))
+            .map(|service| service.status);
+        if !matches!(status, Some(status) if status != InstanceStatus::Up) {
+            break;
+        }
     }
+    RECOVERING.lock().remove(&service_id);
+}
+
+/// 更新实例的心跳状态
+pub fn set_status(service_ids: &[String], status: InstanceStatus, services_map: &ServersMap) {
+    let mut write_guard = services_map.write();
+    write_guard
+        .values_mut()
+        .flatten()
+        .filter(|service| service_ids.contains(&service.id))
+        .for_each(|service| service.status = status);
+}
+
+/// 从 servers_map 中移除心跳超时的实例
+pub fn remove_timeout(timeout_instance_ids: &[String], services_map: &ServersMap) {
+    let mut write_guard = services_map.write();
+    write_guard.iter_mut().for_each(|(_, services)| {
+        services.retain(|service| !timeout_instance_ids.contains(&service.id));
+    });
 }
 
 #[cfg(test)]
@@ -0,0 +1,15 @@
+//! Raft 节点间的消息
+
+use crate::custom_error::ConnorError;
+use crate::models::RpcCodec;
+use crate::models::request::RaftMessage;
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
+use tracing::debug;
+
+pub async fn handle(frame: &Frame, cluster: &Cluster) -> Result<(), ConnorError> {
+    let raft_message = RaftMessage::from_frame(frame)?;
+    debug!("inbound data [ {:?} ]", &raft_message);
+    cluster.step(*raft_message).await;
+    Ok(())
+}
@@ -1,31 +1,69 @@
 //! 服务注册
 
-use crate::models::request::RegistryRequest;
-use crate::models::{InboundHandleBroadcastEvent, RpcCodec};
+use crate::config::SERVER_CONFIG;
+use crate::custom_error::ConnorError;
+use crate::models::request::{RegistryRequest, ReplicateOp};
+use crate::models::{
+    HealthCheck, InboundHandleBroadcastEvent, InboundHandleSingleEvent, InstanceStatus, NewService,
+    RpcCodec,
+};
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
 use crate::server_bootstrap::ServersMap;
 use tracing::info;
 
 /// 请求处理
 ///
-/// 返回此次注册的服务结构体
-pub async fn handle(json: &str, map: ServersMap) -> InboundHandleBroadcastEvent {
-    let registry_req = RegistryRequest::from_json(json);
+/// 实例ID已经属于其它服务时拒绝注册，否则通过集群提交此次注册，返回注册结果的响应事件；
+/// 各节点应用注册时记录实例的第一次心跳，注册之后没有发送过心跳的实例同样会超时被剔除
+pub async fn handle(frame: &Frame, cluster: &Cluster) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let mut registry_req = RegistryRequest::from_frame(frame)?;
+    // 状态由服务端根据心跳维护，注册的实例总是 UP
+    registry_req.service.status = InstanceStatus::Up;
+    let (ttl, heartbeat_interval) = SERVER_CONFIG
+        .heartbeat_policy(registry_req.service.ttl, registry_req.service.heartbeat_interval);
+    registry_req.service.ttl = Some(ttl);
+    registry_req.service.heartbeat_interval = Some(heartbeat_interval);
+    let script_check = matches!(registry_req.service.health_check, Some(HealthCheck::Script { .. }));
+    if script_check && !SERVER_CONFIG.script_health_check {
+        return Err(ConnorError::ScriptCheckDisabled);
+    }
     info!("inbound data [ {:?} ]", &registry_req);
-    // 存储注册的服务
     let service = &registry_req.service;
-    {
-        let mut servers = map.write();
-        match servers.get_mut(&service.name) {
-            Some(list) => {
-                list.push(service.clone());
-            }
-            None => {
-                servers.insert(service.name.clone(), vec![service.clone()]);
-            }
+    if let Some(service_name) = cluster.store().service_name_of(&service.id) {
+        if service_name != service.name {
+            return Err(ConnorError::IdConflict {
+                service_id: service.id.clone(),
+                service_name,
+            });
         }
     }
+    let success = cluster.submit(ReplicateOp::Registry(*registry_req)).await;
+    Ok(InboundHandleSingleEvent::ServiceRegistryResp {
+        success,
+        ttl,
+        heartbeat_interval,
+    })
+}
+
+/// 存储注册的服务：以实例ID为准，已经存在时更新实例的信息（并从其它服务中移除），重复注册不会产生重复的实例
+///
+/// 返回更新客户端缓存的事件
+pub fn store(service: &NewService, map: &ServersMap) -> InboundHandleBroadcastEvent {
+    let mut servers = map.write();
+    for (service_name, list) in servers.iter_mut() {
+        if service_name != &service.name {
+            list.retain(|exist| exist.id != service.id);
+        }
+    }
+    let list = servers.entry(service.name.clone()).or_default();
+    match list.iter_mut().find(|exist| exist.id == service.id) {
+        Some(exist) => *exist = service.clone(),
+        None => list.push(service.clone()),
+    }
     InboundHandleBroadcastEvent::AddServiceResp {
         service_name: service.name.clone(),
-        service_list: map.read().get(&service.name).unwrap().clone(),
+        service_list: list.clone(),
+        deltas: vec![],
     }
 }
@@ -0,0 +1,18 @@
+//! 集群节点间的数据复制
+
+use crate::custom_error::ConnorError;
+use crate::models::RpcCodec;
+use crate::models::request::ReplicateRequest;
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
+use tracing::info;
+
+/// 在本地应用其它节点复制过来的数据变更
+///
+/// 这里不会再次复制给其它节点，避免集群间循环转发
+pub async fn handle(frame: &Frame, cluster: &Cluster) -> Result<(), ConnorError> {
+    let replicate_request = ReplicateRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &replicate_request);
+    cluster.store().apply(replicate_request.op);
+    Ok(())
+}
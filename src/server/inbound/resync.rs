@@ -0,0 +1,23 @@
+//! 重新同步某服务的实例
+
+use crate::custom_error::ConnorError;
+use crate::models::request::ResyncRequest;
+use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
+use tracing::info;
+
+/// 返回某服务全部的实例及其当前的 revision，之后的增量推送从该 revision 继续
+pub async fn handle(
+    frame: &Frame,
+    cluster: &Cluster,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let resync_request = ResyncRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &resync_request);
+    let (revision, services) = cluster.store().resync(&resync_request.service_name);
+    Ok(InboundHandleSingleEvent::ResyncResp {
+        service_name: resync_request.service_name,
+        revision,
+        services,
+    })
+}
@@ -1,25 +1,26 @@
 //! 服务检测
 
+use crate::custom_error::ConnorError;
 use crate::models::request::ServiceCheckRequest;
-use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::models::{InboundHandleSingleEvent, InstanceStatus, RpcCodec};
+use crate::protocol::Frame;
 use crate::server_bootstrap::ServersMap;
 use tracing::info;
 
-pub async fn handle(json: &str, map: ServersMap) -> InboundHandleSingleEvent {
-    let check_request = ServiceCheckRequest::from_json(json);
+/// 查询实例是否存在及其状态，状态由心跳或者服务端的主动探测维护
+pub async fn handle(frame: &Frame, map: ServersMap) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let check_request = ServiceCheckRequest::from_frame(frame)?;
     info!("inbound data [ {:?} ]", &check_request);
-    let service_id: String;
+    let (service_id, status): (String, Option<InstanceStatus>);
     {
         let map = map.read();
-        service_id = map
+        (service_id, status) = map
             .values()
-            .flat_map(|ele| {
-                ele.iter()
-                    .map(|ele| ele.id.clone())
-                    .filter(|ele| ele.eq(&check_request.service_id))
-            })
-            .collect();
+            .flatten()
+            .find(|ele| ele.id.eq(&check_request.service_id))
+            .map(|ele| (ele.id.clone(), Some(ele.status)))
+            .unwrap_or_default();
     }
-    info!("{}", &service_id);
-    InboundHandleSingleEvent::ServiceCheckResp { service_id }
+    info!("{} {:?}", &service_id, status);
+    Ok(InboundHandleSingleEvent::ServiceCheckResp { service_id, status })
 }
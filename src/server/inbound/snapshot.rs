@@ -0,0 +1,35 @@
+//! 集群节点间的全量数据同步
+
+use crate::custom_error::ConnorError;
+use crate::models::request::SnapshotRequest;
+use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
+use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
+use std::collections::HashMap;
+use tracing::info;
+
+/// 返回当前节点的全部注册数据和心跳数据
+pub async fn handle(
+    frame: &Frame,
+    services_map: ServersMap,
+    services_heartbeat_map: ServersHeartbeatMap,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let snapshot_request = SnapshotRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &snapshot_request);
+    let services = services_map.read().clone();
+    let heartbeats = services_heartbeat_map.read().clone();
+    Ok(InboundHandleSingleEvent::SnapshotResp {
+        ready: true,
+        services,
+        heartbeats,
+    })
+}
+
+/// 本节点还在同步全量数据时的响应
+pub fn not_ready() -> InboundHandleSingleEvent {
+    InboundHandleSingleEvent::SnapshotResp {
+        ready: false,
+        services: HashMap::new(),
+        heartbeats: HashMap::new(),
+    }
+}
@@ -0,0 +1,19 @@
+//! 订阅服务变更的推送
+
+use crate::custom_error::ConnorError;
+use crate::models::request::SubscribeRequest;
+use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
+use crate::server::subscription::Subscriptions;
+use tracing::info;
+
+/// 添加连接的订阅，之后只推送订阅的服务
+pub async fn handle(
+    frame: &Frame,
+    subscriptions: &Subscriptions,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let subscribe_request = SubscribeRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &subscribe_request);
+    let service_names = subscriptions.subscribe(subscribe_request.service_names);
+    Ok(InboundHandleSingleEvent::SubscribeResp { service_names })
+}
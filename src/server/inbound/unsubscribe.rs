@@ -0,0 +1,19 @@
+//! 取消订阅服务变更的推送
+
+use crate::custom_error::ConnorError;
+use crate::models::request::UnsubscribeRequest;
+use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
+use crate::server::subscription::Subscriptions;
+use tracing::info;
+
+/// 移除连接的订阅
+pub async fn handle(
+    frame: &Frame,
+    subscriptions: &Subscriptions,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let unsubscribe_request = UnsubscribeRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &unsubscribe_request);
+    let service_names = subscriptions.unsubscribe(&unsubscribe_request.service_names);
+    Ok(InboundHandleSingleEvent::UnsubscribeResp { service_names })
+}
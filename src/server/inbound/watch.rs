@@ -0,0 +1,33 @@
+//! 获取断线期间错过的增量变更
+
+use crate::custom_error::ConnorError;
+use crate::models::request::WatchRequest;
+use crate::models::{InboundHandleSingleEvent, RpcCodec};
+use crate::protocol::Frame;
+use crate::server::cluster::Cluster;
+use crate::server::subscription::Subscriptions;
+use tracing::info;
+
+/// 返回事件历史中 since_revision 之后、连接订阅的服务的增量变更
+pub async fn handle(
+    frame: &Frame,
+    cluster: &Cluster,
+    subscriptions: &Subscriptions,
+) -> Result<InboundHandleSingleEvent, ConnorError> {
+    let watch_request = WatchRequest::from_frame(frame)?;
+    info!("inbound data [ {:?} ]", &watch_request);
+    let ((epoch, revision), deltas) = cluster
+        .store()
+        .since(watch_request.epoch, watch_request.since_revision);
+    let deltas = deltas.map(|deltas| {
+        deltas
+            .into_iter()
+            .filter(|delta| subscriptions.matches(&delta.service_name))
+            .collect()
+    });
+    Ok(InboundHandleSingleEvent::WatchResp {
+        epoch,
+        revision,
+        deltas,
+    })
+}
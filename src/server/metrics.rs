@@ -0,0 +1,167 @@
+//! 服务端运行指标
+//!
+//! 通过 HTTP 接口的 /metrics 以 Prometheus 文本格式暴露
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// 全局指标
+pub static METRICS: Metrics = Metrics::new();
+
+/// 广播的订阅者类型
+#[derive(Debug, Clone, Copy)]
+pub enum Subscriber {
+    /// TCP 连接
+    Tcp,
+    /// gRPC Watch
+    Grpc,
+}
+
+impl Subscriber {
+    const ALL: [Subscriber; 2] = [Subscriber::Tcp, Subscriber::Grpc];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Subscriber::Tcp => "tcp",
+            Subscriber::Grpc => "grpc",
+        }
+    }
+}
+
+pub struct Metrics {
+    /// 广播通道的容量
+    broadcast_capacity: AtomicU64,
+    /// 订阅者落后于广播通道（丢失推送）的次数
+    broadcast_lags: [AtomicU64; 2],
+    /// 订阅者因落后而丢失的事件个数
+    broadcast_lagged_events: [AtomicU64; 2],
+    /// 是否处于自我保护
+    self_preservation: AtomicU64,
+    /// 心跳超时、因自我保护暂停剔除的实例个数
+    held_evictions: AtomicU64,
+    /// 心跳超时被剔除的实例个数
+    heartbeat_evictions: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            broadcast_capacity: AtomicU64::new(0),
+            broadcast_lags: [AtomicU64::new(0), AtomicU64::new(0)],
+            broadcast_lagged_events: [AtomicU64::new(0), AtomicU64::new(0)],
+            self_preservation: AtomicU64::new(0),
+            held_evictions: AtomicU64::new(0),
+            heartbeat_evictions: AtomicU64::new(0),
+        }
+    }
+
+    pub fn set_broadcast_capacity(&self, capacity: usize) {
+        self.broadcast_capacity
+            .store(capacity as u64, Ordering::Relaxed);
+    }
+
+    /// 记录一次订阅者落后，lagged 为丢失的事件个数
+    pub fn broadcast_lagged(&self, subscriber: Subscriber, lagged: u64) {
+        self.broadcast_lags[subscriber as usize].fetch_add(1, Ordering::Relaxed);
+        self.broadcast_lagged_events[subscriber as usize].fetch_add(lagged, Ordering::Relaxed);
+    }
+
+    /// 记录自我保护的状态，held 为暂停剔除的实例个数
+    pub fn set_self_preservation(&self, active: bool, held: usize) {
+        self.self_preservation
+            .store(active as u64, Ordering::Relaxed);
+        self.held_evictions.store(held as u64, Ordering::Relaxed);
+    }
+
+    /// 记录心跳超时被剔除的实例个数
+    pub fn heartbeat_evicted(&self, evicted: usize) {
+        self.heartbeat_evictions
+            .fetch_add(evicted as u64, Ordering::Relaxed);
+    }
+
+    /// Prometheus 文本格式
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "# HELP connor_broadcast_capacity Capacity of the service change broadcast channel."
+        );
+        let _ = writeln!(out, "# TYPE connor_broadcast_capacity gauge");
+        let _ = writeln!(
+            out,
+            "connor_broadcast_capacity {}",
+            self.broadcast_capacity.load(Ordering::Relaxed)
+        );
+        let counters = [
+            (
+                "connor_broadcast_lag_total",
+                "Times a subscriber fell behind the broadcast channel and had to resync.",
+                &self.broadcast_lags,
+            ),
+            (
+                "connor_broadcast_lagged_events_total",
+                "Events dropped for subscribers that fell behind the broadcast channel.",
+                &self.broadcast_lagged_events,
+            ),
+        ];
+        for (name, help, values) in counters {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} counter", name);
+            for subscriber in Subscriber::ALL {
+                let value = values[subscriber as usize].load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "{}{{subscriber=\"{}\"}} {}",
+                    name,
+                    subscriber.label(),
+                    value
+                );
+            }
+        }
+        let heartbeats = [
+            (
+                "connor_self_preservation",
+                "Whether eviction is paused because too many heartbeats expired at once.",
+                "gauge",
+                &self.self_preservation,
+            ),
+            (
+                "connor_held_evictions",
+                "Instances with expired heartbeats whose eviction is held by self preservation.",
+                "gauge",
+                &self.held_evictions,
+            ),
+            (
+                "connor_heartbeat_evictions_total",
+                "Instances evicted because their heartbeat expired.",
+                "counter",
+                &self.heartbeat_evictions,
+            ),
+        ];
+        for (name, help, kind, value) in heartbeats {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn render_lags() {
+        let metrics = Metrics::new();
+        metrics.set_broadcast_capacity(1024);
+        metrics.broadcast_lagged(Subscriber::Tcp, 10);
+        metrics.broadcast_lagged(Subscriber::Tcp, 5);
+        metrics.broadcast_lagged(Subscriber::Grpc, 1);
+        let rendered = metrics.render();
+        assert!(rendered.contains("connor_broadcast_capacity 1024\n"));
+        assert!(rendered.contains("connor_broadcast_lag_total{subscriber=\"tcp\"} 2\n"));
+        assert!(rendered.contains("connor_broadcast_lag_total{subscriber=\"grpc\"} 1\n"));
+        assert!(rendered.contains("connor_broadcast_lagged_events_total{subscriber=\"tcp\"} 15\n"));
+    }
+}
@@ -3,24 +3,58 @@
 use crate::models::response::{
     AddServiceResponse, DeregistryResponse, DiscoveryResponse, DiscoveryServiceNamesResponse,
     HeartbeatResponse, HeartbeatTimeoutResponse, RegistryResponse, RemoveServiceResponse,
-    ServiceCheckResponse,
+    ErrorResponse, HandshakeResponse, InstanceStatusResponse, ResyncRequiredResponse, ResyncResponse,
+    ServiceCheckResponse, SnapshotResponse,
+    SubscribeResponse, UnsubscribeResponse, WatchResponse,
 };
-use crate::models::{InboundHandleBroadcastEvent, InboundHandleSingleEvent, RpcCodec, TcpWriter};
-use bytes::Bytes;
-use futures::SinkExt;
+use crate::custom_error::ConnorError;
+use crate::models::{InboundHandleBroadcastEvent, InboundHandleSingleEvent, RpcCodec, RpcKind};
+use crate::protocol::{Codec, Frame, FrameWriter, Protocol, FLAG_PUSH};
+use crate::server::subscription::Subscriptions;
+use serde::Serialize;
+use serde_json::json;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
-/// 根据inbound handle 发送的消息进行响应
-pub async fn outbound_handle_resp(data: InboundHandleSingleEvent, writer: Arc<Mutex<TcpWriter>>) {
+/// 根据inbound handle 发送的消息进行响应，响应带回请求的 request id
+pub async fn outbound_handle_resp(
+    request_id: u64,
+    data: InboundHandleSingleEvent,
+    writer: Arc<Mutex<FrameWriter>>,
+) {
     let mut writer = writer.lock().await;
+    let frame = match data {
+        // 握手响应本身使用 json，之后的数据使用协商的格式
+        InboundHandleSingleEvent::HandshakeResp { version, codec, .. } => {
+            let frame = response_frame(data, Codec::Json);
+            writer.set_protocol(Protocol::Binary(version));
+            writer.set_codec(codec);
+            frame
+        }
+        data => response_frame(data, writer.codec()),
+    };
+    response(&mut writer, frame.with_request_id(request_id)).await;
+}
+
+/// 将inbound handle 发送的消息转换为响应帧
+pub(crate) fn response_frame(data: InboundHandleSingleEvent, codec: Codec) -> Frame {
     match data {
         // 服务注册
-        InboundHandleSingleEvent::ServiceRegistryResp { success } => {
+        InboundHandleSingleEvent::ServiceRegistryResp {
+            success,
+            ttl,
+            heartbeat_interval,
+        } => {
             info!("Listener ServiceRegistry event");
-            let registry_response = RegistryResponse { success };
-            response(&mut writer, registry_response.to_json()).await;
+            encode(
+                &RegistryResponse {
+                    success,
+                    ttl: Some(ttl),
+                    heartbeat_interval: Some(heartbeat_interval),
+                },
+                codec,
+            )
         }
         // 服务发现
         InboundHandleSingleEvent::ServiceDiscoveryResp {
@@ -28,26 +62,22 @@ pub async fn outbound_handle_resp(data: InboundHandleSingleEvent, writer: Arc<Mu
             services,
         } => {
             info!("Listener ServiceDiscovery event");
-            let discovery_resp = DiscoveryResponse::new(&service_name, services);
-            response(&mut writer, discovery_resp.to_json()).await;
+            encode(&DiscoveryResponse::new(&service_name, services), codec)
         }
         // 获取所有的 service name list
         InboundHandleSingleEvent::ServiceNamesResp { service_names } => {
             info!("Listener ServiceNames event");
-            let names_response = DiscoveryServiceNamesResponse::new(service_names);
-            response(&mut writer, names_response.to_json()).await;
+            encode(&DiscoveryServiceNamesResponse::new(service_names), codec)
         }
         // service 状态检测
-        InboundHandleSingleEvent::ServiceCheckResp { service_id } => {
+        InboundHandleSingleEvent::ServiceCheckResp { service_id, status } => {
             info!("Listener ServiceCheck event");
-            let check_response = ServiceCheckResponse::new(&service_id);
-            response(&mut writer, check_response.to_json()).await;
+            encode(&ServiceCheckResponse::new(&service_id, status), codec)
         }
         // 服务下线
         InboundHandleSingleEvent::ServiceDeregistryResp { success } => {
             info!("Listener ServiceDeregistry event");
-            let dereg_response = DeregistryResponse { success };
-            response(&mut writer, dereg_response.to_json()).await;
+            encode(&DeregistryResponse { success }, codec)
         }
         // 服务心跳响应（对client 每次发送心跳请求的响应）
         InboundHandleSingleEvent::HeartbeatResp { success } => {
@@ -56,49 +86,270 @@ pub async fn outbound_handle_resp(data: InboundHandleSingleEvent, writer: Arc<Mu
             } else {
                 warn!("Listener Heartbeat event, and need to reregistry");
             }
-            let heartbeat_response = HeartbeatResponse { success };
-            response(&mut writer, heartbeat_response.to_json()).await;
+            encode(&HeartbeatResponse { success }, codec)
+        }
+        // 全量数据同步响应
+        InboundHandleSingleEvent::SnapshotResp {
+            ready,
+            services,
+            heartbeats,
+        } => {
+            info!("Listener Snapshot event");
+            let snapshot_response = SnapshotResponse {
+                ready,
+                services,
+                heartbeats,
+            };
+            encode(&snapshot_response, codec)
+        }
+        // 协商协议版本和序列化格式
+        InboundHandleSingleEvent::HandshakeResp {
+            version,
+            codec: negotiated,
+            delta,
+            epoch,
+            revision,
+        } => {
+            info!(
+                "Listener Handshake event, protocol version [{}], codec [{:?}], delta [{}]",
+                version, negotiated, delta
+            );
+            let handshake_response = HandshakeResponse {
+                version,
+                codec: negotiated,
+                delta,
+                epoch,
+                revision,
+            };
+            encode(&handshake_response, codec)
+        }
+        // 某服务全部的实例及其 revision
+        InboundHandleSingleEvent::ResyncResp {
+            service_name,
+            revision,
+            services,
+        } => {
+            info!("Listener Resync event [{}] revision [{}]", service_name, revision);
+            let resync_response = ResyncResponse {
+                service_name,
+                revision,
+                services,
+            };
+            encode(&resync_response, codec)
+        }
+        // 订阅 / 取消订阅
+        InboundHandleSingleEvent::SubscribeResp { service_names } => {
+            info!("Listener Subscribe event {:?}", service_names);
+            encode(&SubscribeResponse { service_names }, codec)
+        }
+        InboundHandleSingleEvent::UnsubscribeResp { service_names } => {
+            info!("Listener Unsubscribe event {:?}", service_names);
+            encode(&UnsubscribeResponse { service_names }, codec)
+        }
+        // 断线期间错过的增量变更
+        InboundHandleSingleEvent::WatchResp {
+            epoch,
+            revision,
+            deltas,
+        } => {
+            info!("Listener Watch event, epoch [{}] revision [{}]", epoch, revision);
+            let watch_response = WatchResponse {
+                epoch,
+                revision,
+                compacted: deltas.is_none(),
+                deltas: deltas.unwrap_or_default(),
+            };
+            encode(&watch_response, codec)
+        }
+        // 请求处理失败
+        InboundHandleSingleEvent::ErrorResp { error, rpc_kind } => {
+            warn!("Listener Error event [{}]", error);
+            error_frame(error, rpc_kind, codec)
         }
     }
 }
 
-/// 根据inbound handle 发送的消息进行广播响应
+/// 根据inbound handle 发送的消息进行广播响应，广播的帧带有推送标识，只推送连接订阅的服务；
+/// 支持增量推送的连接推送每个服务的增量变更，否则推送变更后的全部实例
 pub async fn outbound_handle_broad(
     data: InboundHandleBroadcastEvent,
-    writer: Arc<Mutex<TcpWriter>>,
+    writer: Arc<Mutex<FrameWriter>>,
+    subscriptions: &Subscriptions,
 ) {
+    if subscriptions.delta() {
+        let mut writer = writer.lock().await;
+        let codec = writer.codec();
+        for delta in data.deltas() {
+            if subscriptions.matches(&delta.service_name) {
+                info!("Listener ServiceDelta event [{}] revision [{}]", delta.service_name, delta.revision);
+                response(&mut writer, encode(delta, codec).with_flags(FLAG_PUSH)).await;
+            }
+        }
+        return;
+    }
+    if !subscriptions.accepts(&data) {
+        return;
+    }
     let mut writer = writer.lock().await;
+    let codec = writer.codec();
     match data {
         InboundHandleBroadcastEvent::AddServiceResp {
             service_name,
             service_list,
+            ..
         } => {
             info!("Listener AddService event");
             let add_service_response = AddServiceResponse::new(&service_name, service_list);
-            response(&mut writer, add_service_response.to_json()).await;
+            response(&mut writer, encode(&add_service_response, codec).with_flags(FLAG_PUSH)).await;
         }
         InboundHandleBroadcastEvent::RemoveServiceResp {
             service_name,
             service_list,
+            ..
         } => {
             info!("Listener RemoveService event");
             let remove_service_response = RemoveServiceResponse::new(&service_name, service_list);
-            response(&mut writer, remove_service_response.to_json()).await;
+            response(&mut writer, encode(&remove_service_response, codec).with_flags(FLAG_PUSH)).await;
         }
-        InboundHandleBroadcastEvent::HeartbeatTimeoutResp { service_ids } => {
+        InboundHandleBroadcastEvent::HeartbeatTimeoutResp { service_ids, .. } => {
             info!("Listener HeartbeatTimeout event");
             let heartbeat_timeout_response = HeartbeatTimeoutResponse::new(service_ids);
-            response(&mut writer, heartbeat_timeout_response.to_json()).await;
+            response(&mut writer, encode(&heartbeat_timeout_response, codec).with_flags(FLAG_PUSH)).await;
+        }
+        InboundHandleBroadcastEvent::InstanceStatusResp { service_ids, status, .. } => {
+            info!("Listener InstanceStatus event");
+            let instance_status_response = InstanceStatusResponse { service_ids, status };
+            response(&mut writer, encode(&instance_status_response, codec).with_flags(FLAG_PUSH)).await;
         }
     }
 }
 
-/// 响应客户端content
-async fn response(writer: &mut TcpWriter, content: String) {
-    if let Err(err) = writer
-        .send(Bytes::copy_from_slice(content.as_bytes()))
-        .await
-    {
+/// 连接的推送落后于广播，通知客户端重新同步
+pub async fn outbound_handle_lagged(lagged: u64, writer: Arc<Mutex<FrameWriter>>) {
+    let mut writer = writer.lock().await;
+    let codec = writer.codec();
+    let resync_required_response = ResyncRequiredResponse { lagged };
+    let frame = encode(&resync_required_response, codec).with_flags(FLAG_PUSH);
+    response(&mut writer, frame).await;
+}
+
+/// 将响应转换为传输的帧，序列化失败时转换为错误响应
+fn encode<T: RpcCodec + Serialize>(response: &T, codec: Codec) -> Frame {
+    response.to_frame(codec).unwrap_or_else(|error| {
+        error!("Encode {:?} response failed, err: [{}]", T::rpc_kind(), error);
+        error_frame(error, Some(T::rpc_kind()), codec)
+    })
+}
+
+/// 错误响应只包含错误码和字符串，序列化失败时直接拼接 json 消息体
+fn error_frame(error: ConnorError, rpc_kind: Option<RpcKind>, codec: Codec) -> Frame {
+    let response = ErrorResponse {
+        code: error.code(),
+        message: error.to_string(),
+        rpc_kind: rpc_kind.map(|rpc_kind| rpc_kind.to_string()),
+    };
+    response.to_frame(codec).unwrap_or_else(|_| {
+        let body = json!({
+            "code": response.code,
+            "message": response.message,
+            "rpc_kind": response.rpc_kind,
+        });
+        Frame::new(RpcKind::Error, body.to_string())
+    })
+}
+
+/// 按照连接的协议响应客户端
+async fn response(writer: &mut FrameWriter, frame: Frame) {
+    // 旧协议的客户端只读取一位数字的 kind，新增的类型会被错误解析，不发送；
+    // 错误响应不发送时客户端会一直等待，关闭连接让客户端读到连接结束
+    if !writer.accepts(&frame.rpc_kind) {
+        if frame.rpc_kind == RpcKind::Error {
+            warn!(
+                "Close legacy connection on error response {}",
+                String::from_utf8_lossy(&frame.body)
+            );
+            if let Err(err) = writer.close().await {
+                error!("close legacy connection error {:?}", err);
+            }
+            return;
+        }
+        debug!("Skip [{:?}] for legacy connection", frame.rpc_kind);
+        return;
+    }
+    if let Err(err) = writer.send(&frame).await {
         error!("response error {:?}", err);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::StreamExt;
+    use serde::Serializer;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::time::timeout;
+    use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+    #[derive(Debug)]
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    impl RpcCodec for Unserializable {
+        fn rpc_kind() -> RpcKind {
+            RpcKind::Discovery
+        }
+    }
+
+    #[test]
+    fn encode_failure_to_error_response() {
+        let frame = encode(&Unserializable, Codec::MessagePack);
+        assert_eq!(frame.rpc_kind, RpcKind::Error);
+        let response = ErrorResponse::from_frame(&frame).unwrap();
+        assert_eq!(response.code, 4);
+        assert_eq!(response.rpc_kind, Some(RpcKind::Discovery.to_string()));
+    }
+
+    /// 建立一对连接，返回服务端的写入端和客户端
+    async fn connect(
+        protocol: Protocol,
+    ) -> (Arc<Mutex<FrameWriter>>, Framed<TcpStream, LengthDelimitedCodec>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+        let (sink, _) = Framed::new(socket, LengthDelimitedCodec::new()).split();
+        let writer = Arc::new(Mutex::new(FrameWriter::new(sink, protocol, Codec::Json)));
+        (writer, Framed::new(client, LengthDelimitedCodec::new()))
+    }
+
+    fn unknown_kind() -> InboundHandleSingleEvent {
+        InboundHandleSingleEvent::ErrorResp {
+            error: ConnorError::UnknownKind("99".to_string()),
+            rpc_kind: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn error_response_for_binary_connection() {
+        let (writer, mut client) = connect(Protocol::CURRENT).await;
+        outbound_handle_resp(7, unknown_kind(), writer).await;
+        let data = timeout(Duration::from_secs(1), client.next()).await.unwrap();
+        let (_, frame) = Frame::decode(data.unwrap().unwrap().freeze()).unwrap();
+        assert_eq!(frame.rpc_kind, RpcKind::Error);
+        assert_eq!(frame.request_id, 7);
+    }
+
+    #[tokio::test]
+    async fn error_response_closes_legacy_connection() {
+        let (writer, mut client) = connect(Protocol::Legacy).await;
+        outbound_handle_resp(0, unknown_kind(), writer).await;
+        // 旧客户端无法解析错误响应，读到连接结束而不是一直等待
+        let data = timeout(Duration::from_secs(1), client.next()).await.unwrap();
+        assert!(data.is_none());
+    }
+}
@@ -0,0 +1,872 @@
+//! Raft 一致性模式
+//!
+//! 集群中只有 leader 可以追加日志，服务注册、下线和心跳超时剔除都会先写入复制日志，
+//! 多数实例确认之后才会提交并应用到 ServersMap；follower 收到的写操作会转发给 leader。
+//! 服务发现等读操作直接读取本地数据。
+//!
+//! 任期、投票信息和日志保存在 raft_data_dir 目录中，回复投票和追加日志的请求之前都会 fsync，
+//! 实例重启之后从快照和日志恢复。已经应用的日志超过 raft_snapshot_threshold 条时压缩为快照，
+//! follower 需要的日志已经被压缩时由 leader 发送快照。
+
+mod storage;
+
+use crate::models::request::{RaftBody, RaftEntry, RaftMessage, RaftSnapshot, ReplicateOp};
+use crate::models::RpcCodec;
+use crate::protocol::{Codec, Protocol};
+use crate::server::cluster::ServiceStore;
+use crate::PeerCluster;
+use anyhow::Result;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use storage::{HardState, RaftStorage, Recovered};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::{interval, timeout, Duration, Instant};
+use tracing::{error, info, warn};
+
+/// 定时检测选举超时和发送心跳的周期
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// leader 发送心跳的周期
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+/// 选举超时时间的范围（毫秒）
+const ELECTION_TIMEOUT_MS: std::ops::Range<u64> = 1500..3000;
+/// 单次追加日志的最大条数
+const MAX_APPEND_ENTRIES: usize = 256;
+/// 等待写操作提交的超时时间
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+const EVENT_CHANNEL_SIZE: usize = 1024;
+
+enum RaftEvent {
+    /// 其它节点发来的消息
+    Message(RaftMessage),
+    /// 本节点客户端的写操作
+    Propose(ReplicateOp, oneshot::Sender<bool>),
+}
+
+/// Raft 节点的句柄，所有状态都在单独的任务中维护
+#[derive(Clone)]
+pub struct RaftHandle {
+    sender: mpsc::Sender<RaftEvent>,
+}
+impl RaftHandle {
+    /// 从 data_dir 恢复持久化的状态并启动节点，已经应用的日志超过 snapshot_threshold 条时压缩为快照
+    pub fn start(
+        id: String,
+        peers: Vec<String>,
+        peer_cluster: PeerCluster,
+        store: ServiceStore,
+        data_dir: impl Into<PathBuf>,
+        snapshot_threshold: u64,
+    ) -> Result<Self> {
+        let (sender, receiver) = mpsc::channel::<RaftEvent>(EVENT_CHANNEL_SIZE);
+        let (storage, recovered) = RaftStorage::open(data_dir)?;
+        let node = RaftNode::new(
+            id,
+            peers,
+            peer_cluster,
+            store,
+            storage,
+            recovered,
+            snapshot_threshold,
+        );
+        tokio::spawn(node.run(receiver));
+        Ok(Self { sender })
+    }
+
+    /// 提交写操作，多数实例确认提交后返回 true，失败或超时返回 false
+    pub async fn propose(&self, op: ReplicateOp) -> bool {
+        let (sender, receiver) = oneshot::channel();
+        if self
+            .sender
+            .send(RaftEvent::Propose(op, sender))
+            .await
+            .is_err()
+        {
+            return false;
+        }
+        matches!(timeout(PROPOSE_TIMEOUT, receiver).await, Ok(Ok(true)))
+    }
+
+    /// 处理其它节点发来的消息
+    pub async fn step(&self, message: RaftMessage) {
+        if self.sender.send(RaftEvent::Message(message)).await.is_err() {
+            warn!("Raft node stopped, drop message");
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone, Copy)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// 等待提交的写操作
+enum Proposal {
+    /// 本节点客户端的写操作
+    Local(oneshot::Sender<bool>),
+    /// follower 转发过来的写操作
+    Forwarded { from: String, proposal_id: u64 },
+}
+
+struct RaftNode {
+    id: String,
+    peers: Vec<String>,
+    peer_cluster: PeerCluster,
+    store: ServiceStore,
+    storage: RaftStorage,
+    snapshot_threshold: u64,
+
+    role: Role,
+    current_term: u64,
+    voted_for: Option<String>,
+    leader_id: Option<String>,
+    /// 快照包含的最后一条日志的 index 和任期，没有快照时为 0
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// 快照之后的日志，log[0] 对应 index snapshot_index + 1
+    log: Vec<RaftEntry>,
+    commit_index: u64,
+    last_applied: u64,
+
+    election_deadline: Instant,
+    last_heartbeat: Instant,
+    votes: HashSet<String>,
+    next_index: HashMap<String, u64>,
+    match_index: HashMap<String, u64>,
+
+    /// leader 等待提交的写操作：<日志 index, (任期, 写操作)>
+    pending: HashMap<u64, (u64, Proposal)>,
+    /// follower 转发给 leader 等待结果的写操作
+    forwarded: HashMap<u64, oneshot::Sender<bool>>,
+    next_proposal_id: u64,
+}
+
+impl RaftNode {
+    fn new(
+        id: String,
+        peers: Vec<String>,
+        peer_cluster: PeerCluster,
+        store: ServiceStore,
+        storage: RaftStorage,
+        recovered: Recovered,
+        snapshot_threshold: u64,
+    ) -> Self {
+        let Recovered {
+            state,
+            snapshot,
+            entries,
+        } = recovered;
+        info!(
+            "raft restore term [{}], snapshot index [{}], [{}] log entries",
+            state.current_term,
+            snapshot.last_index,
+            entries.len()
+        );
+        let (snapshot_index, snapshot_term) = (snapshot.last_index, snapshot.last_term);
+        if snapshot_index > 0 {
+            store.install(snapshot.services);
+        }
+        let now = Instant::now();
+        let mut node = Self {
+            id,
+            peers,
+            peer_cluster,
+            store,
+            storage,
+            snapshot_threshold,
+            role: Role::Follower,
+            current_term: state.current_term,
+            voted_for: state.voted_for,
+            leader_id: None,
+            snapshot_index,
+            snapshot_term,
+            log: entries,
+            // 快照之后的日志是否已经提交需要等待 leader 通知
+            commit_index: snapshot_index,
+            last_applied: snapshot_index,
+            election_deadline: now,
+            last_heartbeat: now,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            pending: HashMap::new(),
+            forwarded: HashMap::new(),
+            next_proposal_id: 0,
+        };
+        node.reset_election_deadline();
+        node
+    }
+
+    async fn run(mut self, mut receiver: mpsc::Receiver<RaftEvent>) {
+        let mut ticker = interval(TICK_INTERVAL);
+        loop {
+            tokio::select! {
+                event = receiver.recv() => match event {
+                    Some(RaftEvent::Message(message)) => self.on_message(message),
+                    Some(RaftEvent::Propose(op, responder)) => self.on_propose(op, responder),
+                    None => return,
+                },
+                _ = ticker.tick() => self.on_tick(),
+            }
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    /// index 处日志的任期，日志不存在或者已经压缩到快照中（快照的最后一条除外）时返回 None
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        index
+            .checked_sub(self.snapshot_index + 1)
+            .and_then(|offset| self.log.get(offset as usize))
+            .map(|entry| entry.term)
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    /// 先持久化新的任期和投票信息再更新内存中的状态，持久化失败时保持原来的状态并返回 false
+    fn set_state(&mut self, current_term: u64, voted_for: Option<String>) -> bool {
+        let state = HardState {
+            current_term,
+            voted_for,
+        };
+        if let Err(err) = self.storage.save_state(&state) {
+            error!("Save raft state failed, err: [{}]", err);
+            return false;
+        }
+        self.current_term = state.current_term;
+        self.voted_for = state.voted_for;
+        true
+    }
+
+    /// 多数派的数量（包含自己）
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let millis = rand::thread_rng().gen_range(ELECTION_TIMEOUT_MS);
+        self.election_deadline = Instant::now() + Duration::from_millis(millis);
+    }
+
+    fn send(&self, peer: &str, body: RaftBody) {
+        let message = RaftMessage {
+            from: self.id.clone(),
+            term: self.current_term,
+            body,
+        };
+        match message
+            .to_frame(Codec::Json)
+            .and_then(|frame| frame.encode(Protocol::CURRENT))
+        {
+            Ok(data) => self.peer_cluster.send_to(peer, data),
+            Err(err) => error!("Encode raft message failed, err: [{}]", err),
+        }
+    }
+
+    /// leader 变更后，之前转发出去的写操作不会再有结果
+    fn set_leader(&mut self, leader_id: Option<String>) {
+        if self.leader_id != leader_id {
+            for (_, responder) in self.forwarded.drain() {
+                let _ = responder.send(false);
+            }
+            self.leader_id = leader_id;
+        }
+    }
+
+    fn on_tick(&mut self) {
+        match self.role {
+            Role::Leader => {
+                if self.last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+                    self.broadcast_append();
+                }
+            }
+            Role::Follower | Role::Candidate => {
+                if Instant::now() >= self.election_deadline {
+                    self.start_election();
+                }
+            }
+        }
+    }
+
+    fn start_election(&mut self) {
+        self.reset_election_deadline();
+        if !self.set_state(self.current_term + 1, Some(self.id.clone())) {
+            return;
+        }
+        self.role = Role::Candidate;
+        self.set_leader(None);
+        self.votes = HashSet::from([self.id.clone()]);
+        info!("raft start election for term [{}]", self.current_term);
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+        let body = RaftBody::VoteRequest {
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in &self.peers {
+            self.send(peer, body.clone());
+        }
+    }
+
+    /// 任期的持久化失败时返回 false，此时不能回复对方的请求
+    fn become_follower(&mut self, term: u64) -> bool {
+        if term > self.current_term && !self.set_state(term, None) {
+            return false;
+        }
+        if self.role != Role::Follower {
+            info!("raft become follower for term [{}]", self.current_term);
+            self.role = Role::Follower;
+        }
+        true
+    }
+
+    fn become_leader(&mut self) {
+        info!("raft become leader for term [{}]", self.current_term);
+        self.role = Role::Leader;
+        self.set_leader(Some(self.id.clone()));
+        let next_index = self.last_index() + 1;
+        self.next_index = self
+            .peers
+            .iter()
+            .map(|peer| (peer.clone(), next_index))
+            .collect();
+        self.match_index = self.peers.iter().map(|peer| (peer.clone(), 0)).collect();
+        // 追加一条空日志，用来提交之前任期遗留的日志
+        self.append(None);
+        self.broadcast_append();
+        self.advance_commit();
+    }
+
+    /// leader 持久化并追加一条日志，返回日志 index，持久化失败时返回 None
+    fn append(&mut self, op: Option<ReplicateOp>) -> Option<u64> {
+        let entry = RaftEntry {
+            term: self.current_term,
+            op,
+        };
+        if let Err(err) = self.storage.append(std::slice::from_ref(&entry)) {
+            error!("Append raft log failed, err: [{}]", err);
+            return None;
+        }
+        self.log.push(entry);
+        Some(self.last_index())
+    }
+
+    fn on_propose(&mut self, op: ReplicateOp, responder: oneshot::Sender<bool>) {
+        if self.role == Role::Leader {
+            match self.append(Some(op)) {
+                Some(index) => {
+                    self.pending
+                        .insert(index, (self.current_term, Proposal::Local(responder)));
+                    self.broadcast_append();
+                    self.advance_commit();
+                }
+                None => {
+                    let _ = responder.send(false);
+                }
+            }
+            return;
+        }
+        match self.leader_id.clone() {
+            Some(leader_id) => {
+                self.next_proposal_id += 1;
+                let proposal_id = self.next_proposal_id;
+                self.forwarded.insert(proposal_id, responder);
+                self.send(&leader_id, RaftBody::Propose { proposal_id, op });
+            }
+            None => {
+                warn!("raft has no leader, reject proposal");
+                let _ = responder.send(false);
+            }
+        }
+    }
+
+    fn on_message(&mut self, message: RaftMessage) {
+        let RaftMessage { from, term, body } = message;
+        if term > self.current_term && !self.become_follower(term) {
+            return;
+        }
+        match body {
+            RaftBody::VoteRequest {
+                last_log_index,
+                last_log_term,
+            } => self.on_vote_request(from, term, last_log_index, last_log_term),
+            RaftBody::VoteResponse { granted } => {
+                if self.role == Role::Candidate && term == self.current_term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            RaftBody::AppendEntries {
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => self.on_append_entries(
+                from,
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            ),
+            RaftBody::AppendResponse {
+                success,
+                match_index,
+            } => self.on_append_response(from, term, success, match_index),
+            RaftBody::InstallSnapshot { snapshot } => {
+                self.on_install_snapshot(from, term, snapshot)
+            }
+            RaftBody::Propose { proposal_id, op } => {
+                let index = match self.role {
+                    Role::Leader => self.append(Some(op)),
+                    _ => None,
+                };
+                if let Some(index) = index {
+                    self.pending.insert(
+                        index,
+                        (self.current_term, Proposal::Forwarded { from, proposal_id }),
+                    );
+                    self.broadcast_append();
+                    self.advance_commit();
+                } else {
+                    self.send(
+                        &from,
+                        RaftBody::ProposeResponse {
+                            proposal_id,
+                            success: false,
+                        },
+                    );
+                }
+            }
+            RaftBody::ProposeResponse {
+                proposal_id,
+                success,
+            } => {
+                if let Some(responder) = self.forwarded.remove(&proposal_id) {
+                    let _ = responder.send(success);
+                }
+            }
+        }
+    }
+
+    fn on_vote_request(
+        &mut self,
+        from: String,
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    ) {
+        let my_last_term = self.last_term();
+        let up_to_date = last_log_term > my_last_term
+            || (last_log_term == my_last_term && last_log_index >= self.last_index());
+        let granted = term == self.current_term
+            && up_to_date
+            && self.voted_for.as_ref().is_none_or(|voted| voted == &from);
+        if granted {
+            // 投票持久化之后才能回复，失败时不回复，候选人等待超时后重新选举
+            if !self.set_state(self.current_term, Some(from.clone())) {
+                return;
+            }
+            self.reset_election_deadline();
+        }
+        self.send(&from, RaftBody::VoteResponse { granted });
+    }
+
+    fn on_append_entries(
+        &mut self,
+        from: String,
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<RaftEntry>,
+        leader_commit: u64,
+    ) {
+        if term < self.current_term {
+            let body = RaftBody::AppendResponse {
+                success: false,
+                match_index: 0,
+            };
+            self.send(&from, body);
+            return;
+        }
+        // 当前任期的 leader
+        if !self.become_follower(term) {
+            return;
+        }
+        self.set_leader(Some(from.clone()));
+        self.reset_election_deadline();
+
+        // 已经压缩到快照中的日志都已经提交，一定与 leader 一致
+        let consistent = prev_log_index < self.snapshot_index
+            || self.term_at(prev_log_index) == Some(prev_log_term);
+        if !consistent {
+            // 日志不一致，让 leader 从更早的位置重新发送
+            let match_index = prev_log_index.saturating_sub(1).min(self.last_index());
+            let body = RaftBody::AppendResponse {
+                success: false,
+                match_index,
+            };
+            self.send(&from, body);
+            return;
+        }
+
+        let mut index = prev_log_index;
+        let mut conflict = None;
+        let mut appended = vec![];
+        for entry in entries {
+            index += 1;
+            if index <= self.snapshot_index {
+                continue;
+            }
+            if appended.is_empty() && index <= self.last_index() {
+                if self.term_at(index) == Some(entry.term) {
+                    continue;
+                }
+                conflict = Some(index);
+            }
+            appended.push(entry);
+        }
+        // 与 leader 冲突的日志全部删除，删除和追加的日志都持久化之后才回复 leader
+        if let Some(conflict) = conflict {
+            if !self.truncate(conflict) {
+                return;
+            }
+        }
+        if !appended.is_empty() {
+            if let Err(err) = self.storage.append(&appended) {
+                error!("Append raft log failed, err: [{}]", err);
+                return;
+            }
+            self.log.extend(appended);
+        }
+        if leader_commit > self.commit_index {
+            self.commit_index = self.commit_index.max(leader_commit.min(index));
+            self.apply_committed();
+        }
+        let body = RaftBody::AppendResponse {
+            success: true,
+            match_index: index,
+        };
+        self.send(&from, body);
+    }
+
+    fn on_append_response(&mut self, from: String, term: u64, success: bool, match_index: u64) {
+        if self.role != Role::Leader || term != self.current_term {
+            return;
+        }
+        let next_index = *self.next_index.get(&from).unwrap_or(&1);
+        if success {
+            let matched = self.match_index.entry(from.clone()).or_insert(0);
+            *matched = (*matched).max(match_index);
+            self.next_index
+                .insert(from.clone(), next_index.max(match_index + 1));
+            self.advance_commit();
+            // 继续发送剩余的日志
+            if match_index < self.last_index() {
+                self.send_append(&from);
+            }
+        } else {
+            let next_index = next_index.saturating_sub(1).min(match_index + 1).max(1);
+            self.next_index.insert(from.clone(), next_index);
+            self.send_append(&from);
+        }
+    }
+
+    /// follower 需要的日志已经压缩，安装 leader 的快照
+    fn on_install_snapshot(&mut self, from: String, term: u64, snapshot: RaftSnapshot) {
+        if term < self.current_term {
+            let body = RaftBody::AppendResponse {
+                success: false,
+                match_index: 0,
+            };
+            self.send(&from, body);
+            return;
+        }
+        if !self.become_follower(term) {
+            return;
+        }
+        self.set_leader(Some(from.clone()));
+        self.reset_election_deadline();
+
+        let match_index = snapshot.last_index;
+        // 快照包含的日志已经全部提交时不需要安装
+        if snapshot.last_index > self.commit_index {
+            // 快照的最后一条日志与本地一致时保留之后的日志，否则全部丢弃
+            let retained = if self.term_at(snapshot.last_index) == Some(snapshot.last_term) {
+                self.log[(snapshot.last_index - self.snapshot_index) as usize..].to_vec()
+            } else {
+                vec![]
+            };
+            if let Err(err) = self.storage.save_snapshot(&snapshot, &retained) {
+                error!("Save raft snapshot failed, err: [{}]", err);
+                return;
+            }
+            info!("raft install snapshot at index [{}]", snapshot.last_index);
+            // 快照包含的写操作无法确认任期，和丢弃的日志一样按照失败处理
+            let retained_last = snapshot.last_index + retained.len() as u64;
+            self.fail_pending(|index| index <= snapshot.last_index || index > retained_last);
+            self.log = retained;
+            self.snapshot_index = snapshot.last_index;
+            self.snapshot_term = snapshot.last_term;
+            self.commit_index = snapshot.last_index;
+            self.last_applied = snapshot.last_index;
+            self.store.install(snapshot.services);
+        }
+        let body = RaftBody::AppendResponse {
+            success: true,
+            match_index,
+        };
+        self.send(&from, body);
+    }
+
+    /// 持久化并删除 index 及之后的日志，等待这些日志提交的写操作全部失败
+    fn truncate(&mut self, index: u64) -> bool {
+        let len = (index - self.snapshot_index - 1) as usize;
+        if let Err(err) = self.storage.truncate(len) {
+            error!("Truncate raft log failed, err: [{}]", err);
+            return false;
+        }
+        self.log.truncate(len);
+        self.fail_pending(|pending_index| pending_index >= index);
+        true
+    }
+
+    /// 等待提交的写操作中，日志 index 满足 dropped 的全部失败
+    fn fail_pending(&mut self, dropped: impl Fn(u64) -> bool) {
+        let truncated = self
+            .pending
+            .keys()
+            .filter(|&&pending_index| dropped(pending_index))
+            .cloned()
+            .collect::<Vec<u64>>();
+        for pending_index in truncated {
+            if let Some((_, proposal)) = self.pending.remove(&pending_index) {
+                self.respond(proposal, false);
+            }
+        }
+    }
+
+    fn send_append(&self, peer: &str) {
+        let next_index = *self.next_index.get(peer).unwrap_or(&1);
+        if next_index <= self.snapshot_index {
+            self.send_snapshot(peer);
+            return;
+        }
+        let prev_log_index = next_index - 1;
+        let start = (prev_log_index - self.snapshot_index) as usize;
+        let end = self.log.len().min(start + MAX_APPEND_ENTRIES);
+        let entries = self
+            .log
+            .get(start..end)
+            .map(<[RaftEntry]>::to_vec)
+            .unwrap_or_default();
+        let body = RaftBody::AppendEntries {
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index).unwrap_or(0),
+            entries,
+            leader_commit: self.commit_index,
+        };
+        self.send(peer, body);
+    }
+
+    fn send_snapshot(&self, peer: &str) {
+        match self.storage.load_snapshot() {
+            Ok(snapshot) => self.send(peer, RaftBody::InstallSnapshot { snapshot }),
+            Err(err) => error!("Load raft snapshot failed, err: [{}]", err),
+        }
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in &self.peers {
+            self.send_append(peer);
+        }
+        self.last_heartbeat = Instant::now();
+    }
+
+    /// leader 根据多数实例的日志位置推进 commit_index
+    fn advance_commit(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        for index in (self.commit_index + 1..=self.last_index()).rev() {
+            // 只能通过计数提交当前任期的日志
+            if self.term_at(index) != Some(self.current_term) {
+                break;
+            }
+            let replicated = 1 + self
+                .match_index
+                .values()
+                .filter(|&&matched| matched >= index)
+                .count();
+            if replicated >= self.quorum() {
+                self.commit_index = index;
+                self.apply_committed();
+                break;
+            }
+        }
+    }
+
+    /// 将已经提交的日志应用到 ServersMap
+    fn apply_committed(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = self.log[(self.last_applied - self.snapshot_index) as usize - 1].clone();
+            if let Some(op) = entry.op {
+                self.store.apply(op);
+            }
+            if let Some((term, proposal)) = self.pending.remove(&self.last_applied) {
+                self.respond(proposal, term == entry.term);
+            }
+        }
+        self.compact();
+    }
+
+    /// 已经应用的日志超过阈值时，将 ServersMap 保存为快照并删除快照包含的日志
+    fn compact(&mut self) {
+        let applied = self.last_applied - self.snapshot_index;
+        if applied == 0 || applied < self.snapshot_threshold {
+            return;
+        }
+        let snapshot = RaftSnapshot {
+            last_index: self.last_applied,
+            last_term: self.term_at(self.last_applied).unwrap_or(0),
+            services: self.store.snapshot(),
+        };
+        let retained = &self.log[applied as usize..];
+        if let Err(err) = self.storage.save_snapshot(&snapshot, retained) {
+            error!("Save raft snapshot failed, err: [{}]", err);
+            return;
+        }
+        self.log.drain(..applied as usize);
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        info!("raft compact log into snapshot at index [{}]", self.snapshot_index);
+    }
+
+    fn respond(&self, proposal: Proposal, success: bool) {
+        match proposal {
+            Proposal::Local(responder) => {
+                let _ = responder.send(success);
+            }
+            Proposal::Forwarded { from, proposal_id } => {
+                self.send(
+                    &from,
+                    RaftBody::ProposeResponse {
+                        proposal_id,
+                        success,
+                    },
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::request::RegistryRequest;
+    use crate::models::{InboundHandleBroadcastEvent, InstanceStatus, NewService};
+    use crate::server_bootstrap::{ServersHeartbeatMap, ServersMap};
+    use tokio::sync::broadcast;
+
+    fn service(id: &str) -> NewService {
+        NewService {
+            id: id.to_string(),
+            name: "service-a".to_string(),
+            port: 8000,
+            host: "127.0.0.1".to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: None,
+            heartbeat_interval: None,
+            health_check: None,
+        }
+    }
+
+    fn start(
+        data_dir: &std::path::Path,
+        services_map: &ServersMap,
+    ) -> (RaftHandle, broadcast::Receiver<InboundHandleBroadcastEvent>) {
+        let (publisher, receiver) = broadcast::channel::<InboundHandleBroadcastEvent>(16);
+        let store =
+            ServiceStore::new(services_map.clone(), ServersHeartbeatMap::default(), publisher, 16);
+        let raft = RaftHandle::start(
+            "127.0.0.1:8080".to_string(),
+            vec![],
+            PeerCluster::default(),
+            store,
+            data_dir,
+            2,
+        )
+        .unwrap();
+        (raft, receiver)
+    }
+
+    /// 等待选举完成之后提交，单节点集群自己就是多数派
+    async fn propose(raft: &RaftHandle, id: &str) -> bool {
+        let op = ReplicateOp::Registry(RegistryRequest { service: service(id) });
+        for _ in 0..50 {
+            if raft.propose(op.clone()).await {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn single_node_commits_proposal() {
+        let data_dir = storage::temp_dir("commit");
+        let services_map = ServersMap::default();
+        let (raft, mut receiver) = start(&data_dir, &services_map);
+
+        assert!(propose(&raft, "id-1").await);
+        let service = service("id-1");
+        assert_eq!(services_map.read().get("service-a"), Some(&vec![service]));
+        assert!(matches!(
+            receiver.recv().await,
+            Ok(InboundHandleBroadcastEvent::AddServiceResp { .. })
+        ));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn restart_from_snapshot_and_log() {
+        let data_dir = storage::temp_dir("restart");
+        let services_map = ServersMap::default();
+        let (raft, _receiver) = start(&data_dir, &services_map);
+        // 空日志和前三个实例压缩到快照中，最后一个实例只在日志中
+        for id in ["id-1", "id-2", "id-3", "id-4"] {
+            assert!(propose(&raft, id).await);
+        }
+        let services = services_map.read().get("service-a").cloned().unwrap();
+        assert_eq!(services.len(), 4);
+        drop(raft);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        // 快照在启动时恢复，日志在重新选举并提交之后应用
+        let restored = ServersMap::default();
+        let (raft, _receiver) = start(&data_dir, &restored);
+        assert_eq!(restored.read().get("service-a").map(Vec::len), Some(3));
+        assert!(propose(&raft, "id-5").await);
+        let mut expected = services;
+        expected.push(service("id-5"));
+        assert_eq!(restored.read().get("service-a"), Some(&expected));
+        std::fs::remove_dir_all(&data_dir).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! Raft 的持久化存储
+//!
+//! 数据目录下保存三个文件：
+//! - state：当前任期和投票给的节点，变化时整体重写
+//! - log：快照之后的日志，每行一条 json，追加写入，删除冲突的日志时截断
+//! - snapshot：已经应用的日志压缩成的快照，写入快照之后重写日志文件，删除快照包含的日志
+//!
+//! 整体重写的文件先写入临时文件，fsync 之后再重命名替换；追加和截断日志之后同样会 fsync。
+
+use crate::models::request::{RaftEntry, RaftSnapshot};
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+const STATE_FILE: &str = "state";
+const LOG_FILE: &str = "log";
+const SNAPSHOT_FILE: &str = "snapshot";
+
+/// 需要在回复请求之前持久化的任期和投票信息
+#[derive(Serialize, Deserialize, PartialEq, Debug, Default)]
+pub struct HardState {
+    pub current_term: u64,
+    pub voted_for: Option<String>,
+}
+
+/// 日志文件中的一行，记录日志的 index，重启时用来跳过已经压缩到快照中的日志
+#[derive(Serialize, Deserialize)]
+struct LogRecord {
+    index: u64,
+    entry: RaftEntry,
+}
+
+/// 重启时从数据目录恢复的状态
+#[derive(Debug, Default)]
+pub struct Recovered {
+    pub state: HardState,
+    pub snapshot: RaftSnapshot,
+    /// 快照之后的日志
+    pub entries: Vec<RaftEntry>,
+}
+
+pub struct RaftStorage {
+    dir: PathBuf,
+    log: File,
+    /// 快照包含的最后一条日志的 index，日志文件从它的下一条开始
+    snapshot_index: u64,
+    /// 日志文件中每条日志结束的位置，截断日志时使用
+    offsets: Vec<u64>,
+}
+
+impl RaftStorage {
+    /// 打开数据目录，目录不存在时创建
+    ///
+    /// 日志文件中已经压缩到快照的日志以及崩溃时写了一半的记录会被丢弃
+    pub fn open(dir: impl Into<PathBuf>) -> Result<(Self, Recovered)> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let state = read_json::<HardState>(&dir.join(STATE_FILE))?.unwrap_or_default();
+        let snapshot = read_json::<RaftSnapshot>(&dir.join(SNAPSHOT_FILE))?.unwrap_or_default();
+        let entries = read_log(&dir.join(LOG_FILE), snapshot.last_index)?;
+        let log = open_log(&dir)?;
+        let mut storage = Self {
+            dir,
+            log,
+            snapshot_index: snapshot.last_index,
+            offsets: vec![],
+        };
+        storage.rewrite_log(snapshot.last_index, &entries)?;
+        let recovered = Recovered {
+            state,
+            snapshot,
+            entries,
+        };
+        Ok((storage, recovered))
+    }
+
+    pub fn save_state(&self, state: &HardState) -> Result<()> {
+        self.write_atomic(STATE_FILE, &serde_json::to_vec(state)?)
+    }
+
+    /// 在日志末尾追加日志
+    pub fn append(&mut self, entries: &[RaftEntry]) -> Result<()> {
+        let base = self.offsets.last().cloned().unwrap_or(0);
+        let mut data = vec![];
+        let mut offsets = vec![];
+        for entry in entries {
+            let index = self.snapshot_index + (self.offsets.len() + offsets.len()) as u64 + 1;
+            serde_json::to_writer(&mut data, &LogRecord {
+                index,
+                entry: entry.clone(),
+            })?;
+            data.push(b'\n');
+            offsets.push(base + data.len() as u64);
+        }
+        if let Err(err) = self.log.write_all(&data).and_then(|_| self.log.sync_data()) {
+            // 去掉写了一半的记录，避免之后追加的日志接在后面
+            let _ = self.log.set_len(base);
+            return Err(err.into());
+        }
+        self.offsets.extend(offsets);
+        Ok(())
+    }
+
+    /// 只保留快照之后的前 len 条日志
+    pub fn truncate(&mut self, len: usize) -> Result<()> {
+        if len >= self.offsets.len() {
+            return Ok(());
+        }
+        let offset = if len == 0 { 0 } else { self.offsets[len - 1] };
+        self.log.set_len(offset)?;
+        self.log.sync_data()?;
+        self.offsets.truncate(len);
+        Ok(())
+    }
+
+    /// 保存快照，entries 为快照之后需要保留的日志
+    pub fn save_snapshot(&mut self, snapshot: &RaftSnapshot, entries: &[RaftEntry]) -> Result<()> {
+        self.write_atomic(SNAPSHOT_FILE, &serde_json::to_vec(snapshot)?)?;
+        self.rewrite_log(snapshot.last_index, entries)
+    }
+
+    pub fn load_snapshot(&self) -> Result<RaftSnapshot> {
+        Ok(read_json(&self.dir.join(SNAPSHOT_FILE))?.unwrap_or_default())
+    }
+
+    /// 用 entries 替换整个日志文件
+    fn rewrite_log(&mut self, snapshot_index: u64, entries: &[RaftEntry]) -> Result<()> {
+        let mut data = vec![];
+        let mut offsets = vec![];
+        for (index, entry) in (snapshot_index + 1..).zip(entries) {
+            serde_json::to_writer(&mut data, &LogRecord {
+                index,
+                entry: entry.clone(),
+            })?;
+            data.push(b'\n');
+            offsets.push(data.len() as u64);
+        }
+        self.write_atomic(LOG_FILE, &data)?;
+        self.log = open_log(&self.dir)?;
+        self.snapshot_index = snapshot_index;
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    /// 写入临时文件并 fsync 之后重命名替换，最后 fsync 目录使重命名持久化
+    fn write_atomic(&self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let tmp = self.dir.join(format!("{}.tmp", name));
+        let mut file = File::create(&tmp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+}
+
+fn open_log(dir: &Path) -> Result<File> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILE))?)
+}
+
+/// 文件不存在时返回 None
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    match fs::read(path) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// 读取快照之后连续的日志
+fn read_log(path: &Path, snapshot_index: u64) -> Result<Vec<RaftEntry>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let record = match line
+            .map_err(anyhow::Error::from)
+            .and_then(|line| Ok(serde_json::from_str::<LogRecord>(&line)?))
+        {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("Drop incomplete raft log record, err: [{}]", err);
+                break;
+            }
+        };
+        if record.index <= snapshot_index {
+            continue;
+        }
+        if record.index != snapshot_index + entries.len() as u64 + 1 {
+            warn!("Drop raft log from non-contiguous index [{}]", record.index);
+            break;
+        }
+        entries.push(record.entry);
+    }
+    Ok(entries)
+}
+
+/// 测试使用的临时数据目录
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("connor-raft-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::request::{RegistryRequest, ReplicateOp};
+    use crate::models::{InstanceStatus, NewService};
+    use std::collections::HashMap;
+
+    fn service(id: &str) -> NewService {
+        NewService {
+            id: id.to_string(),
+            name: "service-a".to_string(),
+            port: 8000,
+            host: "127.0.0.1".to_string(),
+            meta: None,
+            status: InstanceStatus::Up,
+            ttl: None,
+            heartbeat_interval: None,
+            health_check: None,
+        }
+    }
+
+    fn entry(term: u64, id: &str) -> RaftEntry {
+        RaftEntry {
+            term,
+            op: Some(ReplicateOp::Registry(RegistryRequest { service: service(id) })),
+        }
+    }
+
+    #[test]
+    fn recover_after_truncate_and_snapshot() {
+        let dir = temp_dir("storage");
+        let (mut storage, recovered) = RaftStorage::open(&dir).unwrap();
+        assert_eq!(recovered.state, HardState::default());
+        assert!(recovered.entries.is_empty());
+
+        let state = HardState {
+            current_term: 2,
+            voted_for: Some("127.0.0.1:8081".to_string()),
+        };
+        storage.save_state(&state).unwrap();
+        storage.append(&[entry(1, "id-1"), entry(1, "id-2")]).unwrap();
+        storage.append(&[entry(1, "id-3")]).unwrap();
+        storage.truncate(2).unwrap();
+        storage.append(&[entry(2, "id-4")]).unwrap();
+        drop(storage);
+
+        let (mut storage, recovered) = RaftStorage::open(&dir).unwrap();
+        assert_eq!(recovered.state, state);
+        assert_eq!(
+            recovered.entries,
+            vec![entry(1, "id-1"), entry(1, "id-2"), entry(2, "id-4")]
+        );
+
+        let snapshot = RaftSnapshot {
+            last_index: 2,
+            last_term: 1,
+            services: HashMap::from([(
+                "service-a".to_string(),
+                vec![service("id-1")],
+            )]),
+        };
+        storage.save_snapshot(&snapshot, &[entry(2, "id-4")]).unwrap();
+        storage.append(&[entry(2, "id-5")]).unwrap();
+        // 崩溃时写了一半的记录
+        OpenOptions::new()
+            .append(true)
+            .open(dir.join(LOG_FILE))
+            .unwrap()
+            .write_all(b"{\"index\":5,")
+            .unwrap();
+        drop(storage);
+
+        let (storage, recovered) = RaftStorage::open(&dir).unwrap();
+        assert_eq!(recovered.snapshot, snapshot);
+        assert_eq!(storage.load_snapshot().unwrap(), snapshot);
+        assert_eq!(recovered.entries, vec![entry(2, "id-4"), entry(2, "id-5")]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,152 @@
+//! 自我保护模式
+//!
+//! Connor 自身的网络短暂中断时，所有实例的心跳都会超时，心跳检测任务会一次剔除全部实例。
+//! 一段时间窗口内心跳超时（已经剔除和等待剔除）的实例占比超过阈值时进入自我保护：暂停剔除实例，
+//! 等待剔除的实例之后重新检查，恢复心跳的实例不再剔除；占比回到阈值以下后退出自我保护，继续剔除
+
+use crate::server::metrics::METRICS;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info};
+
+pub struct SelfPreservation {
+    enabled: bool,
+    /// 心跳超时实例的占比阈值
+    threshold: f64,
+    /// 统计剔除实例的时间窗口
+    window: Duration,
+    /// 实例个数少于该值时不进入自我保护
+    min_instances: usize,
+    /// 时间窗口内剔除的实例：(剔除时间, 个数)
+    evictions: VecDeque<(SystemTime, usize)>,
+    /// 心跳超时、等待剔除的实例
+    held: HashSet<String>,
+    active: bool,
+}
+
+impl SelfPreservation {
+    pub fn new(enabled: bool, threshold: f64, window: Duration, min_instances: usize) -> Self {
+        Self {
+            enabled,
+            threshold,
+            window,
+            min_instances,
+            evictions: VecDeque::new(),
+            held: HashSet::new(),
+            active: false,
+        }
+    }
+
+    /// 心跳超时的实例请求剔除，返回可以剔除的实例；total 为当前全部的实例个数
+    ///
+    /// 处于自我保护时返回空，这些实例需要之后重新检查
+    pub fn admit(&mut self, timeout_ids: Vec<String>, total: usize, now: SystemTime) -> Vec<String> {
+        if !self.enabled {
+            return timeout_ids;
+        }
+        self.held.extend(timeout_ids.iter().cloned());
+        let active = self.exceeded(total, now);
+        if active != self.active {
+            if active {
+                error!(
+                    "entering self preservation: [{}] of [{}] instances expired in the last {:?}, stop evicting",
+                    self.expired(),
+                    total + self.evicted(),
+                    self.window
+                );
+            } else {
+                info!("heartbeats recovered, leaving self preservation and resume evicting");
+            }
+            self.active = active;
+        }
+        if active {
+            METRICS.set_self_preservation(true, self.held.len());
+            return vec![];
+        }
+        timeout_ids.iter().for_each(|id| {
+            self.held.remove(id);
+        });
+        METRICS.set_self_preservation(false, self.held.len());
+        timeout_ids
+    }
+
+    /// 实例恢复了心跳，或者已经被移除，不再等待剔除
+    pub fn release(&mut self, service_id: &str) {
+        if self.held.remove(service_id) {
+            METRICS.set_self_preservation(self.active, self.held.len());
+        }
+    }
+
+    /// 记录已经剔除的实例
+    pub fn record(&mut self, evicted: usize, now: SystemTime) {
+        METRICS.heartbeat_evicted(evicted);
+        self.evictions.push_back((now, evicted));
+    }
+
+    pub fn active(&self) -> bool {
+        self.active
+    }
+
+    /// 时间窗口内心跳超时的实例占比是否超过阈值
+    fn exceeded(&mut self, total: usize, now: SystemTime) -> bool {
+        while let Some((evicted_at, _)) = self.evictions.front() {
+            if now.duration_since(*evicted_at).unwrap_or_default() <= self.window {
+                break;
+            }
+            self.evictions.pop_front();
+        }
+        // 已经剔除的实例不在当前的实例中
+        let total = total + self.evicted();
+        total >= self.min_instances && self.expired() as f64 > self.threshold * total as f64
+    }
+
+    fn evicted(&self) -> usize {
+        self.evictions.iter().map(|(_, evicted)| evicted).sum()
+    }
+
+    fn expired(&self) -> usize {
+        self.evicted() + self.held.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn hold_and_resume() {
+        let now = SystemTime::now();
+        let mut preservation = SelfPreservation::new(true, 0.5, Duration::from_secs(60), 3);
+        // 10 个实例中 2 个超时，正常剔除
+        assert_eq!(preservation.admit(ids(&["a", "b"]), 10, now), ids(&["a", "b"]));
+        preservation.record(2, now);
+        // 窗口内累计超过一半，暂停剔除
+        assert!(preservation.admit(ids(&["c", "d", "e", "f"]), 8, now).is_empty());
+        assert!(preservation.active());
+        // 部分实例恢复了心跳
+        preservation.release("c");
+        preservation.release("d");
+        preservation.release("e");
+        assert_eq!(preservation.admit(ids(&["f"]), 8, now), ids(&["f"]));
+        assert!(!preservation.active());
+        // 之前的剔除移出了时间窗口
+        let later = now + Duration::from_secs(120);
+        assert_eq!(
+            preservation.admit(ids(&["g", "h"]), 4, later),
+            ids(&["g", "h"])
+        );
+    }
+
+    #[test]
+    fn small_fleet_and_disabled() {
+        let now = SystemTime::now();
+        let mut preservation = SelfPreservation::new(true, 0.5, Duration::from_secs(60), 3);
+        assert_eq!(preservation.admit(ids(&["a", "b"]), 2, now), ids(&["a", "b"]));
+        let mut disabled = SelfPreservation::new(false, 0.5, Duration::from_secs(60), 3);
+        assert_eq!(disabled.admit(ids(&["a", "b"]), 4, now), ids(&["a", "b"]));
+    }
+}
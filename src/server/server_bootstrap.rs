@@ -1,26 +1,33 @@
 //! connor server_bootstrap
 
-use crate::custom_error::Byte2JsonErr;
-use crate::models::{InboundHandleBroadcastEvent, InboundHandleSingleEvent, NewService, RpcKind};
-use crate::server::inbound::InboundParams;
-use crate::server::outbound::outbound_handle_broad;
-use crate::server::{inbound_handle, outbound_handle_resp};
+use crate::models::{
+    InboundHandleBroadcastEvent, InboundHandleSingleEvent, InstanceStatus, NewService, RpcKind,
+};
+use crate::protocol::{Codec, Frame, FrameWriter, Protocol};
+use crate::models::request::ReplicateOp;
+use crate::server::cluster::{Cluster, ServiceStore};
+use crate::server::deadline_queue::DeadlineQueue;
+use crate::server::inbound::{snapshot, InboundParams};
+use crate::server::metrics::{Subscriber, METRICS};
+use crate::server::outbound::{outbound_handle_broad, outbound_handle_lagged};
+use crate::server::self_preservation::SelfPreservation;
+use crate::server::subscription::Subscriptions;
+use crate::server::{dns, grpc, health_check, http_api, inbound_handle, outbound_handle_resp};
 use anyhow::Result;
 use futures::{StreamExt, TryStreamExt};
 use parking_lot::RwLock;
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
-use std::str::FromStr;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::net::TcpListener;
-use tokio::sync::broadcast::Sender;
-use tokio::sync::{broadcast, mpsc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{broadcast, mpsc, watch, Mutex};
 use tokio::time::sleep;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 use tracing::{error, info, warn};
-use crate::config::SERVER_CONFIG;
+use crate::config::{ClusterMode, SERVER_CONFIG};
 use crate::PeerCluster;
 
 /// 存放已经注册进来的所有的服务，key是service-name
@@ -28,6 +35,48 @@ pub type ServersMap = Arc<RwLock<HashMap<String, Vec<NewService>>>>;
 /// 存放心跳请求数据（<实例ID, timestamp>）
 pub type ServersHeartbeatMap = Arc<RwLock<HashMap<String, SystemTime>>>;
 
+/// 心跳检测提交失败时重试的间隔
+const HEARTBEAT_RETRY: Duration = Duration::from_secs(5);
+
+/// 实例的心跳超时时间（秒），注册时没有携带时使用配置的默认值
+pub fn service_ttl(service: &NewService) -> u64 {
+    service.ttl.unwrap_or_else(|| SERVER_CONFIG.heartbeat_ttl)
+}
+
+/// 错过心跳多久之后实例变为 SUSPECT、DOWN，以及被剔除：分别为心跳超时时间的 1/3、2/3 和全部
+fn heartbeat_thresholds(ttl: u64) -> [Duration; 3] {
+    let ttl = Duration::from_secs(ttl);
+    [ttl / 3, ttl * 2 / 3, ttl]
+}
+
+/// 根据最后一次心跳距今的时间计算实例应处的状态，None 表示心跳已经超时，需要剔除
+pub fn heartbeat_status(last_heartbeat: &SystemTime, ttl: u64) -> Option<InstanceStatus> {
+    let elapsed = last_heartbeat.elapsed().unwrap_or_default();
+    let [suspect, down, timeout] = heartbeat_thresholds(ttl);
+    match elapsed {
+        elapsed if elapsed > timeout => None,
+        elapsed if elapsed > down => Some(InstanceStatus::Down),
+        elapsed if elapsed > suspect => Some(InstanceStatus::Suspect),
+        _ => Some(InstanceStatus::Up),
+    }
+}
+
+/// 最后一次心跳距今是否已经超时
+pub fn is_heartbeat_timeout(last_heartbeat: &SystemTime, ttl: u64) -> bool {
+    heartbeat_status(last_heartbeat, ttl).is_none()
+}
+
+/// 没有新的心跳时，实例下一次状态变化的时间
+fn next_deadline(last_heartbeat: &SystemTime, ttl: u64) -> SystemTime {
+    let elapsed = last_heartbeat.elapsed().unwrap_or_default();
+    let threshold = heartbeat_thresholds(ttl)
+        .into_iter()
+        .find(|threshold| *threshold >= elapsed)
+        .unwrap_or_default();
+    // 状态在超过阈值之后才变化
+    *last_heartbeat + threshold + Duration::from_millis(1)
+}
+
 /// Connor 服务
 pub struct ConnorServer {
     // 启动地址
@@ -37,7 +86,6 @@ pub struct ConnorServer {
     // 心跳请求数据
     servers_heartbeat: ServersHeartbeatMap,
     // 集群实例
-    #[allow(dead_code)]
     peer_cluster: PeerCluster,
 }
 
@@ -54,7 +102,7 @@ impl Default for ConnorServer {
             servers_heartbeat: ServersHeartbeatMap::new(RwLock::new(
                 HashMap::<String, SystemTime>::new(),
             )),
-            peer_cluster: PeerCluster { clients: Arc::new(Default::default()) }
+            peer_cluster: PeerCluster::default(),
         }
     }
 }
@@ -63,128 +111,309 @@ impl ConnorServer {
         Self::default()
     }
 
-    /// 定时检测心跳数据
-    fn heartbeat_task(&self, heartbeat_publisher: Sender<InboundHandleBroadcastEvent>) {
-        let services_heartbeat_map = self.servers_heartbeat.clone();
+    /// 心跳检测任务：根据错过心跳的时间推进实例的状态：UP → SUSPECT → DOWN → EVICTED，
+    /// 完成全量数据同步之后开始
+    ///
+    /// 每个实例按照自己的 ttl 计算下一次状态变化的时间放入截止时间队列，任务只在截止时间到达时检查；
+    /// 新注册的实例从广播的变更中获得。状态变化通过集群提交并广播给订阅者，恢复为 UP 由收到心跳时提交
+    fn heartbeat_task(
+        &self,
+        cluster: Cluster,
+        mut receiver: broadcast::Receiver<InboundHandleBroadcastEvent>,
+        mut ready: watch::Receiver<bool>,
+    ) {
         let services_map = self.servers.clone();
+        let services_heartbeat_map = self.servers_heartbeat.clone();
         tokio::spawn(async move {
+            wait_ready(&mut ready).await;
+            let mut deadlines = DeadlineQueue::default();
+            let mut preservation = SelfPreservation::new(
+                SERVER_CONFIG.self_preservation,
+                SERVER_CONFIG.self_preservation_threshold,
+                Duration::from_secs(SERVER_CONFIG.self_preservation_window),
+                SERVER_CONFIG.self_preservation_min_instances,
+            );
+            schedule_all(&mut deadlines, &services_map);
             loop {
-                // 每90 秒进行检测
-                sleep(tokio::time::Duration::from_secs(90)).await;
-                // 超时 ID 集合，这些 instance_id都要从servers_map中移除
-                let timeout_instance_ids;
-                {
-                    let read_guard = services_heartbeat_map.read();
-                    // 获取超时的instance_id(当前时间差超过 90秒即为过期)
-                    timeout_instance_ids = read_guard
-                        .iter()
-                        .filter(|(_, system_time)| {
-                            if let Ok(time) = system_time.elapsed() {
-                                return time.as_secs() > 90;
-                            }
-                            false
-                        })
-                        .map(|(id, _)| id.clone())
-                        .collect::<Vec<String>>();
-                }
-                if timeout_instance_ids.is_empty() {
-                    info!("all instance are health");
-                    continue;
+                let wait = deadlines.next_wait();
+                tokio::select! {
+                    _ = sleep(wait.unwrap_or_default()), if wait.is_some() => {
+                        check_heartbeats(
+                            &mut deadlines,
+                            &mut preservation,
+                            &services_map,
+                            &services_heartbeat_map,
+                            &cluster,
+                        )
+                        .await;
+                    }
+                    event = receiver.recv() => match event {
+                        // 新注册或者更新了的实例立即检查，计算它的截止时间
+                        Ok(event) => {
+                            let now = SystemTime::now();
+                            event
+                                .deltas()
+                                .iter()
+                                .flat_map(|delta| delta.added.iter().chain(delta.updated.iter()))
+                                .for_each(|service| deadlines.schedule(&service.id, now));
+                        }
+                        Err(RecvError::Lagged(lagged)) => {
+                            warn!("heartbeat task lagged [{}] events, check all instances", lagged);
+                            schedule_all(&mut deadlines, &services_map);
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
                 }
-                warn!("that`s timeout instance: {:?}", timeout_instance_ids);
+            }
+        });
+    }
 
-                {
-                    let mut write_guard = services_map.write();
-                    // 移除超时的instance_id
-                    write_guard.iter_mut().for_each(|(_, services)| {
-                        services.retain(|service| !timeout_instance_ids.contains(&service.id));
-                    });
+    /// 从集群中存活的实例拉取全量数据，覆盖本地的注册数据和心跳数据
+    async fn sync_from_peers(servers: ServersMap, servers_heartbeat: ServersHeartbeatMap) {
+        let (peer_addr, snapshot) =
+            match PeerCluster::pull_snapshot(&SERVER_CONFIG.cluster_address).await {
+                Some(snapshot) => snapshot,
+                None => {
+                    info!("no peer available, start with empty registry");
+                    return;
                 }
-                // 将timeout_instance_ids进行广播，客户端需要移除
-                if let Err(err) =
-                    heartbeat_publisher.send(InboundHandleBroadcastEvent::HeartbeatTimeoutResp {
-                        service_ids: timeout_instance_ids,
-                    })
-                {
-                    error!("heartbeat_publisher send error: {}", err);
-                }
-            }
+            };
+        let service_count = snapshot.services.len();
+        let instance_count = snapshot.services.values().map(Vec::len).sum::<usize>();
+        // 对端还没有心跳时间的实例以导入的时间作为第一次心跳，之后一直没有心跳同样会超时被剔除
+        let now = SystemTime::now();
+        let mut heartbeats = snapshot.heartbeats;
+        snapshot.services.values().flatten().for_each(|service| {
+            heartbeats.entry(service.id.clone()).or_insert(now);
         });
+        *servers.write() = snapshot.services;
+        *servers_heartbeat.write() = heartbeats;
+        info!(
+            "imported {} services ({} instances) from peer [{}]",
+            service_count, instance_count, peer_addr
+        );
     }
 
     // #[instrument]
     pub async fn start(&mut self) -> Result<()> {
         let listener = TcpListener::bind(self.addr.as_str()).await?;
+        // 开始处理请求之前，先从集群中存活的实例拉取全量数据；同步在后台进行，同步期间已经接受连接，
+        // 其它实例拉取全量数据时立即响应未就绪，同时启动的实例不会互相等待；
+        // 其它请求等到导入全量数据之后才处理，其它实例在此期间产生的变更按照顺序应用；
+        // raft 模式下由 leader 同步日志恢复，gossip 模式下由摘要交换恢复
+        let (ready_tx, ready) = watch::channel(false);
+        let servers = self.servers.clone();
+        let servers_heartbeat = self.servers_heartbeat.clone();
+        tokio::spawn(async move {
+            if SERVER_CONFIG.cluster_mode == ClusterMode::Replicate {
+                Self::sync_from_peers(servers, servers_heartbeat).await;
+            }
+            ready_tx.send_replace(true);
+            info!("Connor Server_Bootstrap Ready");
+        });
         info!("Connor Server_Bootstrap Startup");
         let mut listener_stream = TcpListenerStream::new(listener);
 
-        let (broad_tx, _) = broadcast::channel::<InboundHandleBroadcastEvent>(1024);
+        let (broad_tx, _) =
+            broadcast::channel::<InboundHandleBroadcastEvent>(SERVER_CONFIG.broadcast_capacity);
+        METRICS.set_broadcast_capacity(SERVER_CONFIG.broadcast_capacity);
 
-        self.heartbeat_task(broad_tx.clone());
+        // 连接集群中的其它实例
+        self.peer_cluster.init(&SERVER_CONFIG.cluster_address);
+        let cluster = Cluster::new(
+            &SERVER_CONFIG,
+            ServiceStore::new(
+                self.servers.clone(),
+                self.servers_heartbeat.clone(),
+                broad_tx.clone(),
+                SERVER_CONFIG.event_history_size,
+            ),
+            self.peer_cluster.clone(),
+        )?;
+        info!("cluster start with [{:?}] mode", SERVER_CONFIG.cluster_mode);
+
+        self.heartbeat_task(cluster.clone(), broad_tx.subscribe(), ready.clone());
         info!("heartbeat_task start with [{}]", self.addr.as_str());
 
-        // 开启任务，连接集群中的其它实例
-        let mut peer_cluster = self.peer_cluster.clone();
-        tokio::spawn(async move {
-            let cluster = &SERVER_CONFIG.cluster_address;
-            // 初始化获取server 客户端实例
-            peer_cluster.init(cluster).await;
-        });
+        // 主动探测携带 health_check 的实例，探测成功视为一次心跳；导入全量数据之后才开始，
+        // 先订阅广播，同步期间注册的实例不会遗漏
+        {
+            let services_map = self.servers.clone();
+            let services_heartbeat_map = self.servers_heartbeat.clone();
+            let cluster = cluster.clone();
+            let receiver = broad_tx.subscribe();
+            let mut ready = ready.clone();
+            tokio::spawn(async move {
+                wait_ready(&mut ready).await;
+                health_check::run(services_map, services_heartbeat_map, cluster, receiver).await;
+            });
+        }
+
+        // HTTP REST 接口，与 TCP 连接共享注册数据和集群，导入全量数据之后才开启
+        if let Some(http_address) = SERVER_CONFIG.http_address.clone() {
+            let services_map = self.servers.clone();
+            let services_heartbeat_map = self.servers_heartbeat.clone();
+            let cluster = cluster.clone();
+            let mut ready = ready.clone();
+            tokio::spawn(async move {
+                wait_ready(&mut ready).await;
+                info!("http api start with [{}]", http_address);
+                if let Err(err) =
+                    http_api::serve(&http_address, services_map, services_heartbeat_map, cluster)
+                        .await
+                {
+                    error!("http api [{}] stopped, err: [{:?}]", http_address, err);
+                }
+            });
+        }
+
+        // gRPC 接口，Watch 订阅与 TCP 连接相同的广播，导入全量数据之后才开启
+        if let Some(grpc_address) = SERVER_CONFIG.grpc_address.clone() {
+            let services_map = self.servers.clone();
+            let services_heartbeat_map = self.servers_heartbeat.clone();
+            let cluster = cluster.clone();
+            let broad_tx = broad_tx.clone();
+            let mut ready = ready.clone();
+            tokio::spawn(async move {
+                wait_ready(&mut ready).await;
+                info!("grpc start with [{}]", grpc_address);
+                let serve = grpc::serve(
+                    &grpc_address,
+                    services_map,
+                    services_heartbeat_map,
+                    cluster,
+                    broad_tx,
+                );
+                if let Err(err) = serve.await {
+                    error!("grpc [{}] stopped, err: [{:?}]", grpc_address, err);
+                }
+            });
+        }
+
+        // DNS 接口，解析 <service_name>.service.connor，导入全量数据之后才开启
+        if let Some(dns_address) = SERVER_CONFIG.dns_address.clone() {
+            let services_map = self.servers.clone();
+            let services_heartbeat_map = self.servers_heartbeat.clone();
+            let ttl = SERVER_CONFIG.dns_ttl;
+            let mut ready = ready.clone();
+            tokio::spawn(async move {
+                wait_ready(&mut ready).await;
+                info!("dns start with [{}]", dns_address);
+                if let Err(err) =
+                    dns::serve(&dns_address, services_map, services_heartbeat_map, ttl).await
+                {
+                    error!("dns [{}] stopped, err: [{:?}]", dns_address, err);
+                }
+            });
+        }
 
         while let Some(socket) = listener_stream.try_next().await? {
             let peer_addr = socket.peer_addr().unwrap().to_string();
             info!("connection come in：{}", &peer_addr);
 
-            let (m_sender, mut s_receiver) = mpsc::channel::<InboundHandleSingleEvent>(16);
+            let (m_sender, mut s_receiver) = mpsc::channel::<(u64, InboundHandleSingleEvent)>(16);
 
             let services_map = self.servers.clone();
             let services_heartbeat_map = self.servers_heartbeat.clone();
+            let cluster = cluster.clone();
+            let mut ready = ready.clone();
 
             // channel
             let (writer, mut reader) = Framed::new(socket, LengthDelimitedCodec::new()).split();
-            let writer = Arc::new(Mutex::new(writer));
+            // 协议由连接上的第一帧确定，之前默认按照旧协议响应
+            let writer = Arc::new(Mutex::new(FrameWriter::new(
+                writer,
+                Protocol::Legacy,
+                Codec::Json,
+            )));
 
             // response client spawn
             // 用于监听处理响应客户端的请求(单消费者响应)
             let single_writer = writer.clone();
             let single_handle = tokio::spawn(async move {
-                while let Some(data) = s_receiver.recv().await {
-                    outbound_handle_resp(data, single_writer.clone()).await;
+                while let Some((request_id, data)) = s_receiver.recv().await {
+                    outbound_handle_resp(request_id, data, single_writer.clone()).await;
                 }
             });
 
-            // 多消费者响应
+            // 多消费者响应，只推送连接订阅的服务；握手时声明支持增量推送的连接推送增量变更；
+            // 推送落后于广播时丢失的事件无法补发，通知客户端重新同步后继续推送
+            let subscriptions = Subscriptions::default();
             let mut broad_receiver = broad_tx.subscribe();
             let broad_writer = writer.clone();
+            let broad_subscriptions = subscriptions.clone();
+            let broad_peer_addr = peer_addr.clone();
             let broad_handle = tokio::spawn(async move {
-                while let Ok(data) = broad_receiver.recv().await {
-                    outbound_handle_broad(data, broad_writer.clone()).await;
+                loop {
+                    match broad_receiver.recv().await {
+                        Ok(data) => {
+                            outbound_handle_broad(data, broad_writer.clone(), &broad_subscriptions)
+                                .await
+                        }
+                        Err(RecvError::Lagged(lagged)) => {
+                            warn!(
+                                "connection [{}] lagged [{}] events, resync required",
+                                broad_peer_addr, lagged
+                            );
+                            METRICS.broadcast_lagged(Subscriber::Tcp, lagged);
+                            outbound_handle_lagged(lagged, broad_writer.clone()).await;
+                        }
+                        Err(RecvError::Closed) => break,
+                    }
                 }
             });
 
             // 请求处理
-            // 用来发送响应客户端的消息
-            let broad_sender = broad_tx.clone();
             tokio::spawn(async move {
+                let mut protocol = None;
                 while let Ok(Some(req)) = reader.try_next().await {
-                    let string = String::from_utf8((&req).to_vec())
-                        .unwrap_or_else(|_| panic!("{}", Byte2JsonErr));
-                    info!("Inbound data：{}", string);
-
-                    if let Ok(rpc_kind) = RpcKind::from_str(&string[0..1]) {
-                        let json = &string[1..];
-                        let inbound_params = InboundParams::new(
-                            rpc_kind,
-                            json.to_string(),
-                            broad_sender.clone(),
-                            m_sender.clone(),
-                        );
-                        inbound_handle(
-                            inbound_params,
-                            services_map.clone(),
-                            services_heartbeat_map.clone(),
-                        )
-                        .await;
+                    // 无法解析的数据返回错误响应，不断开连接；旧协议的连接无法解析错误响应，由响应时关闭连接
+                    match Frame::decode(req.freeze()) {
+                        Ok((frame_protocol, frame)) => {
+                            info!("Inbound data：[{:?}] {:?}", frame.rpc_kind, frame.body);
+                            if protocol.is_none() {
+                                protocol = Some(frame_protocol);
+                                writer.lock().await.set_protocol(frame_protocol);
+                            }
+                            // 握手不读取注册数据，拉取全量数据的实例先握手再请求全量数据，不需要等待
+                            if !*ready.borrow() && frame.rpc_kind != RpcKind::Handshake {
+                                // 同步期间的全量数据可能是空的，让对方改为向其它实例拉取
+                                if frame.rpc_kind == RpcKind::Snapshot {
+                                    let not_ready = (frame.request_id, snapshot::not_ready());
+                                    if m_sender.send(not_ready).await.is_err() {
+                                        break;
+                                    }
+                                    continue;
+                                }
+                                wait_ready(&mut ready).await;
+                            }
+                            // 集群节点间的消息和改变连接状态的请求（如握手改变连接的协议和格式）按照到达的顺序处理
+                            let ordered = protocol == Some(Protocol::Legacy) || !frame.rpc_kind.concurrent();
+                            let inbound_params =
+                                InboundParams::new(frame, m_sender.clone(), subscriptions.clone());
+                            let handle = inbound_handle(
+                                inbound_params,
+                                services_map.clone(),
+                                services_heartbeat_map.clone(),
+                                cluster.clone(),
+                            );
+                            // 旧协议按照请求顺序响应；新协议的响应带有 request id，客户端的读写请求并发处理
+                            if ordered {
+                                handle.await;
+                            } else {
+                                tokio::spawn(handle);
+                            }
+                        }
+                        Err(error) => {
+                            warn!("Bad frame from [{}], err: [{}]", peer_addr, error);
+                            let error_event = InboundHandleSingleEvent::ErrorResp {
+                                error,
+                                rpc_kind: None,
+                            };
+                            if m_sender.send((0, error_event)).await.is_err() {
+                                break;
+                            }
+                        }
                     }
                 }
 
@@ -198,6 +427,133 @@ impl ConnorServer {
     }
 }
 
+/// 等待本节点完成全量数据同步
+async fn wait_ready(ready: &mut watch::Receiver<bool>) {
+    while !*ready.borrow() {
+        if ready.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+/// 立即检查所有的实例
+fn schedule_all(deadlines: &mut DeadlineQueue, services_map: &ServersMap) {
+    let now = SystemTime::now();
+    services_map
+        .read()
+        .values()
+        .flatten()
+        .for_each(|service| deadlines.schedule(&service.id, now));
+}
+
+/// 检查截止时间已经到达的实例，提交状态变化和心跳超时，并安排下一次检查
+async fn check_heartbeats(
+    deadlines: &mut DeadlineQueue,
+    preservation: &mut SelfPreservation,
+    services_map: &ServersMap,
+    services_heartbeat_map: &ServersHeartbeatMap,
+    cluster: &Cluster,
+) {
+    let now = SystemTime::now();
+    let expired = deadlines.expired(now);
+    if expired.is_empty() {
+        return;
+    }
+    let current = services_map
+        .read()
+        .values()
+        .flatten()
+        .filter(|service| expired.contains(&service.id))
+        .map(|service| (service.id.clone(), (service.status, service_ttl(service))))
+        .collect::<HashMap<String, (InstanceStatus, u64)>>();
+    // 超时 ID 集合，这些 instance_id都要从servers_map中移除
+    let mut timeout_instance_ids = vec![];
+    let mut suspect_instance_ids = vec![];
+    let mut down_instance_ids = vec![];
+    for id in expired {
+        let (status, ttl) = match current.get(&id) {
+            Some(current) => *current,
+            // 已经下线或者被剔除的实例不再检查
+            None => {
+                preservation.release(&id);
+                continue;
+            }
+        };
+        // 应用注册和导入全量数据时已经记录了第一次心跳，这里兜底以本节点第一次检查到的时间作为第一次心跳，
+        // 注册之后一直没有心跳的实例同样会超时被剔除
+        let last_heartbeat = *services_heartbeat_map
+            .write()
+            .entry(id.clone())
+            .or_insert(now);
+        let verdict = heartbeat_status(&last_heartbeat, ttl);
+        if verdict.is_some() {
+            preservation.release(&id);
+        }
+        match (verdict, status) {
+            (None, _) => {
+                timeout_instance_ids.push(id);
+                continue;
+            }
+            (Some(InstanceStatus::Suspect), InstanceStatus::Up) => suspect_instance_ids.push(id.clone()),
+            (Some(InstanceStatus::Down), status) if status != InstanceStatus::Down => {
+                down_instance_ids.push(id.clone())
+            }
+            _ => {}
+        }
+        deadlines.schedule(&id, next_deadline(&last_heartbeat, ttl));
+    }
+    for (service_ids, status) in [
+        (suspect_instance_ids, InstanceStatus::Suspect),
+        (down_instance_ids, InstanceStatus::Down),
+    ] {
+        if service_ids.is_empty() {
+            continue;
+        }
+        warn!("instance {:?} missed heartbeats, mark {:?}", service_ids, status);
+        let op = ReplicateOp::InstanceStatus {
+            service_ids: service_ids.clone(),
+            status,
+        };
+        if !cluster.submit(op).await {
+            error!("submit instance status failed, retry later");
+            service_ids
+                .iter()
+                .for_each(|id| deadlines.schedule(id, now + HEARTBEAT_RETRY));
+        }
+    }
+    if timeout_instance_ids.is_empty() {
+        return;
+    }
+    let total = services_map.read().values().map(Vec::len).sum::<usize>();
+    let held_instance_ids = timeout_instance_ids.clone();
+    let timeout_instance_ids = preservation.admit(timeout_instance_ids, total, now);
+    if preservation.active() {
+        warn!(
+            "self preservation is active, hold eviction of {:?}",
+            held_instance_ids
+        );
+        held_instance_ids
+            .iter()
+            .for_each(|id| deadlines.schedule(id, now + HEARTBEAT_RETRY));
+        return;
+    }
+    warn!("that`s timeout instance: {:?}", timeout_instance_ids);
+
+    // 通过集群提交：移除超时的instance_id，并将timeout_instance_ids进行广播，客户端需要移除；
+    // 各节点应用剔除时清理心跳数据，之后的心跳会被告知重新注册
+    let op = ReplicateOp::HeartbeatTimeout {
+        service_ids: timeout_instance_ids.clone(),
+    };
+    if cluster.submit(op).await {
+        preservation.record(timeout_instance_ids.len(), now);
+    } else {
+        error!("submit heartbeat timeout failed, retry later");
+        timeout_instance_ids
+            .iter()
+            .for_each(|id| deadlines.schedule(id, now + HEARTBEAT_RETRY));
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -214,4 +570,19 @@ mod test {
         map.iter_mut().for_each(|(_, v)| v.retain(|x| !x.eq("2")));
         println!("{:?}", map);
     }
+
+    #[test]
+    fn heartbeat_lifecycle() {
+        let ago = |secs| SystemTime::now() - Duration::from_secs(secs);
+        assert_eq!(heartbeat_status(&ago(5), 30), Some(InstanceStatus::Up));
+        assert_eq!(heartbeat_status(&ago(11), 30), Some(InstanceStatus::Suspect));
+        assert_eq!(heartbeat_status(&ago(21), 30), Some(InstanceStatus::Down));
+        assert_eq!(heartbeat_status(&ago(31), 30), None);
+
+        // 下一次状态变化为 SUSPECT 之后的 DOWN
+        let last_heartbeat = ago(11);
+        let deadline = next_deadline(&last_heartbeat, 30);
+        let wait = deadline.duration_since(last_heartbeat).unwrap();
+        assert!(wait > Duration::from_secs(20) && wait < Duration::from_secs(21));
+    }
 }
@@ -0,0 +1,148 @@
+//! 连接的服务订阅
+//!
+//! 连接没有订阅过任何服务时接收所有服务的变更推送（兼容旧客户端）；
+//! 订阅之后只推送名称与订阅匹配的服务，订阅的名称支持通配符 `*`（任意个字符）和 `?`（单个字符）。
+//! 握手时声明支持增量推送的连接接收每个服务的增量变更，否则接收变更后的全部实例
+
+use crate::models::InboundHandleBroadcastEvent;
+use parking_lot::RwLock;
+use std::collections::BTreeSet;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// 一个连接的订阅
+#[derive(Clone, Default)]
+pub struct Subscriptions {
+    /// 订阅的服务名称（通配符），None 表示订阅所有服务
+    names: Arc<RwLock<Option<BTreeSet<String>>>>,
+    /// 是否推送增量变更
+    delta: Arc<AtomicBool>,
+}
+
+impl Subscriptions {
+    /// 设置是否推送增量变更
+    pub fn set_delta(&self, delta: bool) {
+        self.delta.store(delta, Ordering::Relaxed);
+    }
+
+    pub fn delta(&self) -> bool {
+        self.delta.load(Ordering::Relaxed)
+    }
+
+    /// 添加订阅，返回当前订阅的所有名称
+    pub fn subscribe(&self, service_names: Vec<String>) -> Vec<String> {
+        let mut subscriptions = self.names.write();
+        let subscribed = subscriptions.get_or_insert_with(BTreeSet::new);
+        subscribed.extend(service_names);
+        subscribed.iter().cloned().collect()
+    }
+
+    /// 取消订阅，返回当前订阅的所有名称；从未订阅过时仍然接收所有服务的推送
+    pub fn unsubscribe(&self, service_names: &[String]) -> Vec<String> {
+        let mut subscriptions = self.names.write();
+        match subscriptions.as_mut() {
+            Some(subscribed) => {
+                subscribed.retain(|name| !service_names.contains(name));
+                subscribed.iter().cloned().collect()
+            }
+            None => vec!["*".to_string()],
+        }
+    }
+
+    /// 某服务是否被订阅
+    pub fn matches(&self, service_name: &str) -> bool {
+        match self.names.read().as_ref() {
+            Some(subscribed) => subscribed
+                .iter()
+                .any(|pattern| glob_match(pattern, service_name)),
+            None => true,
+        }
+    }
+
+    /// 连接是否需要接收该事件（全量推送）
+    pub fn accepts(&self, event: &InboundHandleBroadcastEvent) -> bool {
+        match event {
+            InboundHandleBroadcastEvent::AddServiceResp { service_name, .. }
+            | InboundHandleBroadcastEvent::RemoveServiceResp { service_name, .. } => {
+                self.matches(service_name)
+            }
+            InboundHandleBroadcastEvent::HeartbeatTimeoutResp { deltas, .. }
+            | InboundHandleBroadcastEvent::InstanceStatusResp { deltas, .. } => {
+                self.names.read().is_none()
+                    || deltas.iter().any(|delta| self.matches(&delta.service_name))
+            }
+        }
+    }
+}
+
+/// 通配符匹配：`*` 匹配任意个字符，`?` 匹配单个字符
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<char>>();
+    let name = name.chars().collect::<Vec<char>>();
+    let (mut p, mut n) = (0, 0);
+    // 最近一个 `*` 的位置，以及它当前匹配到的 name 位置
+    let mut star = None;
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // 回到上一个 `*`，让它多匹配一个字符
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::models::response::ServiceDeltaResponse;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match("*", "order"));
+        assert!(glob_match("order-*", "order-api"));
+        assert!(glob_match("*-api", "order-api"));
+        assert!(glob_match("ord?r", "order"));
+        assert!(glob_match("o*r*i", "order-api"));
+        assert!(!glob_match("order-*", "user-api"));
+        assert!(!glob_match("order", "order-api"));
+    }
+
+    #[test]
+    fn subscribe_filter() {
+        let subscriptions = Subscriptions::default();
+        assert!(subscriptions.matches("anything"));
+        let names = subscriptions.subscribe(vec!["order-*".to_string(), "user".to_string()]);
+        assert_eq!(names, vec!["order-*", "user"]);
+        assert!(subscriptions.matches("order-api"));
+        assert!(!subscriptions.matches("payment"));
+
+        let delta = |service_name: &str| ServiceDeltaResponse {
+            service_name: service_name.to_string(),
+            revision: 1,
+            store_revision: 1,
+            added: vec![],
+            removed: vec!["id-1".to_string()],
+            updated: vec![],
+        };
+        let event = InboundHandleBroadcastEvent::HeartbeatTimeoutResp {
+            service_ids: vec!["id-1".to_string()],
+            deltas: vec![delta("payment"), delta("user")],
+        };
+        assert!(subscriptions.accepts(&event));
+        assert_eq!(
+            subscriptions.unsubscribe(&["user".to_string()]),
+            vec!["order-*"]
+        );
+        assert!(!subscriptions.accepts(&event));
+    }
+}